@@ -0,0 +1,584 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::error::{Result, XLimError};
+use crate::storage::StorageEngine;
+
+/// Prefix of the metadata keys that hold user accounts
+const USER_PREFIX: &str = "user:";
+
+/// Prefix of the metadata keys that hold custom roles
+const ROLE_PREFIX: &str = "role:";
+
+/// Names of the roles that always exist and cannot be changed
+pub const BUILT_IN_ROLES: [&str; 3] = ["read", "readWrite", "admin"];
+
+/// Something a user can be allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Action {
+    /// Read documents, including queries
+    Find,
+    /// Insert documents
+    Insert,
+    /// Update documents
+    Update,
+    /// Delete documents
+    Delete,
+    /// Create collections
+    CreateCollection,
+    /// Drop collections
+    DropCollection,
+    /// Create secondary indexes
+    CreateIndex,
+    /// Drop secondary indexes
+    DropIndex,
+    /// Set or remove the TTL policy of collections
+    SetTtl,
+    /// Begin, commit and roll back transactions
+    Transaction,
+    /// Manage users and roles
+    ManageUsers,
+    /// Inspect the state of the server, such as its active transactions
+    ServerStatus,
+    /// Back up the database
+    Backup,
+}
+
+impl Action {
+    /// Every action, in the order they are listed in help text
+    pub const ALL: [Action; 13] = [
+        Self::Find,
+        Self::Insert,
+        Self::Update,
+        Self::Delete,
+        Self::CreateCollection,
+        Self::DropCollection,
+        Self::CreateIndex,
+        Self::DropIndex,
+        Self::SetTtl,
+        Self::Transaction,
+        Self::ManageUsers,
+        Self::ServerStatus,
+        Self::Backup,
+    ];
+    
+    /// Name of the action as used in roles
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Find => "find",
+            Self::Insert => "insert",
+            Self::Update => "update",
+            Self::Delete => "delete",
+            Self::CreateCollection => "createCollection",
+            Self::DropCollection => "dropCollection",
+            Self::CreateIndex => "createIndex",
+            Self::DropIndex => "dropIndex",
+            Self::SetTtl => "setTtl",
+            Self::Transaction => "transaction",
+            Self::ManageUsers => "manageUsers",
+            Self::ServerStatus => "serverStatus",
+            Self::Backup => "backup",
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Action {
+    type Err = XLimError;
+    
+    fn from_str(s: &str) -> Result<Self> {
+        // Accept the kebab-case spelling too, e.g. `manage-users`
+        let normalized = s.replace('-', "").to_lowercase();
+        
+        Self::ALL
+            .into_iter()
+            .find(|action| action.name().to_lowercase() == normalized)
+            .ok_or_else(|| XLimError::InvalidOperation(format!("Unknown action: {}", s)))
+    }
+}
+
+/// A set of actions allowed on one collection, or on all of them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Privilege {
+    /// Collection the actions apply to, or `None` for every collection and for actions
+    /// that do not belong to a collection
+    pub collection: Option<String>,
+    
+    /// Allowed actions
+    pub actions: Vec<Action>,
+}
+
+impl Privilege {
+    /// Check whether the privilege allows an action on a collection
+    pub fn allows(&self, action: Action, collection: Option<&str>) -> bool {
+        let resource_matches = match (&self.collection, collection) {
+            (None, _) => true,
+            (Some(granted), Some(requested)) => granted == requested,
+            (Some(_), None) => false,
+        };
+        
+        resource_matches && self.actions.contains(&action)
+    }
+}
+
+impl FromStr for Privilege {
+    type Err = XLimError;
+    
+    /// Parse `<collection>:<action>,<action>...`, where `*` stands for every collection
+    fn from_str(s: &str) -> Result<Self> {
+        let (collection, actions) = s.split_once(':')
+            .ok_or_else(|| XLimError::InvalidOperation(format!("Invalid privilege '{}', expected <collection>:<action>,...", s)))?;
+        
+        let collection = match collection {
+            "*" => None,
+            name => Some(name.to_string()),
+        };
+        
+        let actions = actions
+            .split(',')
+            .map(|action| action.trim().parse())
+            .collect::<Result<Vec<Action>>>()?;
+        
+        Ok(Self { collection, actions })
+    }
+}
+
+/// A named set of privileges that can be granted to users
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Role {
+    /// Name of the role
+    pub name: String,
+    
+    /// Privileges granted by the role
+    pub privileges: Vec<Privilege>,
+}
+
+impl Role {
+    /// Get a built-in role by name
+    pub fn built_in(name: &str) -> Option<Self> {
+        let actions = match name {
+            "read" => vec![Action::Find, Action::Transaction],
+            "readWrite" => vec![
+                Action::Find,
+                Action::Insert,
+                Action::Update,
+                Action::Delete,
+                Action::CreateIndex,
+                Action::DropIndex,
+                Action::SetTtl,
+                Action::Transaction,
+            ],
+            "admin" => Action::ALL.to_vec(),
+            _ => return None,
+        };
+        
+        Some(Self {
+            name: name.to_string(),
+            privileges: vec![Privilege { collection: None, actions }],
+        })
+    }
+}
+
+/// A user account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    /// Name the user logs in with
+    pub username: String,
+    
+    /// Argon2 hash of the password in PHC string format, which includes its random salt
+    pub password_hash: String,
+    
+    /// Names of the roles granted to the user
+    pub roles: Vec<String>,
+    
+    /// Creation timestamp
+    pub created_at: DateTime<Utc>,
+}
+
+/// User accounts and custom roles, stored in the metadata column family
+pub struct UserStore {
+    /// Storage engine holding the accounts
+    storage: Arc<StorageEngine>,
+    
+    /// Serializes account changes so that existence checks and writes do not interleave
+    write_lock: Mutex<()>,
+}
+
+impl UserStore {
+    /// Create a user store backed by a storage engine
+    pub fn new(storage: Arc<StorageEngine>) -> Self {
+        Self {
+            storage,
+            write_lock: Mutex::new(()),
+        }
+    }
+    
+    /// Get a user by name
+    pub fn get_user(&self, username: &str) -> Result<Option<User>> {
+        self.storage.get_metadata(&user_key(username))
+    }
+    
+    /// Create a user with a password and roles
+    pub fn add_user(&self, username: &str, password: &str, roles: &[String]) -> Result<()> {
+        validate_credentials(username, password)?;
+        
+        let _guard = self.write_lock.lock().unwrap();
+        
+        if self.get_user(username)?.is_some() {
+            return Err(XLimError::InvalidOperation(format!("User already exists: {}", username)));
+        }
+        
+        self.check_roles_exist(roles)?;
+        
+        let user = User {
+            username: username.to_string(),
+            password_hash: hash_password(password)?,
+            roles: roles.to_vec(),
+            created_at: Utc::now(),
+        };
+        
+        self.storage.store_metadata(&user_key(username), &user)?;
+        
+        info!("Added user: {}", username);
+        
+        Ok(())
+    }
+    
+    /// Delete a user
+    pub fn remove_user(&self, username: &str) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        
+        if self.get_user(username)?.is_none() {
+            return Err(XLimError::InvalidOperation(format!("User not found: {}", username)));
+        }
+        
+        self.storage.delete_metadata(&user_key(username))?;
+        
+        info!("Removed user: {}", username);
+        
+        Ok(())
+    }
+    
+    /// Replace a user's password
+    pub fn change_password(&self, username: &str, password: &str) -> Result<()> {
+        validate_credentials(username, password)?;
+        
+        let _guard = self.write_lock.lock().unwrap();
+        
+        let mut user = self.get_user(username)?
+            .ok_or_else(|| XLimError::InvalidOperation(format!("User not found: {}", username)))?;
+        
+        user.password_hash = hash_password(password)?;
+        self.storage.store_metadata(&user_key(username), &user)?;
+        
+        info!("Changed password of user: {}", username);
+        
+        Ok(())
+    }
+    
+    /// Grant roles to a user
+    pub fn grant_roles(&self, username: &str, roles: &[String]) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        
+        self.check_roles_exist(roles)?;
+        
+        let mut user = self.get_user(username)?
+            .ok_or_else(|| XLimError::InvalidOperation(format!("User not found: {}", username)))?;
+        
+        for role in roles {
+            if !user.roles.contains(role) {
+                user.roles.push(role.clone());
+            }
+        }
+        
+        self.storage.store_metadata(&user_key(username), &user)?;
+        
+        info!("Granted roles {:?} to user: {}", roles, username);
+        
+        Ok(())
+    }
+    
+    /// Revoke roles from a user
+    pub fn revoke_roles(&self, username: &str, roles: &[String]) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        
+        let mut user = self.get_user(username)?
+            .ok_or_else(|| XLimError::InvalidOperation(format!("User not found: {}", username)))?;
+        
+        user.roles.retain(|role| !roles.contains(role));
+        self.storage.store_metadata(&user_key(username), &user)?;
+        
+        info!("Revoked roles {:?} from user: {}", roles, username);
+        
+        Ok(())
+    }
+    
+    /// Get a built-in or custom role by name
+    pub fn get_role(&self, name: &str) -> Result<Option<Role>> {
+        match Role::built_in(name) {
+            Some(role) => Ok(Some(role)),
+            None => self.storage.get_metadata(&role_key(name)),
+        }
+    }
+    
+    /// Create a custom role
+    pub fn create_role(&self, role: &Role) -> Result<()> {
+        if role.name.is_empty() {
+            return Err(XLimError::InvalidOperation("Role name must not be empty".to_string()));
+        }
+        
+        let _guard = self.write_lock.lock().unwrap();
+        
+        if self.get_role(&role.name)?.is_some() {
+            return Err(XLimError::InvalidOperation(format!("Role already exists: {}", role.name)));
+        }
+        
+        self.storage.store_metadata(&role_key(&role.name), role)?;
+        
+        info!("Created role: {}", role.name);
+        
+        Ok(())
+    }
+    
+    /// Delete a custom role. Users that were granted it lose its privileges.
+    pub fn drop_role(&self, name: &str) -> Result<()> {
+        if BUILT_IN_ROLES.contains(&name) {
+            return Err(XLimError::InvalidOperation(format!("Cannot drop built-in role: {}", name)));
+        }
+        
+        let _guard = self.write_lock.lock().unwrap();
+        
+        if self.get_role(name)?.is_none() {
+            return Err(XLimError::InvalidOperation(format!("Role not found: {}", name)));
+        }
+        
+        self.storage.delete_metadata(&role_key(name))?;
+        
+        info!("Dropped role: {}", name);
+        
+        Ok(())
+    }
+    
+    /// Check that a user may perform an action, on a collection if the action belongs to one
+    pub fn authorize(&self, username: &str, action: Action, collection: Option<&str>) -> Result<()> {
+        let user = self.get_user(username)?
+            .ok_or_else(|| XLimError::Authentication(format!("User not found: {}", username)))?;
+        
+        for name in &user.roles {
+            // Roles dropped after being granted are skipped
+            let Some(role) = self.get_role(name)? else {
+                continue;
+            };
+            
+            if role.privileges.iter().any(|privilege| privilege.allows(action, collection)) {
+                return Ok(());
+            }
+        }
+        
+        Err(XLimError::Authentication(match collection {
+            Some(collection) => format!("User {} is not allowed to {} on collection {}", username, action, collection),
+            None => format!("User {} is not allowed to {}", username, action),
+        }))
+    }
+    
+    /// Check that every role in a list exists
+    fn check_roles_exist(&self, roles: &[String]) -> Result<()> {
+        for role in roles {
+            if self.get_role(role)?.is_none() {
+                return Err(XLimError::InvalidOperation(format!("Role not found: {}", role)));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Check a username and password, returning the user if they match
+    pub fn authenticate(&self, username: &str, password: &str) -> Result<User> {
+        // The same error for unknown users and wrong passwords avoids revealing which names exist
+        let invalid = || XLimError::Authentication("Invalid username or password".to_string());
+        
+        let user = self.get_user(username)?.ok_or_else(invalid)?;
+        
+        let hash = PasswordHash::new(&user.password_hash)
+            .map_err(|e| XLimError::Storage(format!("Invalid password hash for user {}: {}", username, e)))?;
+        
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .map_err(|_| invalid())?;
+        
+        Ok(user)
+    }
+}
+
+/// Metadata key of a user account
+fn user_key(username: &str) -> String {
+    format!("{}{}", USER_PREFIX, username)
+}
+
+/// Metadata key of a custom role
+fn role_key(name: &str) -> String {
+    format!("{}{}", ROLE_PREFIX, name)
+}
+
+/// Reject empty usernames and passwords
+fn validate_credentials(username: &str, password: &str) -> Result<()> {
+    if username.is_empty() {
+        return Err(XLimError::InvalidOperation("Username must not be empty".to_string()));
+    }
+    
+    if password.is_empty() {
+        return Err(XLimError::InvalidOperation("Password must not be empty".to_string()));
+    }
+    
+    Ok(())
+}
+
+/// Hash a password with Argon2 and a new random salt
+fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| XLimError::Authentication(format!("Failed to hash password: {}", e)))?;
+    
+    Ok(hash.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// A user store backed by a fresh in-memory database
+    fn store() -> UserStore {
+        UserStore::new(Arc::new(StorageEngine::in_memory().unwrap()))
+    }
+    
+    #[test]
+    fn users_log_in_with_their_password_only() {
+        let users = store();
+        users.add_user("ann", "secret", &[]).unwrap();
+        
+        assert_eq!(users.authenticate("ann", "secret").unwrap().username, "ann");
+        
+        // Wrong passwords and unknown users fail the same way
+        let wrong = users.authenticate("ann", "Secret").unwrap_err();
+        let unknown = users.authenticate("bob", "secret").unwrap_err();
+        
+        assert!(matches!(wrong, XLimError::Authentication(_)));
+        assert_eq!(wrong.to_string(), unknown.to_string());
+    }
+    
+    #[test]
+    fn passwords_are_stored_as_salted_hashes() {
+        let users = store();
+        users.add_user("ann", "secret", &[]).unwrap();
+        users.add_user("bob", "secret", &[]).unwrap();
+        
+        let ann = users.get_user("ann").unwrap().unwrap();
+        let bob = users.get_user("bob").unwrap().unwrap();
+        
+        assert!(!ann.password_hash.contains("secret"));
+        assert!(ann.password_hash.starts_with("$argon2"));
+        assert_ne!(ann.password_hash, bob.password_hash);
+    }
+    
+    #[test]
+    fn invalid_accounts_are_rejected() {
+        let users = store();
+        users.add_user("ann", "secret", &[]).unwrap();
+        
+        assert!(users.add_user("ann", "other", &[]).is_err());
+        assert!(users.add_user("", "secret", &[]).is_err());
+        assert!(users.add_user("bob", "", &[]).is_err());
+        assert!(users.add_user("bob", "secret", &["missing".to_string()]).is_err());
+        assert!(users.get_user("bob").unwrap().is_none());
+    }
+    
+    #[test]
+    fn changed_passwords_replace_the_old_one() {
+        let users = store();
+        users.add_user("ann", "secret", &[]).unwrap();
+        users.change_password("ann", "new secret").unwrap();
+        
+        assert!(users.authenticate("ann", "secret").is_err());
+        assert!(users.authenticate("ann", "new secret").is_ok());
+        
+        users.remove_user("ann").unwrap();
+        
+        assert!(users.authenticate("ann", "new secret").is_err());
+        assert!(users.remove_user("ann").is_err());
+        assert!(users.change_password("ann", "secret").is_err());
+    }
+    
+    #[test]
+    fn privileges_parse_collections_and_actions() {
+        let privilege: Privilege = "orders:find, insert,manage-users".parse().unwrap();
+        
+        assert_eq!(privilege.collection.as_deref(), Some("orders"));
+        assert_eq!(privilege.actions, vec![Action::Find, Action::Insert, Action::ManageUsers]);
+        assert_eq!("*:find".parse::<Privilege>().unwrap().collection, None);
+        
+        assert!("orders".parse::<Privilege>().is_err());
+        assert!("orders:fly".parse::<Privilege>().is_err());
+    }
+    
+    #[test]
+    fn roles_allow_only_their_actions_on_their_collections() {
+        let users = store();
+        let role = Role { name: "orderEntry".to_string(), privileges: vec!["orders:find,insert".parse().unwrap()] };
+        users.create_role(&role).unwrap();
+        users.add_user("ann", "secret", &["orderEntry".to_string()]).unwrap();
+        users.add_user("bob", "secret", &["read".to_string()]).unwrap();
+        
+        assert!(users.authorize("ann", Action::Insert, Some("orders")).is_ok());
+        
+        let denied = users.authorize("ann", Action::Update, Some("orders")).unwrap_err();
+        assert!(matches!(&denied, XLimError::Authentication(message) if message.contains("update on collection orders")));
+        
+        assert!(users.authorize("ann", Action::Find, Some("customers")).is_err());
+        assert!(users.authorize("ann", Action::ManageUsers, None).is_err());
+        
+        assert!(users.authorize("bob", Action::Find, Some("customers")).is_ok());
+        assert!(users.authorize("bob", Action::Delete, Some("customers")).is_err());
+        assert!(users.authorize("nobody", Action::Find, Some("customers")).is_err());
+    }
+    
+    #[test]
+    fn grants_apply_to_the_next_check() {
+        let users = store();
+        let role = Role { name: "reader".to_string(), privileges: vec!["c:find".parse().unwrap()] };
+        users.create_role(&role).unwrap();
+        users.add_user("ann", "secret", &[]).unwrap();
+        
+        assert!(users.authorize("ann", Action::Find, Some("c")).is_err());
+        
+        users.grant_roles("ann", &["reader".to_string()]).unwrap();
+        assert!(users.authorize("ann", Action::Find, Some("c")).is_ok());
+        
+        // Dropping a role takes its privileges away from everyone it was granted to
+        users.drop_role("reader").unwrap();
+        assert!(users.authorize("ann", Action::Find, Some("c")).is_err());
+        
+        users.grant_roles("ann", &["admin".to_string()]).unwrap();
+        assert!(users.authorize("ann", Action::Backup, None).is_ok());
+        
+        users.revoke_roles("ann", &["admin".to_string()]).unwrap();
+        assert!(users.authorize("ann", Action::Backup, None).is_err());
+        
+        assert!(users.drop_role("admin").is_err());
+        assert!(users.create_role(&Role { name: "read".to_string(), privileges: Vec::new() }).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use std::any::Any;
+use std::fmt;
+use std::path::Path;
+
+use crate::backup::BackupInfo;
+use crate::error::{Result, XLimError};
+
+/// A stored key and its value
+pub type KeyValue = (Box<[u8]>, Box<[u8]>);
+
+/// Iterator over the keys and values of a keyspace, in key order
+pub type KeyValueIter<'a> = Box<dyn Iterator<Item = Result<KeyValue>> + 'a>;
+
+/// Separate ordered sets of keys that a backend stores
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Keyspace {
+    /// Collections, by name
+    Collections,
+    /// Documents, by collection ID and document ID
+    Documents,
+    /// Secondary index entries, which have empty values
+    Indexes,
+    /// Everything else the database stores, such as users and commit records
+    Metadata,
+}
+
+impl Keyspace {
+    /// Name of the keyspace
+    pub fn name(self) -> &'static str {
+        match self {
+            Keyspace::Collections => "collections",
+            Keyspace::Documents => "documents",
+            Keyspace::Indexes => "indexes",
+            Keyspace::Metadata => "metadata",
+        }
+    }
+}
+
+/// Order in which a scan visits keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Ascending key order
+    Forward,
+    /// Descending key order
+    Reverse,
+}
+
+/// A change to one key, as part of a batch
+#[derive(Debug, Clone)]
+pub enum BatchOperation {
+    /// Set a key to a value
+    Put(Keyspace, Vec<u8>, Vec<u8>),
+    /// Remove a key
+    Delete(Keyspace, Vec<u8>),
+}
+
+/// Changes to any number of keys that a backend writes atomically
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    /// Changes in the order they were added; a later change to a key wins
+    operations: Vec<BatchOperation>,
+}
+
+impl WriteBatch {
+    /// Set a key to a value
+    pub fn put(&mut self, keyspace: Keyspace, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.operations.push(BatchOperation::Put(keyspace, key.into(), value.into()));
+    }
+    
+    /// Remove a key
+    pub fn delete(&mut self, keyspace: Keyspace, key: impl Into<Vec<u8>>) {
+        self.operations.push(BatchOperation::Delete(keyspace, key.into()));
+    }
+    
+    /// Get the number of changes in the batch
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+    
+    /// Check whether the batch has no changes
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+    
+    /// Take the changes out of the batch
+    pub fn into_operations(self) -> Vec<BatchOperation> {
+        self.operations
+    }
+}
+
+/// A consistent, point-in-time view of a backend.
+///
+/// Each backend keeps its own kind of snapshot inside, and only accepts its own.
+pub struct Snapshot {
+    /// Backend-specific snapshot
+    inner: Box<dyn Any + Send + Sync>,
+}
+
+impl Snapshot {
+    /// Wrap a backend-specific snapshot
+    pub fn new<T: Any + Send + Sync>(inner: T) -> Self {
+        Self {
+            inner: Box::new(inner),
+        }
+    }
+    
+    /// Get the backend-specific snapshot, failing if it was taken by another kind of backend
+    pub fn downcast<T: Any>(&self) -> Result<&T> {
+        self.inner.downcast_ref()
+            .ok_or_else(|| XLimError::Storage("Snapshot was taken by a different storage backend".to_string()))
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Snapshot").finish_non_exhaustive()
+    }
+}
+
+/// Ordered key-value storage that the storage engine keeps its data in.
+///
+/// Collections, documents, indexes and metadata each live in their own keyspace. Everything
+/// above this, such as encoding, indexing and commit records, is handled by the storage engine,
+/// so the transaction manager and the query layer work the same over any backend.
+pub trait StorageBackend: Send + Sync {
+    /// Read the value of a key, from a snapshot if one is given
+    fn get(&self, keyspace: Keyspace, key: &[u8], snapshot: Option<&Snapshot>) -> Result<Option<Vec<u8>>>;
+    
+    /// Write every change in a batch, or none of them. With `sync`, the changes are on disk
+    /// before this returns, for backends that have one.
+    fn write(&self, batch: WriteBatch, sync: bool) -> Result<()>;
+    
+    /// Scan the keys that start with `prefix`, beginning at `start` and moving in `direction`.
+    ///
+    /// `start` must itself start with `prefix`; a reverse scan includes `start` if it exists.
+    /// The scan reads from a snapshot if one is given, and otherwise sees a consistent view
+    /// of the data as it was when the scan began.
+    fn scan<'a>(&'a self, keyspace: Keyspace, prefix: &[u8], start: &[u8], direction: Direction, snapshot: Option<&'a Snapshot>) -> Result<KeyValueIter<'a>>;
+    
+    /// Take a snapshot that later reads and scans can be made from
+    fn snapshot(&self) -> Snapshot;
+    
+    /// Back up the data into a backup directory while it stays open, for backends that keep
+    /// their data on disk
+    fn create_backup(&self, _dir: &Path) -> Result<BackupInfo> {
+        Err(XLimError::InvalidOperation("This storage backend does not support backups".to_string()))
+    }
+}
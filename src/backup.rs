@@ -0,0 +1,226 @@
+use chrono::{DateTime, Utc};
+use rocksdb::backup::{BackupEngine, BackupEngineInfo, BackupEngineOptions, RestoreOptions};
+use rocksdb::{Env, DB};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::error::{Result, XLimError};
+
+/// A backup in a backup directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupInfo {
+    /// ID of the backup, which increases with every backup made into the directory
+    pub id: u32,
+    
+    /// When the backup was made
+    pub created_at: DateTime<Utc>,
+    
+    /// Size of the backup's files in bytes, including files it shares with other backups
+    pub size: u64,
+    
+    /// Number of files in the backup
+    pub files: u32,
+}
+
+impl From<BackupEngineInfo> for BackupInfo {
+    fn from(info: BackupEngineInfo) -> Self {
+        Self {
+            id: info.backup_id,
+            created_at: DateTime::from_timestamp(info.timestamp, 0).unwrap_or_default(),
+            size: info.size,
+            files: info.num_files,
+        }
+    }
+}
+
+/// Back up a database into a backup directory, creating the directory if needed.
+///
+/// The backup is a consistent view of the database, taken while it stays open for reads and
+/// writes. Files that an earlier backup in the directory already holds are not copied again,
+/// so every backup after the first is incremental.
+pub fn create_backup(db: &DB, dir: &Path) -> Result<BackupInfo> {
+    std::fs::create_dir_all(dir)?;
+    
+    let mut engine = open_engine(dir)?;
+    
+    engine.create_new_backup_flush(db, true)
+        .map_err(|e| XLimError::Storage(format!("Failed to back up database: {}", e)))?;
+    
+    latest_backup(&engine)
+        .ok_or_else(|| XLimError::Storage("Backup was not recorded".to_string()))
+}
+
+/// List the backups in a backup directory, oldest first
+pub fn list_backups(dir: &Path) -> Result<Vec<BackupInfo>> {
+    let engine = open_existing_engine(dir)?;
+    
+    let mut backups: Vec<BackupInfo> = engine.get_backup_info().into_iter().map(BackupInfo::from).collect();
+    backups.sort_by_key(|backup| backup.id);
+    
+    Ok(backups)
+}
+
+/// Check that the files of a backup are all present and have the sizes they were written with
+pub fn verify_backup(dir: &Path, id: u32) -> Result<()> {
+    let engine = open_existing_engine(dir)?;
+    
+    engine.verify_backup(id)
+        .map_err(|e| XLimError::Storage(format!("Backup {} is damaged: {}", id, e)))
+}
+
+/// Delete all but the newest `keep` backups in a backup directory, returning how many were
+/// deleted. Files still used by a remaining backup are kept.
+pub fn prune_backups(dir: &Path, keep: usize) -> Result<usize> {
+    let mut engine = open_existing_engine(dir)?;
+    let before = engine.get_backup_info().len();
+    
+    engine.purge_old_backups(keep)
+        .map_err(|e| XLimError::Storage(format!("Failed to prune backups: {}", e)))?;
+    
+    Ok(before - engine.get_backup_info().len())
+}
+
+/// Restore a backup, or the newest one if no ID is given, into a database directory.
+///
+/// The database must not be open; its current contents are replaced.
+pub fn restore_backup(dir: &Path, db_path: &Path, id: Option<u32>) -> Result<BackupInfo> {
+    let mut engine = open_existing_engine(dir)?;
+    let options = RestoreOptions::default();
+    
+    let backup = match id {
+        Some(id) => engine.get_backup_info().into_iter().find(|backup| backup.backup_id == id).map(BackupInfo::from),
+        None => latest_backup(&engine),
+    };
+    
+    let backup = backup.ok_or_else(|| match id {
+        Some(id) => XLimError::InvalidOperation(format!("No backup {} in {}", id, dir.display())),
+        None => XLimError::InvalidOperation(format!("No backups in {}", dir.display())),
+    })?;
+    
+    std::fs::create_dir_all(db_path)?;
+    
+    engine.restore_from_backup(db_path, db_path, &options, backup.id)
+        .map_err(|e| XLimError::Storage(format!("Failed to restore backup {}: {}", backup.id, e)))?;
+    
+    Ok(backup)
+}
+
+/// Open the backup engine of a directory
+fn open_engine(dir: &Path) -> Result<BackupEngine> {
+    let options = BackupEngineOptions::new(dir)
+        .map_err(|e| XLimError::Storage(format!("Invalid backup directory {}: {}", dir.display(), e)))?;
+    let env = Env::new()
+        .map_err(|e| XLimError::Storage(format!("Failed to create backup environment: {}", e)))?;
+    
+    BackupEngine::open(&options, &env)
+        .map_err(|e| XLimError::Storage(format!("Failed to open backup directory {}: {}", dir.display(), e)))
+}
+
+/// Open the backup engine of a directory that must already exist, rather than creating it
+fn open_existing_engine(dir: &Path) -> Result<BackupEngine> {
+    if !dir.is_dir() {
+        return Err(XLimError::InvalidOperation(format!("Backup directory {} does not exist", dir.display())));
+    }
+    
+    open_engine(dir)
+}
+
+/// Get the newest backup known to a backup engine
+fn latest_backup(engine: &BackupEngine) -> Option<BackupInfo> {
+    engine.get_backup_info()
+        .into_iter()
+        .max_by_key(|backup| backup.backup_id)
+        .map(BackupInfo::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use crate::storage::StorageEngine;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+    
+    /// A fresh directory under the system temporary directory, removed when dropped
+    struct TempDir(PathBuf);
+    
+    impl TempDir {
+        /// Pick a directory that does not exist yet
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("xlim-backup-test-{}", Uuid::new_v4())))
+        }
+        
+        /// Path of a subdirectory
+        fn join(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+    
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+    
+    /// IDs of the documents in collection "c" of a database directory
+    fn document_ids(path: &Path) -> Vec<Uuid> {
+        let storage = StorageEngine::new(path).unwrap();
+        let mut ids: Vec<Uuid> = storage.list_documents("c").unwrap().iter().map(|document| document.id).collect();
+        ids.sort();
+        ids
+    }
+    
+    #[test]
+    fn backups_restore_the_database_as_it_was() {
+        let dir = TempDir::new();
+        let backups = dir.join("backups");
+        let (a, b) = (Document::new(), Document::new());
+        
+        {
+            let storage = StorageEngine::new(dir.join("db")).unwrap();
+            storage.create_collection("c").unwrap();
+            storage.insert_document("c", &a).unwrap();
+            assert_eq!(storage.create_backup(&backups).unwrap().id, 1);
+            
+            storage.insert_document("c", &b).unwrap();
+            assert_eq!(storage.create_backup(&backups).unwrap().id, 2);
+        }
+        
+        let listed: Vec<u32> = list_backups(&backups).unwrap().iter().map(|backup| backup.id).collect();
+        assert_eq!(listed, vec![1, 2]);
+        verify_backup(&backups, 1).unwrap();
+        
+        assert_eq!(restore_backup(&backups, &dir.join("first"), Some(1)).unwrap().id, 1);
+        assert_eq!(document_ids(&dir.join("first")), vec![a.id]);
+        
+        let mut both = vec![a.id, b.id];
+        both.sort();
+        assert_eq!(restore_backup(&backups, &dir.join("latest"), None).unwrap().id, 2);
+        assert_eq!(document_ids(&dir.join("latest")), both);
+        
+        // The newest backup still restores after older ones are pruned
+        assert_eq!(prune_backups(&backups, 1).unwrap(), 1);
+        assert_eq!(list_backups(&backups).unwrap().len(), 1);
+        assert!(matches!(restore_backup(&backups, &dir.join("pruned"), Some(1)), Err(XLimError::InvalidOperation(_))));
+        
+        restore_backup(&backups, &dir.join("pruned"), Some(2)).unwrap();
+        assert_eq!(document_ids(&dir.join("pruned")), both);
+    }
+    
+    #[test]
+    fn missing_backup_directories_are_refused() {
+        let dir = TempDir::new();
+        
+        assert!(matches!(list_backups(&dir.join("none")), Err(XLimError::InvalidOperation(_))));
+        assert!(matches!(restore_backup(&dir.join("none"), &dir.join("db"), None), Err(XLimError::InvalidOperation(_))));
+        assert!(!dir.join("none").exists());
+    }
+    
+    #[test]
+    fn in_memory_databases_cannot_be_backed_up() {
+        let dir = TempDir::new();
+        let storage = StorageEngine::in_memory().unwrap();
+        
+        assert!(matches!(storage.create_backup(&dir.join("backups")), Err(XLimError::InvalidOperation(_))));
+    }
+}
@@ -1,38 +1,82 @@
-use async_trait::async_trait;
-use log::{debug, error};
-use serde_json::json;
-use std::io::{Error as IoError, ErrorKind};
-use std::net::ToSocketAddrs;
+use futures_util::{stream, Stream, TryStreamExt};
+use log::debug;
+use rand::Rng;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::net::{lookup_host, ToSocketAddrs};
 use uuid::Uuid;
 
+use crate::auth::Privilege;
+use crate::backup::BackupInfo;
+use crate::cursor::CursorBatch;
 use crate::document::Document;
 use crate::error::{Result, XLimError};
+use crate::lock::LockMode;
+use crate::planner::QueryPlan;
+use crate::pool::{Connection, Credentials, Pool, PoolConfig, TransactionPin};
+use crate::protocol::{Request, Response};
 use crate::query::{Query, QueryBuilder};
+use crate::transaction::{IsolationLevel, TransactionInfo, TransactionOptions};
+use crate::transfer::{BulkWriteResult, OnConflict};
+use crate::ttl::TtlPolicy;
+
+/// Number of times `Client::with_transaction` runs a transaction before giving up on conflicts
+const MAX_TRANSACTION_ATTEMPTS: u32 = 5;
+
+/// Number of documents `Collection::stream` asks the server for at a time
+const CURSOR_BATCH_SIZE: usize = 1000;
 
 /// A client for the XLim database
 pub struct Client {
-    /// Connection to the server
-    connection: Arc<Mutex<TcpStream>>,
+    /// Pool of connections to the server, shared by every clone of the client
+    pool: Arc<Pool>,
     
     /// Server address
     address: String,
 }
 
 impl Client {
-    /// Connect to a database server
-    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+    /// Connect to a database server with the default pool settings
+    pub async fn connect<A: ToSocketAddrs + std::fmt::Debug>(addr: A) -> Result<Self> {
+        Self::connect_with_config(addr, PoolConfig::default()).await
+    }
+    
+    /// Connect to a database server that requires authentication
+    pub async fn connect_with_credentials<A: ToSocketAddrs + std::fmt::Debug>(addr: A, username: &str, password: &str) -> Result<Self> {
+        let config = PoolConfig {
+            credentials: Some(Credentials {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            ..PoolConfig::default()
+        };
+        
+        Self::connect_with_config(addr, config).await
+    }
+    
+    /// Connect to a database server with a pool of connections
+    pub async fn connect_with_config<A: ToSocketAddrs + std::fmt::Debug>(addr: A, config: PoolConfig) -> Result<Self> {
         let addr_str = format!("{:?}", addr);
-        let stream = TcpStream::connect(addr).await?;
+        
+        // Resolve once so that broken connections can be replaced without the original address
+        let addrs: Vec<SocketAddr> = lookup_host(addr).await?.collect();
+        
+        if addrs.is_empty() {
+            return Err(XLimError::Connection(format!("No addresses found for {}", addr_str)));
+        }
+        
+        let size = config.size;
         
         let client = Self {
-            connection: Arc::new(Mutex::new(stream)),
+            pool: Pool::connect(addrs, config).await?,
             address: addr_str,
         };
         
+        debug!("Connected to {} with {} pooled connections", client.address, size);
+        
         // Test the connection
         client.ping().await?;
         
@@ -41,22 +85,15 @@ impl Client {
     
     /// Ping the server
     pub async fn ping(&self) -> Result<()> {
-        let response = self.send_command("PING").await?;
-        
-        if response.trim() != "PONG" {
-            return Err(XLimError::Connection(format!("Unexpected response from server: {}", response)));
+        match self.send(Request::Ping).await? {
+            Response::Pong => Ok(()),
+            response => Err(response.unexpected()),
         }
-        
-        Ok(())
     }
     
     /// Create a collection
     pub async fn create_collection(&self, name: &str) -> Result<Collection> {
-        let response = self.send_command(&format!("CREATE {}", name)).await?;
-        
-        if response.starts_with("ERROR:") {
-            return Err(XLimError::Database(response[7..].trim().to_string()));
-        }
+        self.send_ok(Request::CreateCollection { name: name.to_string() }).await?;
         
         Ok(Collection {
             client: self.clone(),
@@ -66,13 +103,7 @@ impl Client {
     
     /// Drop a collection
     pub async fn drop_collection(&self, name: &str) -> Result<()> {
-        let response = self.send_command(&format!("DROP {}", name)).await?;
-        
-        if response.starts_with("ERROR:") {
-            return Err(XLimError::Database(response[7..].trim().to_string()));
-        }
-        
-        Ok(())
+        self.send_ok(Request::DropCollection { name: name.to_string() }).await
     }
     
     /// Get a collection
@@ -83,55 +114,172 @@ impl Client {
         }
     }
     
-    /// Begin a transaction
-    pub async fn begin_transaction(&self) -> Result<Transaction> {
-        let response = self.send_command("BEGIN").await?;
+    /// Create a user account with roles
+    pub async fn add_user(&self, username: &str, password: &str, roles: &[&str]) -> Result<()> {
+        let request = Request::CreateUser {
+            username: username.to_string(),
+            password: password.to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        };
         
-        if response.starts_with("ERROR:") {
-            return Err(XLimError::Database(response[7..].trim().to_string()));
-        }
+        self.send_ok(request).await
+    }
+    
+    /// Delete a user account
+    pub async fn remove_user(&self, username: &str) -> Result<()> {
+        self.send_ok(Request::DropUser { username: username.to_string() }).await
+    }
+    
+    /// Replace a user's password
+    pub async fn change_password(&self, username: &str, password: &str) -> Result<()> {
+        let request = Request::ChangePassword {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
         
-        // Parse the transaction ID
-        let parts: Vec<&str> = response.trim().split(": ").collect();
+        self.send_ok(request).await
+    }
+    
+    /// Grant roles to a user
+    pub async fn grant_roles(&self, username: &str, roles: &[&str]) -> Result<()> {
+        let request = Request::GrantRoles {
+            username: username.to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        };
         
-        if parts.len() != 2 {
-            return Err(XLimError::Database(format!("Invalid response from server: {}", response)));
-        }
+        self.send_ok(request).await
+    }
+    
+    /// Revoke roles from a user
+    pub async fn revoke_roles(&self, username: &str, roles: &[&str]) -> Result<()> {
+        let request = Request::RevokeRoles {
+            username: username.to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        };
         
-        let transaction_id = Uuid::parse_str(parts[1])
-            .map_err(|_| XLimError::Database(format!("Invalid transaction ID: {}", parts[1])))?;
+        self.send_ok(request).await
+    }
+    
+    /// Create a custom role
+    pub async fn create_role(&self, name: &str, privileges: Vec<Privilege>) -> Result<()> {
+        self.send_ok(Request::CreateRole { name: name.to_string(), privileges }).await
+    }
+    
+    /// Delete a custom role
+    pub async fn drop_role(&self, name: &str) -> Result<()> {
+        self.send_ok(Request::DropRole { name: name.to_string() }).await
+    }
+    
+    /// Begin a transaction with snapshot isolation and the server's default timeout
+    pub async fn begin_transaction(&self) -> Result<Transaction> {
+        self.begin_transaction_with(TransactionOptions::default()).await
+    }
+    
+    /// Begin a transaction with the given isolation level
+    pub async fn begin_transaction_with_isolation(&self, isolation: IsolationLevel) -> Result<Transaction> {
+        self.begin_transaction_with(TransactionOptions { isolation, ..Default::default() }).await
+    }
+    
+    /// Begin a transaction with the given options.
+    ///
+    /// Every request of the transaction is sent on the same pooled connection. If that
+    /// connection closes, the server rolls the transaction back.
+    pub async fn begin_transaction_with(&self, options: TransactionOptions) -> Result<Transaction> {
+        let connection = self.pool.get().await?;
+        
+        // Pinned before the transaction begins, so that no health check can close the
+        // connection under it
+        let pin = Arc::new(connection.pin());
+        
+        let transaction_id = match connection.send(Request::Begin { options }).await? {
+            Response::TransactionId(id) => id,
+            response => return Err(response.unexpected()),
+        };
         
         Ok(Transaction {
-            client: self.clone(),
+            connection,
+            pin,
             id: transaction_id,
         })
     }
     
-    /// Send a command to the server
-    async fn send_command(&self, command: &str) -> Result<String> {
-        let mut connection = self.connection.lock().await;
-        
-        // Send the command
-        connection.write_all(command.as_bytes()).await?;
-        
-        // Read the response
-        let mut buffer = [0; 4096];
-        let n = connection.read(&mut buffer).await?;
-        
-        if n == 0 {
-            return Err(XLimError::Connection("Connection closed by server".to_string()));
+    /// List the transactions that are active on the server
+    pub async fn active_transactions(&self) -> Result<Vec<TransactionInfo>> {
+        match self.send(Request::ListTransactions).await? {
+            Response::Transactions(transactions) => Ok(transactions),
+            response => Err(response.unexpected()),
         }
+    }
+    
+    /// Back up the database into a directory on the server's filesystem. Backups made into the
+    /// same directory share their unchanged files, so only the first one copies everything.
+    pub async fn backup(&self, dir: impl AsRef<Path>) -> Result<BackupInfo> {
+        let request = Request::Backup {
+            dir: dir.as_ref().to_path_buf(),
+        };
         
-        let response = String::from_utf8_lossy(&buffer[..n]).to_string();
-        
-        Ok(response.trim().to_string())
+        match self.send(request).await? {
+            Response::Backup(backup) => Ok(backup),
+            response => Err(response.unexpected()),
+        }
+    }
+    
+    /// Run a function in a transaction, committing if it succeeds and rolling back if it fails.
+    ///
+    /// When the transaction conflicts with another one, the whole function is run again in a
+    /// new transaction, a few times at most, so it must not have side effects outside of it.
+    pub async fn with_transaction<T, F, Fut>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(Transaction) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        
+        loop {
+            let transaction = self.begin_transaction().await?;
+            
+            let result = match f(transaction.clone()).await {
+                Ok(value) => transaction.commit().await.map(|_| value),
+                Err(e) => {
+                    // The error that made the function fail matters more than a failed rollback
+                    let _ = transaction.rollback().await;
+                    Err(e)
+                }
+            };
+            
+            match result {
+                Err(e) if e.is_conflict() && attempt < MAX_TRANSACTION_ATTEMPTS => {
+                    debug!("Transaction {} failed with a conflict, retrying: {}", transaction.id(), e);
+                    
+                    // Random backoff, so that transactions conflicting with each other spread out
+                    let delay = rand::thread_rng().gen_range(0..10u64 << attempt);
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+    
+    /// Send a request to the server and wait for its response
+    async fn send(&self, request: Request) -> Result<Response> {
+        self.pool.send(request).await
+    }
+    
+    /// Send a request that is answered with a plain acknowledgement
+    async fn send_ok(&self, request: Request) -> Result<()> {
+        match self.send(request).await? {
+            Response::Ok => Ok(()),
+            response => Err(response.unexpected()),
+        }
     }
 }
 
 impl Clone for Client {
     fn clone(&self) -> Self {
         Self {
-            connection: self.connection.clone(),
+            pool: self.pool.clone(),
             address: self.address.clone(),
         }
     }
@@ -152,99 +300,171 @@ impl Collection {
         &self.name
     }
     
-    /// Insert a document into the collection
+    /// Insert a document into the collection, failing if one with the same ID exists
     pub async fn insert(&self, document: Document) -> Result<Uuid> {
-        let json = document.to_json()?;
-        let response = self.client.send_command(&format!("INSERT {} {}", self.name, json)).await?;
+        let request = Request::Insert {
+            collection: self.name.clone(),
+            document,
+        };
         
-        if response.starts_with("ERROR:") {
-            return Err(XLimError::Database(response[7..].trim().to_string()));
+        match self.client.send(request).await? {
+            Response::DocumentId(id) => Ok(id),
+            response => Err(response.unexpected()),
         }
+    }
+    
+    /// Insert a batch of documents in a single atomic write. Documents whose ID is already
+    /// taken are handled as `on_conflict` says.
+    pub async fn insert_many(&self, documents: Vec<Document>, on_conflict: OnConflict) -> Result<BulkWriteResult> {
+        let request = Request::InsertMany {
+            collection: self.name.clone(),
+            documents,
+            on_conflict,
+        };
         
-        // Parse the document ID
-        let parts: Vec<&str> = response.trim().split(": ").collect();
-        
-        if parts.len() != 2 {
-            return Err(XLimError::Database(format!("Invalid response from server: {}", response)));
+        match self.client.send(request).await? {
+            Response::BulkWrite(result) => Ok(result),
+            response => Err(response.unexpected()),
         }
-        
-        let document_id = Uuid::parse_str(parts[1])
-            .map_err(|_| XLimError::Database(format!("Invalid document ID: {}", parts[1])))?;
-        
-        Ok(document_id)
     }
     
     /// Get a document from the collection
     pub async fn get(&self, id: &str) -> Result<Document> {
-        let response = self.client.send_command(&format!("GET {} {}", self.name, id)).await?;
+        let request = Request::Get {
+            collection: self.name.clone(),
+            id: id.to_string(),
+        };
         
-        if response.starts_with("ERROR:") {
-            return Err(XLimError::Database(response[7..].trim().to_string()));
+        match self.client.send(request).await? {
+            Response::Document(document) => Ok(document),
+            response => Err(response.unexpected()),
         }
-        
-        let document = Document::from_json(&response)?;
-        
-        Ok(document)
     }
     
     /// Update a document in the collection
     pub async fn update(&self, document: Document) -> Result<()> {
-        let json = document.to_json()?;
-        let response = self.client.send_command(&format!("UPDATE {} {}", self.name, json)).await?;
-        
-        if response.starts_with("ERROR:") {
-            return Err(XLimError::Database(response[7..].trim().to_string()));
-        }
+        let request = Request::Update {
+            collection: self.name.clone(),
+            document,
+        };
         
-        Ok(())
+        self.client.send_ok(request).await
     }
     
     /// Delete a document from the collection
     pub async fn delete(&self, id: &str) -> Result<()> {
-        let response = self.client.send_command(&format!("DELETE {} {}", self.name, id)).await?;
-        
-        if response.starts_with("ERROR:") {
-            return Err(XLimError::Database(response[7..].trim().to_string()));
-        }
+        let request = Request::Delete {
+            collection: self.name.clone(),
+            id: id.to_string(),
+        };
         
-        Ok(())
+        self.client.send_ok(request).await
     }
     
-    /// List all documents in the collection
+    /// List all documents in the collection.
+    ///
+    /// Every document is loaded into memory; use [`Collection::stream`] for large collections.
     pub async fn list(&self) -> Result<Vec<Document>> {
-        let response = self.client.send_command(&format!("LIST {}", self.name)).await?;
-        
-        if response.starts_with("ERROR:") {
-            return Err(XLimError::Database(response[7..].trim().to_string()));
-        }
+        self.stream().try_collect().await
+    }
+    
+    /// Stream the documents of the collection, fetched from a server-side cursor in batches.
+    ///
+    /// The documents are read from a snapshot taken when the stream is first polled. Dropping
+    /// the stream early closes the cursor.
+    pub fn stream(&self) -> impl Stream<Item = Result<Document>> {
+        let client = self.client.clone();
+        let name = self.name.clone();
+        
+        stream::try_unfold(CursorState::Unopened, move |state| {
+            let client = client.clone();
+            let name = name.clone();
+            
+            async move {
+                let (connection, request) = match state {
+                    // Every batch must come from the connection that opened the cursor
+                    CursorState::Unopened => {
+                        let request = Request::OpenCursor { collection: name, batch_size: CURSOR_BATCH_SIZE };
+                        (client.pool.get().await?, request)
+                    }
+                    CursorState::Open(mut cursor) => {
+                        let request = Request::GetMore { cursor_id: cursor.id, batch_size: CURSOR_BATCH_SIZE };
+                        (cursor.finish(), request)
+                    }
+                    CursorState::Exhausted => return Ok(None),
+                };
+                
+                let batch: CursorBatch = match connection.send(request).await? {
+                    Response::Cursor(batch) => batch,
+                    response => return Err(response.unexpected()),
+                };
+                
+                let next = match batch.cursor_id {
+                    Some(id) => CursorState::Open(OpenCursor { connection: Some(connection), id }),
+                    None => CursorState::Exhausted,
+                };
+                
+                Ok(Some((stream::iter(batch.documents.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
+    }
+    
+    /// Create a secondary index on a field
+    pub async fn create_index(&self, field: &str) -> Result<()> {
+        let request = Request::CreateIndex {
+            collection: self.name.clone(),
+            field: field.to_string(),
+        };
         
-        // Parse the document IDs
-        let lines: Vec<&str> = response.trim().split('\n').collect();
+        self.client.send_ok(request).await
+    }
+    
+    /// Drop the secondary index on a field
+    pub async fn drop_index(&self, field: &str) -> Result<()> {
+        let request = Request::DropIndex {
+            collection: self.name.clone(),
+            field: field.to_string(),
+        };
         
-        if lines.len() < 1 {
-            return Err(XLimError::Database("Invalid response from server".to_string()));
-        }
+        self.client.send_ok(request).await
+    }
+    
+    /// Make the collection's documents expire under a TTL policy, or stop them expiring with
+    /// `None`. Documents with their own `expires_at` expire then instead.
+    pub async fn set_ttl(&self, policy: Option<TtlPolicy>) -> Result<()> {
+        let request = Request::SetTtl {
+            collection: self.name.clone(),
+            policy,
+        };
         
-        let mut documents = Vec::new();
+        self.client.send_ok(request).await
+    }
+    
+    /// Run a query on the server and return the matching documents
+    pub async fn query(&self, query: Query) -> Result<Vec<Document>> {
+        let request = Request::Query {
+            collection: self.name.clone(),
+            query,
+        };
         
-        for i in 1..lines.len() {
-            let line = lines[i];
-            
-            if line.starts_with("- ") {
-                let parts: Vec<&str> = line[2..].split(": ").collect();
-                
-                if parts.len() == 2 {
-                    let id = parts[0];
-                    
-                    match self.get(id).await {
-                        Ok(document) => documents.push(document),
-                        Err(e) => error!("Failed to get document {}: {}", id, e),
-                    }
-                }
-            }
+        match self.client.send(request).await? {
+            Response::Documents(documents) => Ok(documents),
+            response => Err(response.unexpected()),
         }
+    }
+    
+    /// Run a query on the server and return the plan used to answer it
+    pub async fn explain(&self, query: Query) -> Result<QueryPlan> {
+        let request = Request::Explain {
+            collection: self.name.clone(),
+            query,
+        };
         
-        Ok(documents)
+        match self.client.send(request).await? {
+            Response::Plan(plan) => Ok(plan),
+            response => Err(response.unexpected()),
+        }
     }
     
     /// Create a query builder for this collection
@@ -313,19 +533,22 @@ impl CollectionQueryBuilder {
     
     /// Execute the query
     pub async fn execute(&self) -> Result<Vec<Document>> {
-        // For now, we'll just list all documents and filter them client-side
-        // In a real implementation, we would send the query to the server
-        let documents = self.collection.list().await?;
-        let query = self.query_builder.build();
-        
-        query.apply(documents)
+        self.collection.query(self.query_builder.build()).await
+    }
+    
+    /// Run the query and return the plan the server chose, with its statistics
+    pub async fn explain(&self) -> Result<QueryPlan> {
+        self.collection.explain(self.query_builder.build()).await
     }
 }
 
 /// A transaction in the database
 pub struct Transaction {
-    /// Client connection
-    client: Client,
+    /// Connection the transaction was begun on, which all of its requests use
+    connection: Arc<Connection>,
+    
+    /// Keeps the connection open while the transaction is, shared by every clone
+    pin: Arc<TransactionPin>,
     
     /// Transaction ID
     id: Uuid,
@@ -339,23 +562,219 @@ impl Transaction {
     
     /// Commit the transaction
     pub async fn commit(&self) -> Result<()> {
-        let response = self.client.send_command(&format!("COMMIT {}", self.id)).await?;
+        let result = self.send_ok(Request::Commit { transaction_id: self.id }).await;
         
-        if response.starts_with("ERROR:") {
-            return Err(XLimError::Database(response[7..].trim().to_string()));
-        }
+        // The server ends the transaction whether or not the commit succeeds
+        self.pin.release();
         
-        Ok(())
+        result
     }
     
     /// Rollback the transaction
     pub async fn rollback(&self) -> Result<()> {
-        let response = self.client.send_command(&format!("ROLLBACK {}", self.id)).await?;
+        let result = self.send_ok(Request::Rollback { transaction_id: self.id }).await;
+        self.pin.release();
+        
+        result
+    }
+    
+    /// Set a savepoint that the transaction can later be rolled back to
+    pub async fn savepoint(&self, name: &str) -> Result<()> {
+        self.send_ok(Request::Savepoint { transaction_id: self.id, name: name.to_string() }).await
+    }
+    
+    /// Undo everything the transaction did since a savepoint, keeping the transaction open
+    pub async fn rollback_to(&self, name: &str) -> Result<()> {
+        self.send_ok(Request::RollbackToSavepoint { transaction_id: self.id, name: name.to_string() }).await
+    }
+    
+    /// Remove a savepoint, keeping what the transaction did since it
+    pub async fn release(&self, name: &str) -> Result<()> {
+        self.send_ok(Request::ReleaseSavepoint { transaction_id: self.id, name: name.to_string() }).await
+    }
+    
+    /// Get a handle for working with a collection inside the transaction
+    pub fn collection(&self, name: &str) -> TransactionCollection {
+        TransactionCollection {
+            transaction: self.clone(),
+            name: name.to_string(),
+        }
+    }
+    
+    /// Get a document as the transaction sees it, including its own uncommitted writes
+    pub async fn get(&self, collection: &str, id: &str) -> Result<Document> {
+        let request = Request::TransactionGet {
+            transaction_id: self.id,
+            collection: collection.to_string(),
+            id: id.to_string(),
+        };
         
-        if response.starts_with("ERROR:") {
-            return Err(XLimError::Database(response[7..].trim().to_string()));
+        match self.connection.send(request).await? {
+            Response::Document(document) => Ok(document),
+            response => Err(response.unexpected()),
         }
+    }
+    
+    /// Find the documents in a collection that match a query as the transaction sees them
+    pub async fn find(&self, collection: &str, query: Query) -> Result<Vec<Document>> {
+        let request = Request::TransactionQuery {
+            transaction_id: self.id,
+            collection: collection.to_string(),
+            query,
+        };
         
-        Ok(())
+        match self.connection.send(request).await? {
+            Response::Documents(documents) => Ok(documents),
+            response => Err(response.unexpected()),
+        }
     }
-} 
\ No newline at end of file
+    
+    /// Lock a document until the transaction commits or rolls back, waiting for other
+    /// transactions that hold a conflicting lock on it
+    pub async fn lock(&self, collection: &str, id: &str, mode: LockMode) -> Result<()> {
+        let request = Request::Lock {
+            transaction_id: self.id,
+            collection: collection.to_string(),
+            id: id.to_string(),
+            mode,
+        };
+        
+        self.send_ok(request).await
+    }
+    
+    /// Send a request on the transaction's connection that expects an `Ok` response
+    async fn send_ok(&self, request: Request) -> Result<()> {
+        match self.connection.send(request).await? {
+            Response::Ok => Ok(()),
+            response => Err(response.unexpected()),
+        }
+    }
+}
+
+impl Clone for Transaction {
+    fn clone(&self) -> Self {
+        Self {
+            connection: self.connection.clone(),
+            pin: self.pin.clone(),
+            id: self.id,
+        }
+    }
+}
+
+/// Progress of a stream of documents through a server-side cursor
+enum CursorState {
+    /// The cursor has not been opened yet
+    Unopened,
+    /// The cursor is open and has more documents
+    Open(OpenCursor),
+    /// Every document has been read and the server has closed the cursor
+    Exhausted,
+}
+
+/// A server-side cursor that has more documents, closed on drop unless it was read to the end
+struct OpenCursor {
+    /// Connection the cursor was opened on, taken when the cursor is finished
+    connection: Option<Arc<Connection>>,
+    
+    /// Cursor ID
+    id: Uuid,
+}
+
+impl OpenCursor {
+    /// Take the connection to ask for the next batch, which leaves the server to close the
+    /// cursor if it is the last one
+    fn finish(&mut self) -> Arc<Connection> {
+        self.connection.take().expect("cursor already finished")
+    }
+}
+
+impl Drop for OpenCursor {
+    fn drop(&mut self) {
+        let (Some(connection), Ok(runtime)) = (self.connection.take(), tokio::runtime::Handle::try_current()) else {
+            return;
+        };
+        
+        let request = Request::CloseCursor { cursor_id: self.id };
+        
+        runtime.spawn(async move {
+            // The cursor times out on the server anyway if this fails
+            let _ = connection.send(request).await;
+        });
+    }
+}
+
+/// A collection as seen from inside a transaction.
+///
+/// Writes are buffered on the server until the transaction commits.
+pub struct TransactionCollection {
+    /// Transaction the requests belong to
+    transaction: Transaction,
+    
+    /// Collection name
+    name: String,
+}
+
+impl TransactionCollection {
+    /// Get the collection name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    
+    /// Insert a document when the transaction commits
+    pub async fn insert(&self, document: Document) -> Result<Uuid> {
+        let request = Request::TransactionInsert {
+            transaction_id: self.transaction.id,
+            collection: self.name.clone(),
+            document,
+        };
+        
+        match self.transaction.connection.send(request).await? {
+            Response::DocumentId(id) => Ok(id),
+            response => Err(response.unexpected()),
+        }
+    }
+    
+    /// Get a document as the transaction sees it
+    pub async fn get(&self, id: &str) -> Result<Document> {
+        self.transaction.get(&self.name, id).await
+    }
+    
+    /// Lock a document until the transaction ends
+    pub async fn lock(&self, id: &str, mode: LockMode) -> Result<()> {
+        self.transaction.lock(&self.name, id, mode).await
+    }
+    
+    /// Lock a document exclusively and get its latest version, so that the transaction can
+    /// update it without other locking transactions changing it in between
+    pub async fn get_for_update(&self, id: &str) -> Result<Document> {
+        self.lock(id, LockMode::Exclusive).await?;
+        self.get(id).await
+    }
+    
+    /// Update a document when the transaction commits
+    pub async fn update(&self, document: Document) -> Result<()> {
+        let request = Request::TransactionUpdate {
+            transaction_id: self.transaction.id,
+            collection: self.name.clone(),
+            document,
+        };
+        
+        self.transaction.send_ok(request).await
+    }
+    
+    /// Delete a document when the transaction commits
+    pub async fn delete(&self, id: &str) -> Result<()> {
+        let request = Request::TransactionDelete {
+            transaction_id: self.transaction.id,
+            collection: self.name.clone(),
+            id: id.to_string(),
+        };
+        
+        self.transaction.send_ok(request).await
+    }
+    
+    /// Find the documents that match a query as the transaction sees them
+    pub async fn find(&self, query: Query) -> Result<Vec<Document>> {
+        self.transaction.find(&self.name, query).await
+    }
+}
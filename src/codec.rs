@@ -0,0 +1,213 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::{Result, XLimError};
+
+/// Format of the values written by this version, stored as their first byte
+pub const CURRENT_FORMAT: u8 = 1;
+
+/// First byte of the JSON objects that some values were written as before formats were
+/// versioned
+const LEGACY_JSON: u8 = b'{';
+
+/// A type the storage engine stores, which may have been written before formats were versioned
+pub(crate) trait Stored: Serialize + DeserializeOwned {
+    /// Layout the type was written in by the original storage engine, with bincode
+    type Bincode: DeserializeOwned + Into<Self>;
+}
+
+/// Encode a value for storage: a format byte followed by the value as MessagePack.
+///
+/// MessagePack is self-describing, so values holding arbitrary JSON, like documents, decode
+/// back to what was stored. Structs are encoded as maps keyed by field name, so fields can be
+/// added with a default later without breaking stored values.
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut bytes = vec![CURRENT_FORMAT];
+    
+    rmp_serde::encode::write_named(&mut bytes, value)
+        .map_err(|e| XLimError::Storage(format!("Failed to encode value: {}", e)))?;
+    
+    Ok(bytes)
+}
+
+/// Decode a stored value.
+///
+/// Fails cleanly for values written in a format this version does not know, such as one
+/// from a newer version; values written before formats were versioned must be migrated with
+/// [`decode_unversioned`] first.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    match bytes.first() {
+        Some(&CURRENT_FORMAT) => rmp_serde::from_slice(&bytes[1..])
+            .map_err(|e| XLimError::Storage(format!("Failed to decode value: {}", e))),
+        Some(format) => Err(XLimError::Storage(format!("Unsupported storage format: {}", format))),
+        None => Err(XLimError::Storage("Cannot decode an empty value".to_string())),
+    }
+}
+
+/// Decode a value that may have been written before formats were versioned.
+///
+/// Such values were bincode, as the original storage engine wrote them, or plain JSON. Their
+/// format is not marked, so each is tried in turn, starting with the current format for
+/// values rewritten by a migration that was cut short. Bincode cannot store arbitrary JSON
+/// values, so bincode documents only decode if their data is empty.
+pub(crate) fn decode_unversioned<T: Stored>(bytes: &[u8]) -> Result<T> {
+    if let Ok(value) = decode(bytes) {
+        return Ok(value);
+    }
+    
+    if bytes.first() == Some(&LEGACY_JSON) {
+        if let Ok(value) = serde_json::from_slice(bytes) {
+            return Ok(value);
+        }
+    }
+    
+    bincode::deserialize::<T::Bincode>(bytes)
+        .map(Into::into)
+        .map_err(|e| XLimError::Storage(format!("Failed to decode unversioned value: {}", e)))
+}
+
+/// Check whether a stored value is in the current format, or must be migrated
+pub fn is_current(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&CURRENT_FORMAT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{Collection, Document};
+    use serde_json::{json, Map, Value};
+    
+    /// Every shape of JSON value, including the edges of each number representation
+    fn values() -> Vec<Value> {
+        vec![
+            Value::Null,
+            json!(true),
+            json!(false),
+            json!(0),
+            json!(42),
+            json!(-42),
+            json!(i64::MIN),
+            json!(i64::MAX),
+            json!(u64::MAX),
+            json!(0.0),
+            json!(-1.5),
+            json!(2.0),
+            json!(f64::MIN_POSITIVE),
+            json!(f64::MAX),
+            json!(""),
+            json!("plain"),
+            json!("unicode: é ✓ 😀 \u{0}"),
+            json!([]),
+            json!([1, "two", null, [3.5, false], {"four": 4}]),
+            json!({}),
+            json!({"nested": {"deeper": {"list": [{"x": 1}], "empty": {}}}}),
+        ]
+    }
+    
+    #[test]
+    fn values_round_trip() {
+        for value in values() {
+            let bytes = encode(&value).unwrap();
+            
+            assert!(is_current(&bytes));
+            assert_eq!(decode::<Value>(&bytes).unwrap(), value);
+        }
+    }
+    
+    #[test]
+    fn numbers_keep_their_representation() {
+        for value in [json!(7), json!(-7), json!(u64::MAX), json!(7.0)] {
+            let decoded: Value = decode(&encode(&value).unwrap()).unwrap();
+            
+            assert_eq!(decoded.is_u64(), value.is_u64());
+            assert_eq!(decoded.is_i64(), value.is_i64());
+            assert_eq!(decoded.is_f64(), value.is_f64());
+        }
+    }
+    
+    #[test]
+    fn document_round_trips() {
+        let mut document = Document::new();
+        
+        for (i, value) in values().into_iter().enumerate() {
+            document = document.set(&format!("field{}", i), value);
+        }
+        
+        let decoded: Document = decode(&encode(&document).unwrap()).unwrap();
+        
+        assert_eq!(decoded.id, document.id);
+        assert_eq!(decoded.created_at, document.created_at);
+        assert_eq!(decoded.updated_at, document.updated_at);
+        assert_eq!(decoded.data, document.data);
+    }
+    
+    #[test]
+    fn collection_round_trips() {
+        let mut collection = Collection::new("users");
+        collection.set_metadata("indexes", json!(["email", "age"]));
+        collection.set_metadata("options", json!({"capped": false, "size": 1024}));
+        
+        let decoded: Collection = decode(&encode(&collection).unwrap()).unwrap();
+        
+        assert_eq!(decoded.name, collection.name);
+        assert_eq!(decoded.created_at, collection.created_at);
+        assert_eq!(decoded.metadata, collection.metadata);
+        assert_eq!(decoded.indexes(), vec!["email", "age"]);
+    }
+    
+    #[test]
+    fn legacy_json_is_decoded() {
+        let document = Document::new().set("name", "Jane").set("tags", json!(["a", "b"]));
+        let legacy = serde_json::to_vec(&document).unwrap();
+        
+        assert!(!is_current(&legacy));
+        assert!(decode::<Document>(&legacy).is_err());
+        
+        let decoded: Document = decode_unversioned(&legacy).unwrap();
+        
+        assert_eq!(decoded.id, document.id);
+        assert_eq!(decoded.data, document.data);
+    }
+    
+    #[test]
+    fn legacy_bincode_is_decoded() {
+        let document = Document::new();
+        let legacy = bincode::serialize(&(document.id, document.created_at, document.updated_at, Map::<String, Value>::new())).unwrap();
+        
+        let decoded: Document = decode_unversioned(&legacy).unwrap();
+        
+        assert_eq!(decoded.id, document.id);
+        assert_eq!(decoded.created_at, document.created_at);
+        assert!(decoded.data.is_empty());
+        assert!(decoded.expires_at.is_none());
+        
+        // A one-letter name makes the bincode start with the current format byte
+        let collection = Collection::new("u");
+        let legacy = bincode::serialize(&(&collection.name, collection.created_at, collection.updated_at, &collection.metadata)).unwrap();
+        
+        assert_eq!(legacy[0], CURRENT_FORMAT);
+        
+        let decoded: Collection = decode_unversioned(&legacy).unwrap();
+        
+        assert_eq!(decoded.name, "u");
+        assert_eq!(decoded.id, 0);
+        assert_eq!(decoded.created_at, collection.created_at);
+    }
+    
+    #[test]
+    fn migrated_values_are_decoded_as_unversioned() {
+        let document = Document::new().set("name", "Jane");
+        
+        let decoded: Document = decode_unversioned(&encode(&document).unwrap()).unwrap();
+        
+        assert_eq!(decoded.data, document.data);
+    }
+    
+    #[test]
+    fn unknown_formats_are_rejected() {
+        let mut bytes = encode(&json!({"x": 1})).unwrap();
+        bytes[0] = CURRENT_FORMAT + 1;
+        
+        assert!(decode::<Value>(&bytes).is_err());
+        assert!(decode::<Value>(&[]).is_err());
+    }
+}
@@ -1,5 +1,19 @@
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::time::Duration;
+
+/// Where the storage engine keeps its data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// A RocksDB database in the data directory
+    #[default]
+    #[value(name = "rocksdb")]
+    RocksDb,
+    /// Memory only, lost when the server stops
+    Memory,
+}
 
 /// Configuration for the XLim database
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,19 +29,76 @@ pub struct Config {
     
     /// Cache size in megabytes
     pub cache_size_mb: usize,
+    
+    /// Storage backend to keep the data in
+    #[serde(default)]
+    pub backend: BackendKind,
+    
+    /// Require clients to authenticate before running any other command
+    #[serde(default)]
+    pub auth_enabled: bool,
+    
+    /// Flush the write-ahead log to disk before a commit is acknowledged
+    #[serde(default)]
+    pub sync_commits: bool,
+    
+    /// Seconds a transaction may stay open before it is aborted, unless it asks for a different timeout
+    #[serde(default = "default_transaction_timeout_secs")]
+    pub transaction_timeout_secs: u64,
+    
+    /// Seconds a transaction waits for a document lock before giving up
+    #[serde(default = "default_lock_timeout_secs")]
+    pub lock_timeout_secs: u64,
+    
+    /// Seconds a cursor may go unused before it is closed
+    #[serde(default = "default_cursor_timeout_secs")]
+    pub cursor_timeout_secs: u64,
+    
+    /// Seconds between purges of expired documents
+    #[serde(default = "default_ttl_interval_secs")]
+    pub ttl_interval_secs: u64,
 }
 
-impl Config {
+/// Default for `Config::transaction_timeout_secs`, also used for configuration files without it
+fn default_transaction_timeout_secs() -> u64 {
+    60
+}
+
+/// Default for `Config::lock_timeout_secs`, also used for configuration files without it
+fn default_lock_timeout_secs() -> u64 {
+    10
+}
+
+/// Default for `Config::cursor_timeout_secs`, also used for configuration files without it
+fn default_cursor_timeout_secs() -> u64 {
+    600
+}
+
+/// Default for `Config::ttl_interval_secs`, also used for configuration files without it
+fn default_ttl_interval_secs() -> u64 {
+    60
+}
+
+impl Default for Config {
     /// Create a new configuration with default values
-    pub fn default() -> Self {
+    fn default() -> Self {
         Self {
             port: 7878,
             data_dir: PathBuf::from("./data"),
             max_connections: 100,
             cache_size_mb: 128,
+            backend: BackendKind::RocksDb,
+            auth_enabled: false,
+            sync_commits: false,
+            transaction_timeout_secs: default_transaction_timeout_secs(),
+            lock_timeout_secs: default_lock_timeout_secs(),
+            cursor_timeout_secs: default_cursor_timeout_secs(),
+            ttl_interval_secs: default_ttl_interval_secs(),
         }
     }
-    
+}
+
+impl Config {
     /// Load configuration from a file
     pub fn from_file(path: &str) -> crate::error::Result<Self> {
         let contents = std::fs::read_to_string(path)?;
@@ -42,6 +113,26 @@ impl Config {
         Ok(())
     }
     
+    /// Get the default transaction timeout
+    pub fn transaction_timeout(&self) -> Duration {
+        Duration::from_secs(self.transaction_timeout_secs)
+    }
+    
+    /// Get how long a transaction waits for a document lock
+    pub fn lock_timeout(&self) -> Duration {
+        Duration::from_secs(self.lock_timeout_secs)
+    }
+    
+    /// Get how long a cursor may go unused
+    pub fn cursor_timeout(&self) -> Duration {
+        Duration::from_secs(self.cursor_timeout_secs)
+    }
+    
+    /// Get how often expired documents are purged
+    pub fn ttl_interval(&self) -> Duration {
+        Duration::from_secs(self.ttl_interval_secs)
+    }
+    
     /// Get the path to the database files
     pub fn db_path(&self) -> PathBuf {
         self.data_dir.join("db")
@@ -0,0 +1,269 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::document::Document;
+use crate::error::{Result, XLimError};
+use crate::storage::{Snapshot, StorageEngine};
+
+/// How often the reaper looks for cursors that have not been used within the timeout
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest number of documents returned in one batch, whatever the client asks for
+pub const MAX_BATCH_SIZE: usize = 10_000;
+
+/// A batch of documents read through a cursor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CursorBatch {
+    /// Cursor to ask for the next batch with, or `None` if the collection has been read to
+    /// the end and the cursor is closed
+    pub cursor_id: Option<Uuid>,
+    
+    /// Documents in the batch
+    pub documents: Vec<Document>,
+}
+
+/// An open server-side cursor
+struct Cursor {
+    /// Collection being read
+    collection: String,
+    
+    /// View of the database taken when the cursor was opened, which every batch reads from
+    snapshot: Arc<Snapshot>,
+    
+    /// ID of the last document returned, which the next batch starts after
+    last: Option<Uuid>,
+    
+    /// Connection that opened the cursor, which is the only one that may use it
+    owner: Option<u64>,
+    
+    /// When the cursor was last used
+    last_used: Instant,
+}
+
+/// Cursors that clients read collections through in batches.
+///
+/// A cursor reads from a snapshot, so its batches together are a consistent view of the
+/// collection, and only keeps its position between batches. Cursors that go unused for longer
+/// than the timeout are closed.
+pub struct CursorManager {
+    /// Storage engine
+    storage: Arc<StorageEngine>,
+    
+    /// Open cursors
+    cursors: Mutex<HashMap<Uuid, Cursor>>,
+    
+    /// How long a cursor may go unused before it is closed
+    timeout: Duration,
+}
+
+impl CursorManager {
+    /// Create a cursor manager with no open cursors
+    pub fn new(storage: Arc<StorageEngine>, timeout: Duration) -> Self {
+        Self {
+            storage,
+            cursors: Mutex::new(HashMap::new()),
+            timeout,
+        }
+    }
+    
+    /// Open a cursor over a collection and read its first batch.
+    ///
+    /// If the whole collection fits in the batch, no cursor is left open.
+    pub fn open(&self, collection: &str, batch_size: usize, owner: Option<u64>) -> Result<CursorBatch> {
+        let cursor = Cursor {
+            collection: collection.to_string(),
+            snapshot: Arc::new(self.storage.snapshot()),
+            last: None,
+            owner,
+            last_used: Instant::now(),
+        };
+        
+        self.next_batch(Uuid::new_v4(), cursor, batch_size)
+    }
+    
+    /// Read the next batch from a cursor
+    pub fn get_more(&self, cursor_id: Uuid, batch_size: usize, owner: Option<u64>) -> Result<CursorBatch> {
+        let cursor = self.take(cursor_id, owner)?;
+        
+        self.next_batch(cursor_id, cursor, batch_size)
+    }
+    
+    /// Close a cursor before it has been read to the end
+    pub fn close(&self, cursor_id: Uuid, owner: Option<u64>) -> Result<()> {
+        self.take(cursor_id, owner)?;
+        
+        Ok(())
+    }
+    
+    /// Read a batch from a cursor, putting the cursor back if there is more to read
+    fn next_batch(&self, cursor_id: Uuid, mut cursor: Cursor, batch_size: usize) -> Result<CursorBatch> {
+        let batch_size = batch_size.clamp(1, MAX_BATCH_SIZE);
+        let mut documents = Vec::new();
+        let mut exhausted = true;
+        
+        for document in self.storage.cursor(&cursor.collection, cursor.last.as_ref(), Some(&cursor.snapshot))? {
+            if documents.len() == batch_size {
+                exhausted = false;
+                break;
+            }
+            
+            documents.push(document?);
+        }
+        
+        if exhausted {
+            return Ok(CursorBatch {
+                cursor_id: None,
+                documents,
+            });
+        }
+        
+        cursor.last = documents.last().map(|document| document.id);
+        cursor.last_used = Instant::now();
+        self.cursors.lock().unwrap().insert(cursor_id, cursor);
+        
+        Ok(CursorBatch {
+            cursor_id: Some(cursor_id),
+            documents,
+        })
+    }
+    
+    /// Remove an open cursor so that it can be used, checking that it belongs to the
+    /// connection and has not timed out
+    fn take(&self, cursor_id: Uuid, owner: Option<u64>) -> Result<Cursor> {
+        let mut cursors = self.cursors.lock().unwrap();
+        
+        let cursor = match cursors.get(&cursor_id) {
+            Some(cursor) if cursor.owner == owner => cursors.remove(&cursor_id).unwrap(),
+            _ => return Err(XLimError::InvalidOperation(format!("Cursor not found: {}", cursor_id))),
+        };
+        
+        if cursor.last_used.elapsed() >= self.timeout {
+            return Err(XLimError::Timeout(format!("Cursor timed out: {}", cursor_id)));
+        }
+        
+        Ok(cursor)
+    }
+    
+    /// Close every cursor that has gone unused for longer than the timeout, returning how many
+    /// there were
+    pub fn close_expired(&self) -> usize {
+        let mut cursors = self.cursors.lock().unwrap();
+        let before = cursors.len();
+        
+        cursors.retain(|_, cursor| cursor.last_used.elapsed() < self.timeout);
+        
+        before - cursors.len()
+    }
+    
+    /// Close every cursor opened by a connection, returning how many there were
+    pub fn close_owned_by(&self, owner: u64) -> usize {
+        let mut cursors = self.cursors.lock().unwrap();
+        let before = cursors.len();
+        
+        cursors.retain(|_, cursor| cursor.owner != Some(owner));
+        
+        before - cursors.len()
+    }
+}
+
+/// Close expired cursors periodically.
+///
+/// Stops once the cursor manager has been dropped.
+pub async fn reap_cursors(manager: Weak<CursorManager>) {
+    loop {
+        tokio::time::sleep(REAP_INTERVAL).await;
+        
+        let Some(manager) = manager.upgrade() else {
+            return;
+        };
+        
+        let closed = manager.close_expired();
+        
+        if closed > 0 {
+            info!("Closed {} cursors that timed out", closed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    
+    /// Cursor manager over an in-memory collection "c" holding a number of documents
+    fn manager(documents: usize, timeout: Duration) -> (CursorManager, Arc<StorageEngine>) {
+        let storage = Arc::new(StorageEngine::in_memory().unwrap());
+        storage.create_collection("c").unwrap();
+        
+        for n in 0..documents {
+            storage.insert_document("c", &Document::new().set("n", n)).unwrap();
+        }
+        
+        (CursorManager::new(storage.clone(), timeout), storage)
+    }
+    
+    #[test]
+    fn batches_read_a_snapshot_of_the_collection() {
+        let (manager, storage) = manager(25, Duration::from_secs(60));
+        let mut batch = manager.open("c", 10, Some(1)).unwrap();
+        
+        // Changes made after the cursor was opened are not seen
+        storage.insert_document("c", &Document::new()).unwrap();
+        
+        let mut seen = HashSet::new();
+        let mut sizes = Vec::new();
+        
+        loop {
+            sizes.push(batch.documents.len());
+            seen.extend(batch.documents.iter().map(|document| document.id));
+            
+            match batch.cursor_id {
+                Some(cursor_id) => batch = manager.get_more(cursor_id, 10, Some(1)).unwrap(),
+                None => break,
+            }
+        }
+        
+        assert_eq!(sizes, vec![10, 10, 5]);
+        assert_eq!(seen.len(), 25);
+        assert!(manager.cursors.lock().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn small_collections_leave_no_cursor_open() {
+        let (manager, _) = manager(3, Duration::from_secs(60));
+        let batch = manager.open("c", 3, None).unwrap();
+        
+        assert_eq!((batch.cursor_id, batch.documents.len()), (None, 3));
+        assert!(matches!(manager.open("missing", 3, None), Err(XLimError::CollectionNotFound(_))));
+    }
+    
+    #[test]
+    fn cursors_belong_to_the_connection_that_opened_them() {
+        let (manager, _) = manager(5, Duration::from_secs(60));
+        let cursor_id = manager.open("c", 2, Some(1)).unwrap().cursor_id.unwrap();
+        
+        assert!(matches!(manager.get_more(cursor_id, 2, Some(2)), Err(XLimError::InvalidOperation(_))));
+        assert!(manager.close(cursor_id, None).is_err());
+        
+        assert_eq!(manager.close_owned_by(2), 0);
+        assert_eq!(manager.close_owned_by(1), 1);
+        assert!(manager.get_more(cursor_id, 2, Some(1)).is_err());
+    }
+    
+    #[test]
+    fn unused_cursors_time_out() {
+        let (manager, _) = manager(5, Duration::from_millis(20));
+        let first = manager.open("c", 2, None).unwrap().cursor_id.unwrap();
+        let second = manager.open("c", 2, None).unwrap().cursor_id.unwrap();
+        
+        std::thread::sleep(Duration::from_millis(30));
+        
+        assert!(matches!(manager.get_more(first, 2, None), Err(XLimError::Timeout(_))));
+        assert_eq!(manager.close_expired(), 1);
+        assert!(manager.close(second, None).is_err());
+    }
+}
@@ -4,7 +4,9 @@ use serde_json::{Map, Value};
 use std::collections::HashMap;
 use uuid::Uuid;
 
-use crate::error::{Result, XLimError};
+use crate::codec::Stored;
+use crate::error::Result;
+use crate::ttl::{TtlPolicy, TTL_METADATA_KEY};
 
 /// A document in the database
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,6 +23,10 @@ pub struct Document {
     #[serde(default = "Utc::now")]
     pub updated_at: DateTime<Utc>,
     
+    /// Time the document expires, overriding its collection's TTL policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    
     /// Document data
     pub data: Map<String, Value>,
 }
@@ -32,6 +38,7 @@ impl Document {
             id: Uuid::new_v4(),
             created_at: Utc::now(),
             updated_at: Utc::now(),
+            expires_at: None,
             data: Map::new(),
         }
     }
@@ -76,6 +83,12 @@ impl Document {
         self
     }
     
+    /// Make the document expire at a time, whatever its collection's TTL policy
+    pub fn expire_at(mut self, expires_at: DateTime<Utc>) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+    
     /// Get a field from the document
     pub fn get(&self, key: &str) -> Option<&Value> {
         self.data.get(key)
@@ -106,12 +119,55 @@ impl Document {
     }
 }
 
+impl Default for Document {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stored for Document {
+    type Bincode = BincodeDocument;
+}
+
+/// A document as the original storage engine wrote it with bincode
+#[derive(Debug, Deserialize)]
+pub(crate) struct BincodeDocument {
+    /// Unique identifier for the document
+    id: Uuid,
+    
+    /// Creation timestamp
+    created_at: DateTime<Utc>,
+    
+    /// Last update timestamp
+    updated_at: DateTime<Utc>,
+    
+    /// Document data
+    data: Map<String, Value>,
+}
+
+impl From<BincodeDocument> for Document {
+    fn from(document: BincodeDocument) -> Self {
+        Self {
+            id: document.id,
+            created_at: document.created_at,
+            updated_at: document.updated_at,
+            expires_at: None,
+            data: document.data,
+        }
+    }
+}
+
 /// A collection of documents
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Collection {
     /// Name of the collection
     pub name: String,
     
+    /// Numeric ID that the storage keys of the collection's documents start with, assigned
+    /// by the storage engine when the collection is created
+    #[serde(default)]
+    pub id: u64,
+    
     /// Creation timestamp
     pub created_at: DateTime<Utc>,
     
@@ -127,6 +183,7 @@ impl Collection {
     pub fn new(name: &str) -> Self {
         Self {
             name: name.to_string(),
+            id: 0,
             created_at: Utc::now(),
             updated_at: Utc::now(),
             metadata: HashMap::new(),
@@ -139,8 +196,60 @@ impl Collection {
         self.updated_at = Utc::now();
     }
     
+    /// Remove metadata from the collection
+    pub fn remove_metadata(&mut self, key: &str) -> Option<Value> {
+        self.updated_at = Utc::now();
+        self.metadata.remove(key)
+    }
+    
     /// Get metadata from the collection
     pub fn get_metadata(&self, key: &str) -> Option<&Value> {
         self.metadata.get(key)
     }
-} 
\ No newline at end of file
+    
+    /// Get the fields that have secondary indexes
+    pub fn indexes(&self) -> Vec<String> {
+        self.get_metadata("indexes")
+            .and_then(Value::as_array)
+            .map(|fields| fields.iter().filter_map(|f| f.as_str().map(str::to_string)).collect())
+            .unwrap_or_default()
+    }
+    
+    /// Get the TTL policy that the collection's documents expire under, if it has one
+    pub fn ttl(&self) -> Option<TtlPolicy> {
+        self.get_metadata(TTL_METADATA_KEY)
+            .and_then(|policy| serde_json::from_value(policy.clone()).ok())
+    }
+}
+
+impl Stored for Collection {
+    type Bincode = BincodeCollection;
+}
+
+/// A collection as the original storage engine wrote it with bincode, before collections had IDs
+#[derive(Debug, Deserialize)]
+pub(crate) struct BincodeCollection {
+    /// Name of the collection
+    name: String,
+    
+    /// Creation timestamp
+    created_at: DateTime<Utc>,
+    
+    /// Last update timestamp
+    updated_at: DateTime<Utc>,
+    
+    /// Metadata for the collection
+    metadata: HashMap<String, Value>,
+}
+
+impl From<BincodeCollection> for Collection {
+    fn from(collection: BincodeCollection) -> Self {
+        Self {
+            name: collection.name,
+            id: 0,
+            created_at: collection.created_at,
+            updated_at: collection.updated_at,
+            metadata: collection.metadata,
+        }
+    }
+}
@@ -14,6 +14,9 @@ pub enum XLimError {
     #[error("Bincode error: {0}")]
     Bincode(#[from] bincode::Error),
 
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+    
     #[error("Storage error: {0}")]
     Storage(String),
 
@@ -29,6 +32,9 @@ pub enum XLimError {
     #[error("Transaction error: {0}")]
     Transaction(String),
 
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    
     #[error("Connection error: {0}")]
     Connection(String),
 
@@ -52,4 +58,12 @@ pub enum XLimError {
 
     #[error("Unknown error: {0}")]
     Unknown(String),
-} 
\ No newline at end of file
+}
+
+impl XLimError {
+    /// Check whether the error is a transaction conflict, which may succeed if the
+    /// transaction is retried
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, XLimError::Conflict(_))
+    }
+}
@@ -0,0 +1,288 @@
+use serde_json::Value;
+use uuid::Uuid;
+
+/// Length of the document ID suffix of an index key
+const ID_LENGTH: usize = 16;
+
+// Type tags, ordered the same way `compare_json_values` orders values of different types
+const TAG_NULL: u8 = 0x01;
+const TAG_BOOL: u8 = 0x02;
+const TAG_NUMBER: u8 = 0x03;
+const TAG_STRING: u8 = 0x04;
+const TAG_ARRAY: u8 = 0x05;
+const TAG_OBJECT: u8 = 0x06;
+
+/// Build the prefix shared by every entry of an index
+pub fn index_prefix(collection_id: u64, field: &str) -> Vec<u8> {
+    let mut key = collection_prefix(collection_id);
+    encode_bytes(field.as_bytes(), &mut key);
+    key
+}
+
+/// Build the prefix shared by every index entry of a collection: its ID, like the keys of its
+/// documents, so that entries left behind by a dropped collection never match a new one
+pub fn collection_prefix(collection_id: u64) -> Vec<u8> {
+    collection_id.to_be_bytes().to_vec()
+}
+
+/// Build the prefix shared by every entry of an index with the given value
+pub fn value_prefix(collection_id: u64, field: &str, value: &Value) -> Vec<u8> {
+    let mut key = index_prefix(collection_id, field);
+    encode_value(value, &mut key);
+    key
+}
+
+/// Build the key of a single index entry: (collection ID, field, value, document ID)
+pub fn index_key(collection_id: u64, field: &str, value: &Value, document_id: &Uuid) -> Vec<u8> {
+    let mut key = value_prefix(collection_id, field, value);
+    key.extend_from_slice(document_id.as_bytes());
+    key
+}
+
+/// Build the prefix shared by every entry of an index whose value is a string starting with `prefix`
+pub fn string_prefix(collection_id: u64, field: &str, prefix: &str) -> Vec<u8> {
+    let mut key = index_prefix(collection_id, field);
+    key.push(TAG_STRING);
+    
+    // Leave off the terminator so that longer strings match too
+    encode_bytes(prefix.as_bytes(), &mut key);
+    key.truncate(key.len() - 2);
+    key
+}
+
+/// Get the smallest key that is greater than every key starting with `prefix`
+pub fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    
+    while let Some(last) = end.pop() {
+        if last < 0xFF {
+            end.push(last + 1);
+            break;
+        }
+    }
+    
+    end
+}
+
+/// Extract the document ID from an index key
+pub fn document_id(key: &[u8]) -> Option<Uuid> {
+    if key.len() < ID_LENGTH {
+        return None;
+    }
+    
+    Uuid::from_slice(&key[key.len() - ID_LENGTH..]).ok()
+}
+
+/// Encode a value so that the byte order of encodings matches the order of the values.
+///
+/// Scalars and arrays sort like `compare_json_values`. Objects are encoded as their JSON text,
+/// which keeps equality lookups exact but does not give them a meaningful order among themselves.
+pub fn encode_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(TAG_NULL),
+        Value::Bool(b) => {
+            out.push(TAG_BOOL);
+            out.push(*b as u8);
+        }
+        Value::Number(n) => {
+            out.push(TAG_NUMBER);
+            out.extend_from_slice(&encode_f64(n.as_f64().unwrap_or(0.0)));
+        }
+        Value::String(s) => {
+            out.push(TAG_STRING);
+            encode_bytes(s.as_bytes(), out);
+        }
+        Value::Array(values) => {
+            // Arrays compare by length first, then element by element
+            out.push(TAG_ARRAY);
+            out.extend_from_slice(&(values.len() as u64).to_be_bytes());
+            
+            for value in values {
+                encode_value(value, out);
+            }
+        }
+        Value::Object(_) => {
+            out.push(TAG_OBJECT);
+            encode_bytes(value.to_string().as_bytes(), out);
+        }
+    }
+}
+
+/// Encode an `f64` as 8 bytes that sort in numeric order
+fn encode_f64(value: f64) -> [u8; 8] {
+    // Normalize -0.0 so that it sorts equal to 0.0
+    let value = if value == 0.0 { 0.0 } else { value };
+    let bits = value.to_bits();
+    
+    let ordered = if bits & (1 << 63) == 0 {
+        bits | (1 << 63)
+    } else {
+        !bits
+    };
+    
+    ordered.to_be_bytes()
+}
+
+/// Encode a byte string so that it can be followed by more key parts without ambiguity.
+///
+/// Zero bytes are escaped as `00 FF` and the string ends with `00 01`,
+/// which keeps the encoding in the same order as the raw bytes.
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    for &b in bytes {
+        out.push(b);
+        
+        if b == 0 {
+            out.push(0xFF);
+        }
+    }
+    
+    out.push(0x00);
+    out.push(0x01);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    /// Values in the order `compare_json_values` puts them, with no two equal
+    fn ordered_values() -> Vec<Value> {
+        vec![
+            Value::Null,
+            json!(false),
+            json!(true),
+            json!(f64::MIN),
+            json!(-1e300),
+            json!(-2),
+            json!(-1.5),
+            json!(-1),
+            json!(-f64::MIN_POSITIVE),
+            json!(0),
+            json!(f64::MIN_POSITIVE),
+            json!(0.5),
+            json!(1),
+            json!(1.5),
+            json!(2),
+            json!(u64::MAX),
+            json!(1e300),
+            json!(f64::MAX),
+            json!(""),
+            json!("\0"),
+            json!("\0\0"),
+            json!("\0a"),
+            json!("a"),
+            json!("a\0"),
+            json!("a\0b"),
+            json!("ab"),
+            json!("b"),
+            json!([]),
+            json!([null]),
+            json!([1]),
+            json!([2]),
+            json!(["a"]),
+            json!([1, 2]),
+            json!([2, 1]),
+            json!({"a": 1}),
+        ]
+    }
+    
+    fn encode(value: &Value) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_value(value, &mut out);
+        out
+    }
+    
+    #[test]
+    fn encoded_order_matches_value_order() {
+        let values = ordered_values();
+        
+        for pair in values.windows(2) {
+            assert!(encode(&pair[0]) < encode(&pair[1]), "{} should sort before {}", pair[0], pair[1]);
+        }
+    }
+    
+    #[test]
+    fn index_keys_sort_by_value_before_document_id() {
+        let values = ordered_values();
+        let low = Uuid::from_bytes([0xFF; 16]);
+        let high = Uuid::nil();
+        
+        for pair in values.windows(2) {
+            assert!(index_key(1, "f", &pair[0], &low) < index_key(1, "f", &pair[1], &high), "{} should sort before {}", pair[0], pair[1]);
+        }
+    }
+    
+    #[test]
+    fn zeros_encode_equal() {
+        assert_eq!(encode(&json!(0)), encode(&json!(-0.0)));
+        assert_eq!(encode(&json!(0)), encode(&json!(0.0)));
+        assert_eq!(encode(&json!(1)), encode(&json!(1.0)));
+    }
+    
+    #[test]
+    fn document_ids_are_recovered() {
+        let id = Uuid::new_v4();
+        
+        for value in ordered_values() {
+            assert_eq!(document_id(&index_key(0, "f", &value, &id)), Some(id));
+        }
+    }
+    
+    #[test]
+    fn prefixes_do_not_collide() {
+        let id = Uuid::new_v4();
+        let value = json!(1);
+        
+        // Fields containing the terminator's bytes cannot be confused with shorter fields
+        let pairs = [("a\0", "a"), ("ab", "a"), ("a\0\u{1}b", "a"), ("", "\0")];
+        
+        for (f1, f2) in pairs {
+            assert!(!index_key(1, f1, &value, &id).starts_with(&index_prefix(1, f2)), "{:?} is inside {:?}", f1, f2);
+            assert!(!index_key(1, f2, &value, &id).starts_with(&index_prefix(1, f1)), "{:?} is inside {:?}", f2, f1);
+        }
+        
+        // Collection IDs have a fixed width, so one never covers another
+        for (c1, c2) in [(1, 256), (1, 1 << 56), (0, u64::MAX)] {
+            assert!(!index_key(c1, "f", &value, &id).starts_with(&collection_prefix(c2)));
+            assert!(!index_key(c2, "f", &value, &id).starts_with(&collection_prefix(c1)));
+        }
+        
+        assert!(index_key(1, "f", &value, &id).starts_with(&collection_prefix(1)));
+        
+        // Nor does one value's prefix cover a longer value
+        assert!(!index_key(1, "f", &json!("ab"), &id).starts_with(&value_prefix(1, "f", &json!("a"))));
+        assert!(!index_key(1, "f", &json!([1, 2]), &id).starts_with(&value_prefix(1, "f", &json!([1]))));
+    }
+    
+    #[test]
+    fn string_prefixes_cover_longer_strings_only() {
+        let id = Uuid::new_v4();
+        let prefix = string_prefix(1, "f", "a\0");
+        
+        for covered in ["a\0", "a\0\0", "a\0b"] {
+            assert!(index_key(1, "f", &json!(covered), &id).starts_with(&prefix), "{:?}", covered);
+        }
+        
+        for other in ["a", "ab", "b", "\0a"] {
+            assert!(!index_key(1, "f", &json!(other), &id).starts_with(&prefix), "{:?}", other);
+        }
+        
+        assert!(!index_key(1, "f", &json!(["a\0"]), &id).starts_with(&prefix));
+    }
+    
+    #[test]
+    fn prefix_end_bounds_every_key_with_the_prefix() {
+        let id = Uuid::from_bytes([0xFF; 16]);
+        let prefix = index_prefix(1, "f");
+        let end = prefix_end(&prefix);
+        
+        for value in ordered_values() {
+            let key = index_key(1, "f", &value, &id);
+            
+            assert!(key.as_slice() >= prefix.as_slice() && key < end);
+        }
+        
+        assert_eq!(prefix_end(&[0x01, 0xFF, 0xFF]), vec![0x02]);
+        assert!(prefix_end(&[0xFF]).is_empty());
+    }
+}
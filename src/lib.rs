@@ -0,0 +1,28 @@
+//! XLim, a lightweight NoSQL database.
+//!
+//! The server, its storage engine and the async client are all available as a library; the
+//! `xlim` binary adds the command-line interface and interactive shell on top.
+
+pub mod auth;
+pub mod backend;
+pub mod backup;
+pub mod client;
+pub mod codec;
+pub mod config;
+pub mod cursor;
+pub mod document;
+pub mod error;
+pub mod index;
+pub mod lock;
+pub mod memory_backend;
+pub mod parser;
+pub mod planner;
+pub mod pool;
+pub mod protocol;
+pub mod query;
+pub mod rocksdb_backend;
+pub mod server;
+pub mod storage;
+pub mod transaction;
+pub mod transfer;
+pub mod ttl;
@@ -0,0 +1,429 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::error::{Result, XLimError};
+
+/// How a document is locked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockMode {
+    /// Many transactions may hold a shared lock on a document at once
+    Shared,
+    /// Only one transaction may hold an exclusive lock, and no one else may hold any lock
+    Exclusive,
+}
+
+impl LockMode {
+    /// Check whether two transactions may hold locks in these modes on the same document
+    fn is_compatible(self, other: LockMode) -> bool {
+        self == LockMode::Shared && other == LockMode::Shared
+    }
+}
+
+/// A locked document, by collection and ID
+pub type LockKey = (String, Uuid);
+
+/// Holders and waiters of the lock on one document
+#[derive(Debug, Default)]
+struct LockState {
+    /// Transactions holding the lock, with their modes
+    holders: HashMap<Uuid, LockMode>,
+    
+    /// Transactions waiting for the lock, first come first served
+    queue: VecDeque<(Uuid, LockMode)>,
+}
+
+impl LockState {
+    /// Check whether a transaction can be granted the lock now
+    fn can_grant(&self, transaction_id: Uuid, mode: LockMode) -> bool {
+        let holders_allow = self.holders
+            .iter()
+            .filter(|(holder, _)| **holder != transaction_id)
+            .all(|(_, held)| mode.is_compatible(*held));
+        
+        // Upgrades skip the queue, since everyone in it is waiting for the upgrading transaction anyway
+        if self.holders.contains_key(&transaction_id) {
+            return holders_allow;
+        }
+        
+        holders_allow && self.ahead_of(transaction_id).all(|(_, waiting)| mode.is_compatible(*waiting))
+    }
+    
+    /// Waiters queued before a transaction, or the whole queue if it is not queued
+    fn ahead_of(&self, transaction_id: Uuid) -> impl Iterator<Item = &(Uuid, LockMode)> {
+        self.queue.iter().take_while(move |(waiter, _)| *waiter != transaction_id)
+    }
+    
+    /// Transactions that a waiting transaction is blocked by
+    fn blockers(&self, transaction_id: Uuid, mode: LockMode) -> Vec<Uuid> {
+        let holders = self.holders
+            .iter()
+            .filter(|(holder, held)| **holder != transaction_id && !mode.is_compatible(**held))
+            .map(|(holder, _)| *holder);
+        
+        // Upgrades skip the queue, so only the other holders stand in their way
+        if self.holders.contains_key(&transaction_id) {
+            return holders.collect();
+        }
+        
+        let waiters = self.ahead_of(transaction_id)
+            .filter(|(_, waiting)| !mode.is_compatible(*waiting))
+            .map(|(waiter, _)| *waiter);
+        
+        holders.chain(waiters).collect()
+    }
+}
+
+/// Locks of every document, and what each waiting transaction waits for
+#[derive(Debug, Default)]
+struct LockTable {
+    /// Lock state by document
+    locks: HashMap<LockKey, LockState>,
+    
+    /// Document and mode every waiting transaction is waiting for
+    waiting: HashMap<Uuid, (LockKey, LockMode)>,
+}
+
+impl LockTable {
+    /// Check whether a transaction waiting for a lock would be part of a cycle of transactions
+    /// waiting for each other
+    fn has_deadlock(&self, transaction_id: Uuid) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = self.blockers_of(transaction_id);
+        
+        while let Some(blocker) = stack.pop() {
+            if blocker == transaction_id {
+                return true;
+            }
+            
+            if visited.insert(blocker) {
+                stack.extend(self.blockers_of(blocker));
+            }
+        }
+        
+        false
+    }
+    
+    /// Transactions that a transaction is waiting for, if it is waiting
+    fn blockers_of(&self, transaction_id: Uuid) -> Vec<Uuid> {
+        self.waiting
+            .get(&transaction_id)
+            .and_then(|(key, mode)| self.locks.get(key).map(|state| state.blockers(transaction_id, *mode)))
+            .unwrap_or_default()
+    }
+    
+    /// Stop a transaction waiting for a lock
+    fn stop_waiting(&mut self, transaction_id: Uuid, key: &LockKey) {
+        self.waiting.remove(&transaction_id);
+        
+        if let Some(state) = self.locks.get_mut(key) {
+            state.queue.retain(|(waiter, _)| *waiter != transaction_id);
+            
+            if state.holders.is_empty() && state.queue.is_empty() {
+                self.locks.remove(key);
+            }
+        }
+    }
+}
+
+/// Shared and exclusive document locks held by transactions until they finish.
+///
+/// Transactions that cannot get a lock wait for it in a queue, up to a timeout. A transaction
+/// whose wait would close a cycle of transactions waiting for each other is refused the lock
+/// instead, so that the others can go on.
+#[derive(Debug, Default)]
+pub struct LockManager {
+    /// Lock table
+    table: Mutex<LockTable>,
+    
+    /// Signalled whenever locks are released or waiters give up
+    released: Condvar,
+}
+
+impl LockManager {
+    /// Create a lock manager with no locks
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Lock a document for a transaction, waiting up to `timeout` for other transactions to
+    /// release conflicting locks. Locking a document again in the same or a weaker mode does
+    /// nothing, and a shared lock can be upgraded to an exclusive one.
+    ///
+    /// Fails with `XLimError::Timeout` if the lock is not granted in time, and with a conflict
+    /// error if waiting would deadlock.
+    pub fn acquire(&self, transaction_id: Uuid, key: &LockKey, mode: LockMode, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut table = self.table.lock().unwrap();
+        let mut waited = false;
+        
+        loop {
+            let state = table.locks.entry(key.clone()).or_default();
+            let held = state.holders.get(&transaction_id).copied();
+            
+            if held == Some(LockMode::Exclusive) || held == Some(mode) {
+                return Ok(());
+            }
+            
+            let queued = state.queue.iter().any(|(waiter, _)| *waiter == transaction_id);
+            
+            if waited && !queued {
+                // Taken out of the queue by `release_all` while waiting
+                table.stop_waiting(transaction_id, key);
+                return Err(XLimError::Transaction(format!("Transaction {} ended while waiting for a lock", transaction_id)));
+            }
+            
+            if state.can_grant(transaction_id, mode) {
+                state.queue.retain(|(waiter, _)| *waiter != transaction_id);
+                state.holders.insert(transaction_id, mode);
+                table.waiting.remove(&transaction_id);
+                
+                // Waiters behind this one may be compatible with it
+                self.released.notify_all();
+                
+                return Ok(());
+            }
+            
+            if !queued {
+                state.queue.push_back((transaction_id, mode));
+                table.waiting.insert(transaction_id, (key.clone(), mode));
+                waited = true;
+                
+                if table.has_deadlock(transaction_id) {
+                    table.stop_waiting(transaction_id, key);
+                    self.released.notify_all();
+                    
+                    return Err(XLimError::Conflict(format!(
+                        "deadlock detected while transaction {} was waiting for a {:?} lock on document {} in collection {}",
+                        transaction_id, mode, key.1, key.0,
+                    )));
+                }
+            }
+            
+            let now = Instant::now();
+            
+            if now >= deadline {
+                table.stop_waiting(transaction_id, key);
+                self.released.notify_all();
+                
+                return Err(XLimError::Timeout(format!(
+                    "Timed out waiting for a {:?} lock on document {} in collection {}",
+                    mode, key.1, key.0,
+                )));
+            }
+            
+            table = self.released.wait_timeout(table, deadline - now).unwrap().0;
+        }
+    }
+    
+    /// Release every lock a transaction holds, and stop it waiting for one
+    pub fn release_all(&self, transaction_id: Uuid) {
+        let mut table = self.table.lock().unwrap();
+        
+        table.waiting.remove(&transaction_id);
+        table.locks.retain(|_, state| {
+            state.holders.remove(&transaction_id);
+            state.queue.retain(|(waiter, _)| *waiter != transaction_id);
+            
+            !state.holders.is_empty() || !state.queue.is_empty()
+        });
+        
+        self.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    
+    /// Long enough that no test should ever hit it unless it is meant to
+    const LONG: Duration = Duration::from_secs(10);
+    
+    fn key() -> LockKey {
+        ("accounts".to_string(), Uuid::new_v4())
+    }
+    
+    /// Acquire a lock on another thread
+    fn acquire_in_background(manager: &Arc<LockManager>, transaction_id: Uuid, key: &LockKey, mode: LockMode) -> JoinHandle<Result<()>> {
+        let manager = manager.clone();
+        let key = key.clone();
+        
+        thread::spawn(move || manager.acquire(transaction_id, &key, mode, LONG))
+    }
+    
+    /// Wait until a transaction is queued for a lock
+    fn wait_until_queued(manager: &LockManager, transaction_id: Uuid) {
+        let deadline = Instant::now() + LONG;
+        
+        while !manager.table.lock().unwrap().waiting.contains_key(&transaction_id) {
+            assert!(Instant::now() < deadline, "transaction never started waiting");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    
+    #[test]
+    fn shared_locks_are_granted_together() {
+        let manager = LockManager::new();
+        let key = key();
+        
+        manager.acquire(Uuid::new_v4(), &key, LockMode::Shared, Duration::ZERO).unwrap();
+        manager.acquire(Uuid::new_v4(), &key, LockMode::Shared, Duration::ZERO).unwrap();
+    }
+    
+    #[test]
+    fn exclusive_locks_block_until_released() {
+        let manager = Arc::new(LockManager::new());
+        let key = key();
+        let (holder, waiter) = (Uuid::new_v4(), Uuid::new_v4());
+        
+        manager.acquire(holder, &key, LockMode::Exclusive, Duration::ZERO).unwrap();
+        
+        let waiting = acquire_in_background(&manager, waiter, &key, LockMode::Shared);
+        wait_until_queued(&manager, waiter);
+        
+        assert!(!waiting.is_finished());
+        
+        manager.release_all(holder);
+        
+        waiting.join().unwrap().unwrap();
+        assert_eq!(manager.table.lock().unwrap().locks[&key].holders[&waiter], LockMode::Shared);
+    }
+    
+    #[test]
+    fn locks_time_out() {
+        let manager = LockManager::new();
+        let key = key();
+        let (holder, waiter) = (Uuid::new_v4(), Uuid::new_v4());
+        
+        manager.acquire(holder, &key, LockMode::Shared, Duration::ZERO).unwrap();
+        
+        let result = manager.acquire(waiter, &key, LockMode::Exclusive, Duration::from_millis(20));
+        
+        assert!(matches!(result, Err(XLimError::Timeout(_))));
+        
+        // The waiter is no longer queued, so it does not hold up later shared locks
+        let table = manager.table.lock().unwrap();
+        
+        assert!(table.locks[&key].queue.is_empty());
+        assert!(!table.waiting.contains_key(&waiter));
+    }
+    
+    #[test]
+    fn upgrades_skip_the_queue() {
+        let manager = Arc::new(LockManager::new());
+        let key = key();
+        let (holder, waiter) = (Uuid::new_v4(), Uuid::new_v4());
+        
+        manager.acquire(holder, &key, LockMode::Shared, Duration::ZERO).unwrap();
+        
+        let waiting = acquire_in_background(&manager, waiter, &key, LockMode::Exclusive);
+        wait_until_queued(&manager, waiter);
+        
+        // The holder is granted the upgrade at once, even though the waiter queued first
+        manager.acquire(holder, &key, LockMode::Exclusive, Duration::ZERO).unwrap();
+        
+        assert!(!waiting.is_finished());
+        
+        manager.release_all(holder);
+        
+        waiting.join().unwrap().unwrap();
+    }
+    
+    #[test]
+    fn upgrade_deadlocks_are_detected() {
+        let manager = Arc::new(LockManager::new());
+        let key = key();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        
+        manager.acquire(first, &key, LockMode::Shared, Duration::ZERO).unwrap();
+        manager.acquire(second, &key, LockMode::Shared, Duration::ZERO).unwrap();
+        
+        let upgrading = acquire_in_background(&manager, first, &key, LockMode::Exclusive);
+        wait_until_queued(&manager, first);
+        
+        // Each transaction would wait for the other to give up its shared lock
+        let error = manager.acquire(second, &key, LockMode::Exclusive, LONG).unwrap_err();
+        
+        assert!(error.is_conflict());
+        
+        // The refused transaction still holds its shared lock until it is rolled back
+        assert!(!upgrading.is_finished());
+        
+        manager.release_all(second);
+        
+        upgrading.join().unwrap().unwrap();
+    }
+    
+    #[test]
+    fn upgrades_do_not_wait_for_the_queue() {
+        let manager = Arc::new(LockManager::new());
+        let key = key();
+        let (first, second, waiter) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        
+        manager.acquire(first, &key, LockMode::Shared, Duration::ZERO).unwrap();
+        manager.acquire(second, &key, LockMode::Shared, Duration::ZERO).unwrap();
+        
+        let waiting = acquire_in_background(&manager, waiter, &key, LockMode::Exclusive);
+        wait_until_queued(&manager, waiter);
+        
+        // The waiter is blocked by the upgrading transaction, but not the other way round
+        let upgrading = acquire_in_background(&manager, first, &key, LockMode::Exclusive);
+        wait_until_queued(&manager, first);
+        
+        manager.release_all(second);
+        upgrading.join().unwrap().unwrap();
+        
+        assert!(!waiting.is_finished());
+        
+        manager.release_all(first);
+        waiting.join().unwrap().unwrap();
+    }
+    
+    #[test]
+    fn release_all_wakes_every_compatible_waiter() {
+        let manager = Arc::new(LockManager::new());
+        let key = key();
+        let holder = Uuid::new_v4();
+        let waiters = [Uuid::new_v4(), Uuid::new_v4()];
+        
+        manager.acquire(holder, &key, LockMode::Exclusive, Duration::ZERO).unwrap();
+        
+        let waiting: Vec<_> = waiters
+            .iter()
+            .map(|waiter| {
+                let handle = acquire_in_background(&manager, *waiter, &key, LockMode::Shared);
+                wait_until_queued(&manager, *waiter);
+                handle
+            })
+            .collect();
+        
+        manager.release_all(holder);
+        
+        for handle in waiting {
+            handle.join().unwrap().unwrap();
+        }
+        
+        assert_eq!(manager.table.lock().unwrap().locks[&key].holders.len(), 2);
+    }
+    
+    #[test]
+    fn release_all_ends_a_wait() {
+        let manager = Arc::new(LockManager::new());
+        let key = key();
+        let (holder, waiter) = (Uuid::new_v4(), Uuid::new_v4());
+        
+        manager.acquire(holder, &key, LockMode::Exclusive, Duration::ZERO).unwrap();
+        
+        let waiting = acquire_in_background(&manager, waiter, &key, LockMode::Exclusive);
+        wait_until_queued(&manager, waiter);
+        
+        // The waiting transaction is rolled back
+        manager.release_all(waiter);
+        
+        assert!(matches!(waiting.join().unwrap(), Err(XLimError::Transaction(_))));
+    }
+}
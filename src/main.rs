@@ -1,20 +1,25 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use env_logger::Env;
-use log::{error, info};
-use std::path::PathBuf;
-
-mod client;
-mod config;
-mod document;
-mod error;
-mod query;
-mod server;
-mod storage;
-mod transaction;
-
-use crate::config::Config;
-use crate::error::Result;
-use crate::server::Server;
+use log::info;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+mod output;
+mod shell;
+
+use xlim::auth::Privilege;
+use xlim::{backup, parser, transfer};
+use xlim::backend::StorageBackend;
+use xlim::client::Client;
+use xlim::config::{BackendKind, Config};
+use xlim::error::{Result, XLimError};
+use xlim::rocksdb_backend::RocksDbBackend;
+use xlim::server::Server;
+use xlim::transfer::{ColumnMapping, DocumentReader, DocumentWriter, Format, OnConflict, DEFAULT_IMPORT_BATCH_SIZE};
+
+use crate::output::{print_documents, render_backups, render_transactions, OutputFormat};
+use crate::shell::Shell;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -34,6 +39,34 @@ enum Commands {
         /// Data directory
         #[arg(short, long, default_value = "./data")]
         data_dir: PathBuf,
+        
+        /// Storage backend; `memory` keeps nothing once the server stops
+        #[arg(long, value_enum, default_value_t = BackendKind::RocksDb)]
+        backend: BackendKind,
+        
+        /// Require clients to log in
+        #[arg(long)]
+        auth: bool,
+        
+        /// Wait for every commit to reach the disk before acknowledging it
+        #[arg(long)]
+        sync_commits: bool,
+        
+        /// Seconds a transaction may stay open before it is aborted
+        #[arg(long, default_value_t = 60)]
+        transaction_timeout: u64,
+        
+        /// Seconds a transaction waits for a document lock
+        #[arg(long, default_value_t = 10)]
+        lock_timeout: u64,
+        
+        /// Seconds a cursor may go unused before it is closed
+        #[arg(long, default_value_t = 600)]
+        cursor_timeout: u64,
+        
+        /// Seconds between purges of expired documents
+        #[arg(long, default_value_t = 60)]
+        ttl_interval: u64,
     },
     /// Run a query against the database
     Query {
@@ -43,7 +76,301 @@ enum Commands {
         /// Server address
         #[arg(short, long, default_value = "localhost:7878")]
         server: String,
+        
+        /// Output format for the results
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Json)]
+        format: OutputFormat,
+        
+        #[command(flatten)]
+        login: Login,
     },
+    /// Export the documents of a collection as JSON Lines or CSV
+    Export {
+        /// Collection to export
+        collection: String,
+        
+        /// File to write to; defaults to stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        
+        /// File format; defaults to CSV for `.csv` files and JSON Lines otherwise
+        #[arg(short, long, value_enum)]
+        format: Option<Format>,
+        
+        /// CSV column to write, as <column>=<field> or just <field>; may be repeated.
+        /// Defaults to the ID, the timestamps and every data field
+        #[arg(long = "map", value_name = "COLUMN=FIELD")]
+        mappings: Vec<ColumnMapping>,
+        
+        /// Server address
+        #[arg(short, long, default_value = "localhost:7878")]
+        server: String,
+        
+        #[command(flatten)]
+        login: Login,
+    },
+    /// Import documents into a collection from a JSON Lines or CSV file
+    Import {
+        /// Collection to import into
+        collection: String,
+        
+        /// File to read, or `-` for stdin
+        file: PathBuf,
+        
+        /// File format; defaults to CSV for `.csv` files and JSON Lines otherwise
+        #[arg(short, long, value_enum)]
+        format: Option<Format>,
+        
+        /// CSV column to read into a field, as <column>=<field>, or <column>= to ignore it;
+        /// may be repeated. Other columns are read into the field their header names
+        #[arg(long = "map", value_name = "COLUMN=FIELD")]
+        mappings: Vec<ColumnMapping>,
+        
+        /// What to do with a document whose ID is already taken
+        #[arg(long, value_enum, default_value_t = OnConflict::Error)]
+        on_conflict: OnConflict,
+        
+        /// Replace documents whose ID is already taken; short for `--on-conflict replace`
+        #[arg(long, conflicts_with = "on_conflict")]
+        upsert: bool,
+        
+        /// Number of documents written per atomic batch
+        #[arg(long, default_value_t = DEFAULT_IMPORT_BATCH_SIZE)]
+        batch_size: usize,
+        
+        /// Server address
+        #[arg(short, long, default_value = "localhost:7878")]
+        server: String,
+        
+        #[command(flatten)]
+        login: Login,
+    },
+    /// Start an interactive shell connected to a server
+    Shell {
+        /// Server address
+        #[arg(short, long, default_value = "localhost:7878")]
+        server: String,
+        
+        #[command(flatten)]
+        login: Login,
+    },
+    /// List the transactions that are active on a server
+    Transactions {
+        /// Server address
+        #[arg(short, long, default_value = "localhost:7878")]
+        server: String,
+        
+        #[command(flatten)]
+        login: Login,
+    },
+    /// Manage user accounts
+    User {
+        #[command(subcommand)]
+        action: UserAction,
+        
+        /// Server address
+        #[arg(short, long, default_value = "localhost:7878", global = true)]
+        server: String,
+        
+        #[command(flatten)]
+        login: Login,
+    },
+    /// Manage custom roles
+    Role {
+        #[command(subcommand)]
+        action: RoleAction,
+        
+        /// Server address
+        #[arg(short, long, default_value = "localhost:7878", global = true)]
+        server: String,
+        
+        #[command(flatten)]
+        login: Login,
+    },
+    /// Back up a database, or manage the backups in a directory
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Backup {
+        #[command(subcommand)]
+        action: Option<BackupAction>,
+        
+        /// Directory to back up into; backups made into the same directory only copy the
+        /// files that changed since the last one
+        #[arg(long, value_name = "DIR", required = true)]
+        to: Option<PathBuf>,
+        
+        /// Back up the data directory of a stopped server instead of asking a running one
+        #[arg(short, long, conflicts_with = "server")]
+        data_dir: Option<PathBuf>,
+        
+        /// Server address
+        #[arg(short, long, default_value = "localhost:7878")]
+        server: String,
+        
+        #[command(flatten)]
+        login: Login,
+    },
+    /// Restore a backup into the data directory of a stopped server
+    Restore {
+        /// Directory holding the backup
+        #[arg(long, value_name = "DIR")]
+        from: PathBuf,
+        
+        /// ID of the backup to restore; defaults to the newest one
+        #[arg(long)]
+        id: Option<u32>,
+        
+        /// Data directory to restore into
+        #[arg(short, long, default_value = "./data")]
+        data_dir: PathBuf,
+        
+        /// Replace the database already in the data directory
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum BackupAction {
+    /// List the backups in a directory
+    List {
+        /// Backup directory
+        dir: PathBuf,
+    },
+    /// Check that backups are complete and undamaged
+    Verify {
+        /// Backup directory
+        dir: PathBuf,
+        
+        /// ID of the backup to check; defaults to every backup in the directory
+        #[arg(long)]
+        id: Option<u32>,
+    },
+    /// Delete all but the newest backups in a directory
+    Prune {
+        /// Backup directory
+        dir: PathBuf,
+        
+        /// Number of backups to keep
+        #[arg(long)]
+        keep: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum UserAction {
+    /// Create a user
+    Add {
+        /// Name of the new user
+        username: String,
+        
+        /// Password of the new user; asked for if not given, which keeps it out of the
+        /// shell history and the process list
+        password: Option<String>,
+        
+        /// Role to grant the new user (read, readWrite, admin or a custom role); may be repeated
+        #[arg(short, long = "role")]
+        roles: Vec<String>,
+    },
+    /// Delete a user
+    Remove {
+        /// Name of the user
+        username: String,
+    },
+    /// Change a user's password
+    Passwd {
+        /// Name of the user
+        username: String,
+        
+        /// New password; asked for if not given
+        password: Option<String>,
+    },
+    /// Grant roles to a user
+    Grant {
+        /// Name of the user
+        username: String,
+        
+        /// Roles to grant
+        #[arg(required = true)]
+        roles: Vec<String>,
+    },
+    /// Revoke roles from a user
+    Revoke {
+        /// Name of the user
+        username: String,
+        
+        /// Roles to revoke
+        #[arg(required = true)]
+        roles: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum RoleAction {
+    /// Create a custom role
+    Create {
+        /// Name of the new role
+        name: String,
+        
+        /// Privilege as <collection>:<action>,<action>..., where * means every collection;
+        /// may be repeated
+        #[arg(short, long = "grant", required = true)]
+        privileges: Vec<Privilege>,
+    },
+    /// Delete a custom role
+    Drop {
+        /// Name of the role
+        name: String,
+    },
+}
+
+/// Credentials for servers that require authentication
+#[derive(Args)]
+struct Login {
+    /// User to log in as
+    #[arg(short, long, global = true)]
+    user: Option<String>,
+    
+    /// Password to log in with; read from XLIM_PASSWORD, or asked for, if not given
+    #[arg(long = "password", id = "login_password", value_name = "PASSWORD", global = true, requires = "user")]
+    password: Option<String>,
+}
+
+impl Login {
+    /// Connect to a server, logging in if a user was given
+    async fn connect(&self, server: &str) -> Result<Client> {
+        match &self.user {
+            Some(user) => {
+                let password = match &self.password {
+                    Some(password) => password.clone(),
+                    None => match std::env::var(PASSWORD_VAR) {
+                        Ok(password) => password,
+                        Err(_) => rpassword::prompt_password(format!("Password for {}: ", user))?,
+                    },
+                };
+                
+                Client::connect_with_credentials(server, user, &password).await
+            }
+            None => Client::connect(server).await,
+        }
+    }
+}
+
+/// Environment variable the login password is read from when `--password` is not given
+const PASSWORD_VAR: &str = "XLIM_PASSWORD";
+
+/// Use the password given on the command line, or ask for a new one twice
+fn new_password(password: Option<String>) -> Result<String> {
+    if let Some(password) = password {
+        return Ok(password);
+    }
+    
+    let password = rpassword::prompt_password("New password: ")?;
+    
+    if rpassword::prompt_password("Retype new password: ")? != password {
+        return Err(XLimError::InvalidOperation("Passwords do not match".to_string()));
+    }
+    
+    Ok(password)
 }
 
 #[tokio::main]
@@ -53,7 +380,7 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Server { port, data_dir } => {
+        Commands::Server { port, data_dir, backend, auth, sync_commits, transaction_timeout, lock_timeout, cursor_timeout, ttl_interval } => {
             info!("Starting XLim server on port {}", port);
             
             if !data_dir.exists() {
@@ -66,6 +393,13 @@ async fn main() -> Result<()> {
                 data_dir,
                 max_connections: 100,
                 cache_size_mb: 128,
+                backend,
+                auth_enabled: auth,
+                sync_commits,
+                transaction_timeout_secs: transaction_timeout,
+                lock_timeout_secs: lock_timeout,
+                cursor_timeout_secs: cursor_timeout,
+                ttl_interval_secs: ttl_interval,
             };
             
             let server = Server::new(config)?;
@@ -73,11 +407,220 @@ async fn main() -> Result<()> {
             
             Ok(())
         }
-        Commands::Query { query, server } => {
+        Commands::Query { query, server, format, login } => {
             info!("Executing query: {} on server {}", query, server);
             
-            // TODO: Implement query execution against server
-            println!("Query execution not yet implemented");
+            let parsed = parser::parse(&query)?;
+            
+            let client = login.connect(&server).await?;
+            let collection = client.collection(&parsed.collection).await;
+            let documents = collection.query(parsed.query).await?;
+            
+            print_documents(&documents, format)?;
+            
+            Ok(())
+        }
+        Commands::Export { collection, output, format, mappings, server, login } => {
+            let format = format.unwrap_or_else(|| output.as_deref().map_or(Format::Jsonl, Format::of_path));
+            
+            let client = login.connect(&server).await?;
+            let collection = client.collection(&collection).await;
+            
+            let file: Box<dyn Write> = match &output {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(std::io::stdout()),
+            };
+            
+            let writer = match format {
+                Format::Jsonl => DocumentWriter::jsonl(file),
+                Format::Csv if mappings.is_empty() => DocumentWriter::csv(file, transfer::discover_columns(&collection).await?)?,
+                Format::Csv => DocumentWriter::csv(file, mappings)?,
+            };
+            
+            let exported = transfer::export(&collection, writer).await?;
+            
+            info!("Exported {} documents from collection {}", exported, collection.name());
+            
+            Ok(())
+        }
+        Commands::Import { collection, file, format, mappings, on_conflict, upsert, batch_size, server, login } => {
+            let on_conflict = if upsert { OnConflict::Replace } else { on_conflict };
+            
+            let input: Box<dyn Read> = match file.as_path() == Path::new("-") {
+                true => Box::new(std::io::stdin()),
+                false => Box::new(File::open(&file)?),
+            };
+            
+            let reader = match format.unwrap_or_else(|| Format::of_path(&file)) {
+                Format::Jsonl => DocumentReader::jsonl(input),
+                Format::Csv => DocumentReader::csv(input, &mappings)?,
+            };
+            
+            let client = login.connect(&server).await?;
+            let collection = client.collection(&collection).await;
+            
+            let report = transfer::import(&collection, reader, on_conflict, batch_size, |report| {
+                info!("Read {} documents, wrote {}", report.read, report.inserted + report.replaced);
+            })
+            .await?;
+            
+            for error in &report.errors {
+                eprintln!("Line {}: {}", error.line, error.message);
+            }
+            
+            println!(
+                "Read {} documents into collection {}: {} inserted, {} replaced, {} skipped, {} failed",
+                report.read,
+                collection.name(),
+                report.inserted,
+                report.replaced,
+                report.skipped,
+                report.errors.len()
+            );
+            
+            if report.aborted {
+                return Err(XLimError::InvalidOperation(
+                    "Import stopped at a conflict; the batches before it were written".to_string(),
+                ));
+            }
+            
+            if !report.errors.is_empty() {
+                return Err(XLimError::InvalidOperation(format!("{} documents could not be imported", report.errors.len())));
+            }
+            
+            Ok(())
+        }
+        Commands::Shell { server, login } => {
+            let client = login.connect(&server).await?;
+            Shell::new(client).run().await
+        }
+        Commands::Transactions { server, login } => {
+            let client = login.connect(&server).await?;
+            let transactions = client.active_transactions().await?;
+            
+            print!("{}", render_transactions(&transactions));
+            
+            Ok(())
+        }
+        Commands::User { action, server, login } => {
+            let client = login.connect(&server).await?;
+            
+            match action {
+                UserAction::Add { username, password, roles } => {
+                    let password = new_password(password)?;
+                    let roles: Vec<&str> = roles.iter().map(String::as_str).collect();
+                    client.add_user(&username, &password, &roles).await?;
+                    println!("Added user {}", username);
+                }
+                UserAction::Remove { username } => {
+                    client.remove_user(&username).await?;
+                    println!("Removed user {}", username);
+                }
+                UserAction::Passwd { username, password } => {
+                    let password = new_password(password)?;
+                    client.change_password(&username, &password).await?;
+                    println!("Changed password of user {}", username);
+                }
+                UserAction::Grant { username, roles } => {
+                    let roles: Vec<&str> = roles.iter().map(String::as_str).collect();
+                    client.grant_roles(&username, &roles).await?;
+                    println!("Granted {} to user {}", roles.join(", "), username);
+                }
+                UserAction::Revoke { username, roles } => {
+                    let roles: Vec<&str> = roles.iter().map(String::as_str).collect();
+                    client.revoke_roles(&username, &roles).await?;
+                    println!("Revoked {} from user {}", roles.join(", "), username);
+                }
+            }
+            
+            Ok(())
+        }
+        Commands::Role { action, server, login } => {
+            let client = login.connect(&server).await?;
+            
+            match action {
+                RoleAction::Create { name, privileges } => {
+                    client.create_role(&name, privileges).await?;
+                    println!("Created role {}", name);
+                }
+                RoleAction::Drop { name } => {
+                    client.drop_role(&name).await?;
+                    println!("Dropped role {}", name);
+                }
+            }
+            
+            Ok(())
+        }
+        Commands::Backup { action: Some(action), .. } => {
+            match action {
+                BackupAction::List { dir } => {
+                    print!("{}", render_backups(&backup::list_backups(&dir)?));
+                }
+                BackupAction::Verify { dir, id } => {
+                    let ids = match id {
+                        Some(id) => vec![id],
+                        None => backup::list_backups(&dir)?.iter().map(|backup| backup.id).collect(),
+                    };
+                    
+                    for id in ids {
+                        backup::verify_backup(&dir, id)?;
+                        println!("Backup {} is intact", id);
+                    }
+                }
+                BackupAction::Prune { dir, keep } => {
+                    let pruned = backup::prune_backups(&dir, keep)?;
+                    println!("Deleted {} backups from {}", pruned, dir.display());
+                }
+            }
+            
+            Ok(())
+        }
+        Commands::Backup { action: None, to, data_dir, server, login } => {
+            // `--to` is required whenever no subcommand is given
+            let to = to.unwrap_or_default();
+            
+            let backup = match data_dir {
+                Some(data_dir) => {
+                    let config = Config {
+                        data_dir,
+                        ..Config::default()
+                    };
+                    let db_path = config.db_path();
+                    
+                    if !db_path.exists() {
+                        return Err(XLimError::InvalidOperation(format!("No database in {}", config.data_dir.display())));
+                    }
+                    
+                    RocksDbBackend::open(&db_path)?.create_backup(&to)?
+                }
+                None => {
+                    // The server writes the backup, so the directory must be one it can reach
+                    let to = std::path::absolute(&to)?;
+                    login.connect(&server).await?.backup(&to).await?
+                }
+            };
+            
+            println!("Created backup {} in {} ({} files, {} bytes)", backup.id, to.display(), backup.files, backup.size);
+            
+            Ok(())
+        }
+        Commands::Restore { from, id, data_dir, force } => {
+            let config = Config {
+                data_dir,
+                ..Config::default()
+            };
+            let db_path = config.db_path();
+            
+            if db_path.exists() && !force {
+                return Err(XLimError::InvalidOperation(format!(
+                    "{} already holds a database; stop its server and pass --force to replace it",
+                    config.data_dir.display()
+                )));
+            }
+            
+            let backup = backup::restore_backup(&from, &db_path, id)?;
+            
+            println!("Restored backup {} from {} into {}", backup.id, from.display(), config.data_dir.display());
             
             Ok(())
         }
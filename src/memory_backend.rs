@@ -0,0 +1,454 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+use crate::backend::{BatchOperation, Direction, KeyValue, KeyValueIter, Keyspace, Snapshot, StorageBackend, WriteBatch};
+use crate::error::Result;
+
+/// Number of entries a scan reads each time it takes the lock
+const SCAN_CHUNK_SIZE: usize = 256;
+
+/// A value a key had from a sequence number on, or `None` if the key was deleted
+struct Version {
+    /// Sequence number of the write that set the value
+    sequence: u64,
+    
+    /// Value, or `None` if the write deleted the key
+    value: Option<Vec<u8>>,
+}
+
+/// Every keyspace and the snapshots that are reading them
+#[derive(Default)]
+struct State {
+    /// Versions of every key, oldest first, by keyspace
+    keyspaces: HashMap<Keyspace, BTreeMap<Vec<u8>, Vec<Version>>>,
+    
+    /// Sequence number of the latest write
+    sequence: u64,
+    
+    /// Number of open snapshots at each sequence number
+    snapshots: BTreeMap<u64, usize>,
+    
+    /// Keys with versions that only open snapshots can see, to prune once those are dropped
+    stale: HashSet<(Keyspace, Vec<u8>)>,
+}
+
+impl State {
+    /// Oldest sequence number that an open snapshot still reads at
+    fn oldest_snapshot(&self) -> u64 {
+        self.snapshots.keys().next().copied().unwrap_or(self.sequence)
+    }
+    
+    /// Drop the versions of a key that no open snapshot can see, removing the key once it
+    /// has none left, and remember the key if it still has versions kept for snapshots
+    fn prune_key(&mut self, keyspace: Keyspace, key: &[u8]) {
+        let oldest = self.oldest_snapshot();
+        
+        let Some(keys) = self.keyspaces.get_mut(&keyspace) else {
+            return;
+        };
+        
+        let stale = match keys.get_mut(key) {
+            Some(versions) => {
+                prune(versions, oldest);
+                versions.len() > 1 || versions.first().is_some_and(|version| version.value.is_none())
+            }
+            None => false,
+        };
+        
+        if keys.get(key).is_some_and(Vec::is_empty) {
+            keys.remove(key);
+        }
+        
+        if stale {
+            self.stale.insert((keyspace, key.to_vec()));
+        } else if !self.stale.is_empty() {
+            self.stale.remove(&(keyspace, key.to_vec()));
+        }
+    }
+    
+    /// Prune every key that had versions kept for snapshots
+    fn prune_stale(&mut self) {
+        for (keyspace, key) in std::mem::take(&mut self.stale) {
+            self.prune_key(keyspace, &key);
+        }
+    }
+}
+
+/// A sequence number pinned until the snapshot is dropped, so its versions are kept
+struct MemorySnapshot {
+    /// Sequence number of the last write the snapshot sees
+    sequence: u64,
+    
+    /// State of the backend, to unpin the sequence number from
+    state: Arc<RwLock<State>>,
+}
+
+impl Drop for MemorySnapshot {
+    fn drop(&mut self) {
+        let mut state = self.state.write().unwrap();
+        
+        if let Some(count) = state.snapshots.get_mut(&self.sequence) {
+            *count -= 1;
+            
+            if *count == 0 {
+                let oldest = state.oldest_snapshot();
+                state.snapshots.remove(&self.sequence);
+                
+                // Versions only this snapshot could see can go now
+                if oldest == self.sequence {
+                    state.prune_stale();
+                }
+            }
+        }
+    }
+}
+
+/// Backend that keeps everything in memory, in sorted maps behind a lock.
+///
+/// Every write gets a sequence number and keeps the versions that open snapshots still need,
+/// so snapshots are as cheap as on disk. Those versions are dropped when the key is written
+/// again or when the oldest snapshot that needs them is dropped. Nothing survives the backend being dropped.
+#[derive(Default)]
+pub struct MemoryBackend {
+    /// Every keyspace and the snapshots that are reading them
+    state: Arc<RwLock<State>>,
+}
+
+impl MemoryBackend {
+    /// Create an empty backend
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Pin the latest sequence number
+    fn pin(&self) -> MemorySnapshot {
+        let mut state = self.state.write().unwrap();
+        let sequence = state.sequence;
+        
+        *state.snapshots.entry(sequence).or_insert(0) += 1;
+        
+        MemorySnapshot {
+            sequence,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn get(&self, keyspace: Keyspace, key: &[u8], snapshot: Option<&Snapshot>) -> Result<Option<Vec<u8>>> {
+        let state = self.state.read().unwrap();
+        
+        let sequence = match snapshot {
+            Some(snapshot) => snapshot.downcast::<MemorySnapshot>()?.sequence,
+            None => state.sequence,
+        };
+        
+        let value = state.keyspaces
+            .get(&keyspace)
+            .and_then(|keys| keys.get(key))
+            .and_then(|versions| visible(versions, sequence))
+            .map(<[u8]>::to_vec);
+        
+        Ok(value)
+    }
+    
+    fn write(&self, batch: WriteBatch, _sync: bool) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        
+        let mut state = self.state.write().unwrap();
+        
+        state.sequence += 1;
+        
+        let sequence = state.sequence;
+        
+        for operation in batch.into_operations() {
+            let (keyspace, key, value) = match operation {
+                BatchOperation::Put(keyspace, key, value) => (keyspace, key, Some(value)),
+                BatchOperation::Delete(keyspace, key) => (keyspace, key, None),
+            };
+            
+            let versions = state.keyspaces.entry(keyspace).or_default().entry(key.clone()).or_default();
+            
+            // A later change to the same key in the batch replaces the earlier one
+            if versions.last().is_some_and(|version| version.sequence == sequence) {
+                versions.pop();
+            }
+            
+            versions.push(Version { sequence, value });
+            state.prune_key(keyspace, &key);
+        }
+        
+        Ok(())
+    }
+    
+    fn scan<'a>(&'a self, keyspace: Keyspace, prefix: &[u8], start: &[u8], direction: Direction, snapshot: Option<&'a Snapshot>) -> Result<KeyValueIter<'a>> {
+        // Without a snapshot, the scan pins its own so that it sees a consistent view
+        let (sequence, pinned) = match snapshot {
+            Some(snapshot) => (snapshot.downcast::<MemorySnapshot>()?.sequence, None),
+            None => {
+                let pinned = self.pin();
+                (pinned.sequence, Some(pinned))
+            }
+        };
+        
+        Ok(Box::new(MemoryScan {
+            state: &self.state,
+            keyspace,
+            prefix: prefix.to_vec(),
+            next: Bound::Included(start.to_vec()),
+            direction,
+            sequence,
+            buffer: VecDeque::new(),
+            done: false,
+            _pinned: pinned,
+        }))
+    }
+    
+    fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.pin())
+    }
+}
+
+/// A scan of a keyspace, which reads a chunk of entries at a time so that writes are not
+/// held up for the whole scan
+struct MemoryScan<'a> {
+    /// State of the backend
+    state: &'a RwLock<State>,
+    
+    /// Keyspace being scanned
+    keyspace: Keyspace,
+    
+    /// Prefix that every key in the scan starts with
+    prefix: Vec<u8>,
+    
+    /// Bound of the next chunk on the side the scan moves away from
+    next: Bound<Vec<u8>>,
+    
+    /// Order of the scan
+    direction: Direction,
+    
+    /// Sequence number of the last write the scan sees
+    sequence: u64,
+    
+    /// Entries read but not yet returned
+    buffer: VecDeque<KeyValue>,
+    
+    /// Set once the last chunk has been read
+    done: bool,
+    
+    /// Snapshot the scan took for itself, if it was not given one
+    _pinned: Option<MemorySnapshot>,
+}
+
+impl MemoryScan<'_> {
+    /// Read the next chunk of entries into the buffer
+    fn read_chunk(&mut self) {
+        let state = self.state.read().unwrap();
+        
+        let Some(keys) = state.keyspaces.get(&self.keyspace) else {
+            self.done = true;
+            return;
+        };
+        
+        let bound = match &self.next {
+            Bound::Included(key) => Bound::Included(key.as_slice()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_slice()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        
+        let range: Box<dyn Iterator<Item = (&Vec<u8>, &Vec<Version>)>> = match self.direction {
+            Direction::Forward => Box::new(keys.range::<[u8], _>((bound, Bound::Unbounded))),
+            Direction::Reverse => Box::new(keys.range::<[u8], _>((Bound::Unbounded, bound)).rev()),
+        };
+        
+        let mut read = 0;
+        let mut last = None;
+        
+        for (key, versions) in range {
+            if !key.starts_with(&self.prefix) {
+                self.done = true;
+                return;
+            }
+            
+            last = Some(key);
+            
+            if let Some(value) = visible(versions, self.sequence) {
+                self.buffer.push_back((key.as_slice().into(), value.into()));
+                read += 1;
+                
+                if read == SCAN_CHUNK_SIZE {
+                    break;
+                }
+            }
+        }
+        
+        match last {
+            Some(key) if read == SCAN_CHUNK_SIZE => self.next = Bound::Excluded(key.clone()),
+            _ => self.done = true,
+        }
+    }
+}
+
+impl Iterator for MemoryScan<'_> {
+    type Item = Result<KeyValue>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && !self.done {
+            self.read_chunk();
+        }
+        
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+/// Value of a key as of a sequence number, if it existed then
+fn visible(versions: &[Version], sequence: u64) -> Option<&[u8]> {
+    versions
+        .iter()
+        .rev()
+        .find(|version| version.sequence <= sequence)
+        .and_then(|version| version.value.as_deref())
+}
+
+/// Drop the versions of a key that no snapshot can see: every one older than the version
+/// the oldest snapshot reads, and that version too if it is a deletion
+fn prune(versions: &mut Vec<Version>, oldest: u64) {
+    let Some(seen) = versions.iter().rposition(|version| version.sequence <= oldest) else {
+        return;
+    };
+    
+    let keep_from = if versions[seen].value.is_none() { seen + 1 } else { seen };
+    
+    versions.drain(..keep_from);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Write one key, or delete it if there is no value
+    fn write(backend: &MemoryBackend, key: &[u8], value: Option<&[u8]>) {
+        let mut batch = WriteBatch::default();
+        
+        match value {
+            Some(value) => batch.put(Keyspace::Documents, key, value),
+            None => batch.delete(Keyspace::Documents, key),
+        }
+        
+        backend.write(batch, false).unwrap();
+    }
+    
+    /// Value of a key, from a snapshot if one is given
+    fn get(backend: &MemoryBackend, key: &[u8], snapshot: Option<&Snapshot>) -> Option<Vec<u8>> {
+        backend.get(Keyspace::Documents, key, snapshot).unwrap()
+    }
+    
+    /// Keys a scan returns
+    fn scan(backend: &MemoryBackend, prefix: &[u8], start: &[u8], direction: Direction, snapshot: Option<&Snapshot>) -> Vec<Vec<u8>> {
+        backend
+            .scan(Keyspace::Documents, prefix, start, direction, snapshot)
+            .unwrap()
+            .map(|item| item.unwrap().0.to_vec())
+            .collect()
+    }
+    
+    /// Number of versions stored for a key
+    fn versions(backend: &MemoryBackend, key: &[u8]) -> usize {
+        let state = backend.state.read().unwrap();
+        
+        state.keyspaces.get(&Keyspace::Documents).and_then(|keys| keys.get(key)).map_or(0, Vec::len)
+    }
+    
+    #[test]
+    fn snapshots_see_the_data_as_it_was() {
+        let backend = MemoryBackend::new();
+        write(&backend, b"a", Some(b"1"));
+        
+        let snapshot = backend.snapshot();
+        write(&backend, b"a", Some(b"2"));
+        write(&backend, b"b", Some(b"1"));
+        
+        assert_eq!(get(&backend, b"a", None), Some(b"2".to_vec()));
+        assert_eq!(get(&backend, b"a", Some(&snapshot)), Some(b"1".to_vec()));
+        assert_eq!(get(&backend, b"b", Some(&snapshot)), None);
+        
+        write(&backend, b"a", None);
+        
+        assert_eq!(get(&backend, b"a", None), None);
+        assert_eq!(get(&backend, b"a", Some(&snapshot)), Some(b"1".to_vec()));
+        assert_eq!(scan(&backend, b"", b"", Direction::Forward, Some(&snapshot)), vec![b"a".to_vec()]);
+        assert_eq!(scan(&backend, b"", b"", Direction::Forward, None), vec![b"b".to_vec()]);
+    }
+    
+    #[test]
+    fn versions_are_pruned_once_no_snapshot_needs_them() {
+        let backend = MemoryBackend::new();
+        write(&backend, b"kept", Some(b"1"));
+        write(&backend, b"deleted", Some(b"1"));
+        
+        // Without snapshots, only the latest version is kept
+        write(&backend, b"kept", Some(b"2"));
+        assert_eq!(versions(&backend, b"kept"), 1);
+        
+        let older = backend.snapshot();
+        write(&backend, b"kept", Some(b"3"));
+        
+        let newer = backend.snapshot();
+        write(&backend, b"kept", Some(b"4"));
+        write(&backend, b"deleted", None);
+        
+        assert_eq!(versions(&backend, b"kept"), 3);
+        assert_eq!(versions(&backend, b"deleted"), 2);
+        
+        // Dropping a snapshot prunes keys that are not written again
+        drop(older);
+        assert_eq!(versions(&backend, b"kept"), 2);
+        assert_eq!(get(&backend, b"kept", Some(&newer)), Some(b"3".to_vec()));
+        
+        drop(newer);
+        assert_eq!(versions(&backend, b"kept"), 1);
+        assert_eq!(versions(&backend, b"deleted"), 0);
+        assert!(backend.state.read().unwrap().stale.is_empty());
+    }
+    
+    #[test]
+    fn scans_move_in_either_direction_within_the_prefix() {
+        let backend = MemoryBackend::new();
+        
+        for key in [&b"a1"[..], b"b1", b"b2", b"b3", b"c1"] {
+            write(&backend, key, Some(b"x"));
+        }
+        
+        assert_eq!(scan(&backend, b"b", b"b", Direction::Forward, None), vec![b"b1".to_vec(), b"b2".to_vec(), b"b3".to_vec()]);
+        assert_eq!(scan(&backend, b"b", b"b2", Direction::Forward, None), vec![b"b2".to_vec(), b"b3".to_vec()]);
+        assert_eq!(scan(&backend, b"b", b"b2", Direction::Reverse, None), vec![b"b2".to_vec(), b"b1".to_vec()]);
+        assert_eq!(scan(&backend, b"b", b"b\xff", Direction::Reverse, None), vec![b"b3".to_vec(), b"b2".to_vec(), b"b1".to_vec()]);
+        assert!(scan(&backend, b"d", b"d", Direction::Forward, None).is_empty());
+    }
+    
+    #[test]
+    fn scans_span_many_chunks() {
+        let backend = MemoryBackend::new();
+        let keys: Vec<Vec<u8>> = (0..SCAN_CHUNK_SIZE as u32 * 2 + 10).map(|n| n.to_be_bytes().to_vec()).collect();
+        
+        for key in &keys {
+            write(&backend, key, Some(b"x"));
+        }
+        
+        let mut scanned = backend.scan(Keyspace::Documents, b"", b"", Direction::Forward, None).unwrap();
+        let first = scanned.next().unwrap().unwrap().0.to_vec();
+        
+        // Written after the scan began, so not seen by it
+        write(&backend, &keys[SCAN_CHUNK_SIZE + 1], None);
+        
+        let rest: Vec<_> = scanned.map(|item| item.unwrap().0.to_vec()).collect();
+        assert_eq!([vec![first], rest].concat(), keys);
+        
+        let reversed = scan(&backend, b"", &[0xff; 4], Direction::Reverse, None);
+        assert_eq!(reversed.len(), keys.len() - 1);
+        assert_eq!(reversed.first(), keys.last());
+    }
+}
@@ -0,0 +1,190 @@
+use clap::ValueEnum;
+use serde_json::Value;
+
+use xlim::backup::BackupInfo;
+use xlim::document::Document;
+use xlim::error::Result;
+use xlim::transaction::TransactionInfo;
+
+/// How query results are printed on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// One JSON document per line
+    Json,
+    /// An aligned text table with one column per field
+    Table,
+}
+
+/// Print documents to stdout in the given format
+pub fn print_documents(documents: &[Document], format: OutputFormat) -> Result<()> {
+    match format {
+        OutputFormat::Json => {
+            for document in documents {
+                println!("{}", document.to_json()?);
+            }
+        }
+        OutputFormat::Table => print!("{}", render_table(documents)),
+    }
+    
+    Ok(())
+}
+
+/// Render documents as a table with an `id` column followed by every data field in order of appearance
+pub fn render_table(documents: &[Document]) -> String {
+    let mut columns: Vec<String> = vec!["id".to_string()];
+    
+    for document in documents {
+        for field in document.data.keys() {
+            if !columns.contains(field) {
+                columns.push(field.clone());
+            }
+        }
+    }
+    
+    let rows: Vec<Vec<String>> = documents
+        .iter()
+        .map(|document| {
+            columns
+                .iter()
+                .enumerate()
+                .map(|(i, column)| match i {
+                    0 => document.id.to_string(),
+                    _ => document.get(column).map(format_cell).unwrap_or_default(),
+                })
+                .collect()
+        })
+        .collect();
+    
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain(std::iter::once(column.chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    
+    let mut table = format_row(&columns, &widths);
+    let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    table.push_str(&format_row(&separator, &widths));
+    
+    for row in &rows {
+        table.push_str(&format_row(row, &widths));
+    }
+    
+    table.push_str(&format!("({} documents)\n", documents.len()));
+    
+    table
+}
+
+/// Render a summary of active transactions as a table
+pub fn render_transactions(transactions: &[TransactionInfo]) -> String {
+    let columns: Vec<String> = ["id", "isolation", "age", "operations", "locks", "expires in"]
+        .iter()
+        .map(|column| column.to_string())
+        .collect();
+    
+    let rows: Vec<Vec<String>> = transactions
+        .iter()
+        .map(|transaction| {
+            vec![
+                transaction.id.to_string(),
+                format!("{:?}", transaction.isolation),
+                format!("{}s", transaction.age.as_secs()),
+                transaction.operations.to_string(),
+                transaction.locks.to_string(),
+                transaction.expires_in.map(|left| format!("{}s", left.as_secs())).unwrap_or_else(|| "never".to_string()),
+            ]
+        })
+        .collect();
+    
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain(std::iter::once(column.chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    
+    let mut table = format_row(&columns, &widths);
+    let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    table.push_str(&format_row(&separator, &widths));
+    
+    for row in &rows {
+        table.push_str(&format_row(row, &widths));
+    }
+    
+    table.push_str(&format!("({} transactions)\n", transactions.len()));
+    
+    table
+}
+
+/// Render the backups in a backup directory as a table
+pub fn render_backups(backups: &[BackupInfo]) -> String {
+    let columns: Vec<String> = ["id", "created", "size", "files"]
+        .iter()
+        .map(|column| column.to_string())
+        .collect();
+    
+    let rows: Vec<Vec<String>> = backups
+        .iter()
+        .map(|backup| {
+            vec![
+                backup.id.to_string(),
+                backup.created_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+                format!("{} bytes", backup.size),
+                backup.files.to_string(),
+            ]
+        })
+        .collect();
+    
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| row[i].chars().count())
+                .chain(std::iter::once(column.chars().count()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    
+    let mut table = format_row(&columns, &widths);
+    let separator: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    table.push_str(&format_row(&separator, &widths));
+    
+    for row in &rows {
+        table.push_str(&format_row(row, &widths));
+    }
+    
+    table.push_str(&format!("({} backups)\n", backups.len()));
+    
+    table
+}
+
+/// Format a single table row, padding every cell to its column width
+fn format_row(cells: &[String], widths: &[usize]) -> String {
+    let padded: Vec<String> = cells
+        .iter()
+        .zip(widths)
+        .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+        .collect();
+    
+    format!("{}\n", padded.join(" | ").trim_end())
+}
+
+/// Format a value for a table cell, printing strings without quotes
+fn format_cell(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,688 @@
+//! Lexer and parser for the XLim query language.
+//!
+//! ```text
+//! query     := FROM name clause*
+//! clause    := WHERE condition ((AND | OR) condition)*
+//!            | ORDER BY field [ASC | DESC] (, field [ASC | DESC])*
+//!            | LIMIT integer
+//!            | SKIP integer
+//!            | SELECT field (, field)*
+//! condition := field operator value
+//! operator  := = | == | != | <> | > | >= | < | <=
+//!            | CONTAINS | STARTSWITH | ENDSWITH | IN | NOT IN
+//! value     := string | number | TRUE | FALSE | NULL | [ value (, value)* ]
+//! ```
+//!
+//! Keywords are case-insensitive and each clause may appear at most once, in any order.
+//! Conditions are combined from left to right without precedence, matching `Query::matches`.
+
+use serde_json::{Number, Value};
+use std::fmt;
+
+use crate::error::{Result, XLimError};
+use crate::query::{ComparisonOperator, Condition, LogicalOperator, Query};
+
+/// A byte range in the query source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// Offset of the first byte
+    pub start: usize,
+    
+    /// Offset one past the last byte
+    pub end: usize,
+}
+
+impl Span {
+    fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+}
+
+/// An error found while lexing or parsing a query
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    /// Description of the problem
+    pub message: String,
+    
+    /// Location of the problem in the source
+    pub span: Span,
+}
+
+impl ParseError {
+    fn new(message: impl Into<String>, span: Span) -> Self {
+        Self {
+            message: message.into(),
+            span,
+        }
+    }
+    
+    /// Render the error with the offending part of the source underlined
+    pub fn render(&self, source: &str) -> String {
+        let line_start = source[..self.span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[self.span.start..]
+            .find('\n')
+            .map_or(source.len(), |i| self.span.start + i);
+        let line_number = source[..line_start].matches('\n').count() + 1;
+        let column = source[line_start..self.span.start].chars().count() + 1;
+        let width = source[self.span.start..self.span.end.min(line_end)].chars().count().max(1);
+        
+        format!(
+            "{} at line {}, column {}\n  {}\n  {}{}",
+            self.message,
+            line_number,
+            column,
+            &source[line_start..line_end],
+            " ".repeat(column - 1),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.span.start)
+    }
+}
+
+/// Result type used inside the lexer and parser
+type ParseResult<T> = std::result::Result<T, ParseError>;
+
+/// A query parsed from the query language
+#[derive(Debug, Clone)]
+pub struct ParsedQuery {
+    /// Collection named in the FROM clause
+    pub collection: String,
+    
+    /// The compiled query
+    pub query: Query,
+}
+
+/// Parse a query string, reporting errors as `XLimError::Query` with the source underlined
+pub fn parse(source: &str) -> Result<ParsedQuery> {
+    Parser::new(source)
+        .and_then(|mut parser| parser.parse_query())
+        .map_err(|e| XLimError::Query(e.render(source)))
+}
+
+/// Kinds of tokens produced by the lexer
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    /// A keyword or field name
+    Ident(String),
+    /// A quoted string literal
+    Str(String),
+    /// A numeric literal
+    Number(Number),
+    /// A comparison operator written with symbols
+    Symbol(&'static str),
+    /// ,
+    Comma,
+    /// [
+    LeftBracket,
+    /// ]
+    RightBracket,
+    /// End of input
+    Eof,
+}
+
+impl TokenKind {
+    fn describe(&self) -> String {
+        match self {
+            Self::Ident(name) => format!("'{}'", name),
+            Self::Str(value) => format!("string \"{}\"", value),
+            Self::Number(value) => format!("number {}", value),
+            Self::Symbol(symbol) => format!("'{}'", symbol),
+            Self::Comma => "','".to_string(),
+            Self::LeftBracket => "'['".to_string(),
+            Self::RightBracket => "']'".to_string(),
+            Self::Eof => "end of query".to_string(),
+        }
+    }
+}
+
+/// A token and its location in the source
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    span: Span,
+}
+
+/// Split a query string into tokens
+fn tokenize(source: &str) -> ParseResult<Vec<Token>> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    
+    while pos < bytes.len() {
+        let c = bytes[pos];
+        let start = pos;
+        
+        if c.is_ascii_whitespace() {
+            pos += 1;
+            continue;
+        }
+        
+        let kind = match c {
+            b',' => {
+                pos += 1;
+                TokenKind::Comma
+            }
+            b'[' => {
+                pos += 1;
+                TokenKind::LeftBracket
+            }
+            b']' => {
+                pos += 1;
+                TokenKind::RightBracket
+            }
+            b'=' | b'!' | b'<' | b'>' => {
+                let two = source.get(pos..pos + 2).unwrap_or("");
+                let symbol = match two {
+                    "==" => "==",
+                    "!=" => "!=",
+                    "<>" => "<>",
+                    ">=" => ">=",
+                    "<=" => "<=",
+                    _ => match c {
+                        b'=' => "=",
+                        b'<' => "<",
+                        b'>' => ">",
+                        _ => return Err(ParseError::new("Expected '!='", Span::new(pos, pos + 1))),
+                    },
+                };
+                pos += symbol.len();
+                TokenKind::Symbol(symbol)
+            }
+            b'"' | b'\'' => {
+                let (value, end) = lex_string(source, pos)?;
+                pos = end;
+                TokenKind::Str(value)
+            }
+            b'-' | b'0'..=b'9' => {
+                let (number, end) = lex_number(source, pos)?;
+                pos = end;
+                TokenKind::Number(number)
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' => {
+                while pos < bytes.len() && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_') {
+                    pos += 1;
+                }
+                TokenKind::Ident(source[start..pos].to_string())
+            }
+            _ => {
+                let len = source[pos..].chars().next().map_or(1, char::len_utf8);
+                return Err(ParseError::new("Unexpected character", Span::new(pos, pos + len)));
+            }
+        };
+        
+        tokens.push(Token {
+            kind,
+            span: Span::new(start, pos),
+        });
+    }
+    
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        span: Span::new(source.len(), source.len()),
+    });
+    
+    Ok(tokens)
+}
+
+/// Lex a quoted string starting at `start`, returning its value and the offset after the closing quote
+fn lex_string(source: &str, start: usize) -> ParseResult<(String, usize)> {
+    let mut chars = source[start..].char_indices();
+    let (_, quote) = chars.next().unwrap_or((0, '"'));
+    let mut value = String::new();
+    
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c == quote => return Ok((value, start + i + 1)),
+            '\\' => {
+                let (j, escaped) = chars
+                    .next()
+                    .ok_or_else(|| ParseError::new("Unterminated string", Span::new(start, source.len())))?;
+                
+                value.push(match escaped {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    '\\' | '"' | '\'' => escaped,
+                    _ => {
+                        let at = start + j;
+                        return Err(ParseError::new("Unknown escape sequence", Span::new(at - 1, at + escaped.len_utf8())));
+                    }
+                });
+            }
+            c => value.push(c),
+        }
+    }
+    
+    Err(ParseError::new("Unterminated string", Span::new(start, source.len())))
+}
+
+/// Lex a number starting at `start`, returning it and the offset after its last digit
+fn lex_number(source: &str, start: usize) -> ParseResult<(Number, usize)> {
+    let bytes = source.as_bytes();
+    let mut pos = start;
+    
+    if bytes[pos] == b'-' {
+        pos += 1;
+    }
+    
+    let digits_start = pos;
+    
+    while pos < bytes.len() && (bytes[pos].is_ascii_digit() || matches!(bytes[pos], b'.' | b'e' | b'E')) {
+        // Allow a sign directly after an exponent marker
+        if matches!(bytes[pos], b'e' | b'E') && pos + 1 < bytes.len() && matches!(bytes[pos + 1], b'+' | b'-') {
+            pos += 1;
+        }
+        
+        pos += 1;
+    }
+    
+    let text = &source[start..pos];
+    let span = Span::new(start, pos.max(start + 1));
+    
+    if pos == digits_start {
+        return Err(ParseError::new("Expected a number", span));
+    }
+    
+    if let Ok(value) = text.parse::<i64>() {
+        return Ok((Number::from(value), pos));
+    }
+    
+    text.parse::<f64>()
+        .ok()
+        .and_then(Number::from_f64)
+        .map(|number| (number, pos))
+        .ok_or_else(|| ParseError::new(format!("Invalid number '{}'", text), span))
+}
+
+/// Recursive-descent parser over a token stream
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(source: &str) -> ParseResult<Self> {
+        Ok(Self {
+            tokens: tokenize(source)?,
+            pos: 0,
+        })
+    }
+    
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos]
+    }
+    
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.pos].clone();
+        
+        if self.pos < self.tokens.len() - 1 {
+            self.pos += 1;
+        }
+        
+        token
+    }
+    
+    fn error<T>(&self, expected: &str) -> ParseResult<T> {
+        let token = self.peek();
+        Err(ParseError::new(
+            format!("Expected {}, found {}", expected, token.kind.describe()),
+            token.span,
+        ))
+    }
+    
+    /// Check whether the next token is the given keyword
+    fn at_keyword(&self, keyword: &str) -> bool {
+        matches!(&self.peek().kind, TokenKind::Ident(name) if name.eq_ignore_ascii_case(keyword))
+    }
+    
+    /// Consume the given keyword if it is next
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.at_keyword(keyword) {
+            self.advance();
+            return true;
+        }
+        
+        false
+    }
+    
+    fn expect_keyword(&mut self, keyword: &str) -> ParseResult<()> {
+        if self.eat_keyword(keyword) {
+            return Ok(());
+        }
+        
+        self.error(keyword)
+    }
+    
+    /// Consume an identifier that is not a reserved keyword
+    fn expect_name(&mut self, what: &str) -> ParseResult<String> {
+        match &self.peek().kind {
+            TokenKind::Ident(name) if !is_keyword(name) => {
+                let name = name.clone();
+                self.advance();
+                Ok(name)
+            }
+            TokenKind::Str(name) => {
+                let name = name.clone();
+                self.advance();
+                Ok(name)
+            }
+            _ => self.error(what),
+        }
+    }
+    
+    fn parse_query(&mut self) -> ParseResult<ParsedQuery> {
+        self.expect_keyword("FROM")?;
+        let collection = self.expect_name("a collection name")?;
+        let mut query = Query::new();
+        let mut seen: Vec<&'static str> = Vec::new();
+        
+        loop {
+            let clause = match &self.peek().kind {
+                TokenKind::Eof => break,
+                TokenKind::Ident(name) => ["WHERE", "ORDER", "LIMIT", "SKIP", "SELECT"]
+                    .into_iter()
+                    .find(|clause| name.eq_ignore_ascii_case(clause)),
+                _ => None,
+            };
+            
+            let Some(clause) = clause else {
+                return self.error("WHERE, ORDER BY, LIMIT, SKIP or SELECT");
+            };
+            
+            if seen.contains(&clause) {
+                let span = self.peek().span;
+                return Err(ParseError::new(format!("Duplicate {} clause", clause), span));
+            }
+            
+            seen.push(clause);
+            self.advance();
+            
+            match clause {
+                "WHERE" => self.parse_where(&mut query)?,
+                "ORDER" => {
+                    self.expect_keyword("BY")?;
+                    self.parse_order_by(&mut query)?;
+                }
+                "LIMIT" => query.limit = Some(self.parse_count("LIMIT")?),
+                "SKIP" => query.skip = Some(self.parse_count("SKIP")?),
+                _ => query.projection = Some(self.parse_field_list()?),
+            }
+        }
+        
+        Ok(ParsedQuery { collection, query })
+    }
+    
+    fn parse_where(&mut self, query: &mut Query) -> ParseResult<()> {
+        query.conditions.push(self.parse_condition()?);
+        
+        loop {
+            let operator = if self.eat_keyword("AND") {
+                LogicalOperator::And
+            } else if self.eat_keyword("OR") {
+                LogicalOperator::Or
+            } else {
+                return Ok(());
+            };
+            
+            query.operators.push(operator);
+            query.conditions.push(self.parse_condition()?);
+        }
+    }
+    
+    fn parse_condition(&mut self) -> ParseResult<Condition> {
+        let field = self.expect_name("a field name")?;
+        let operator = self.parse_operator()?;
+        let value_span = self.peek().span;
+        let value = self.parse_value()?;
+        
+        if matches!(operator, ComparisonOperator::In | ComparisonOperator::NotIn) && !value.is_array() {
+            return Err(ParseError::new("IN requires a list such as [1, 2, 3]", value_span));
+        }
+        
+        Ok(Condition::new(&field, operator, value))
+    }
+    
+    fn parse_operator(&mut self) -> ParseResult<ComparisonOperator> {
+        let operator = match &self.peek().kind {
+            TokenKind::Symbol(symbol) => symbol.parse().ok(),
+            TokenKind::Ident(name) => match name.to_ascii_uppercase().as_str() {
+                "CONTAINS" => Some(ComparisonOperator::Contains),
+                "STARTSWITH" => Some(ComparisonOperator::StartsWith),
+                "ENDSWITH" => Some(ComparisonOperator::EndsWith),
+                "IN" => Some(ComparisonOperator::In),
+                "NOT" => {
+                    self.advance();
+                    self.expect_keyword("IN")?;
+                    return Ok(ComparisonOperator::NotIn);
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        
+        match operator {
+            Some(operator) => {
+                self.advance();
+                Ok(operator)
+            }
+            None => self.error("a comparison operator"),
+        }
+    }
+    
+    fn parse_value(&mut self) -> ParseResult<Value> {
+        let token = self.peek().clone();
+        
+        let value = match token.kind {
+            TokenKind::Str(value) => Value::String(value),
+            TokenKind::Number(number) => Value::Number(number),
+            TokenKind::Ident(name) if name.eq_ignore_ascii_case("TRUE") => Value::Bool(true),
+            TokenKind::Ident(name) if name.eq_ignore_ascii_case("FALSE") => Value::Bool(false),
+            TokenKind::Ident(name) if name.eq_ignore_ascii_case("NULL") => Value::Null,
+            TokenKind::LeftBracket => {
+                self.advance();
+                return self.parse_list();
+            }
+            _ => return self.error("a value"),
+        };
+        
+        self.advance();
+        
+        Ok(value)
+    }
+    
+    fn parse_list(&mut self) -> ParseResult<Value> {
+        let mut values = Vec::new();
+        
+        if self.peek().kind == TokenKind::RightBracket {
+            self.advance();
+            return Ok(Value::Array(values));
+        }
+        
+        loop {
+            values.push(self.parse_value()?);
+            
+            match self.peek().kind {
+                TokenKind::Comma => {
+                    self.advance();
+                }
+                TokenKind::RightBracket => {
+                    self.advance();
+                    return Ok(Value::Array(values));
+                }
+                _ => return self.error("',' or ']'"),
+            }
+        }
+    }
+    
+    fn parse_order_by(&mut self, query: &mut Query) -> ParseResult<()> {
+        loop {
+            let field = self.expect_name("a field name")?;
+            let ascending = !self.eat_keyword("DESC");
+            
+            if ascending {
+                self.eat_keyword("ASC");
+            }
+            
+            query.sort.push((field, ascending));
+            
+            if self.peek().kind != TokenKind::Comma {
+                return Ok(());
+            }
+            
+            self.advance();
+        }
+    }
+    
+    fn parse_count(&mut self, clause: &str) -> ParseResult<usize> {
+        let token = self.peek().clone();
+        
+        match token.kind {
+            TokenKind::Number(number) => match number.as_u64() {
+                Some(count) => {
+                    self.advance();
+                    Ok(count as usize)
+                }
+                None => Err(ParseError::new(
+                    format!("{} requires a non-negative integer", clause),
+                    token.span,
+                )),
+            },
+            _ => self.error("a non-negative integer"),
+        }
+    }
+    
+    fn parse_field_list(&mut self) -> ParseResult<Vec<String>> {
+        let mut fields = vec![self.expect_name("a field name")?];
+        
+        while self.peek().kind == TokenKind::Comma {
+            self.advance();
+            fields.push(self.expect_name("a field name")?);
+        }
+        
+        Ok(fields)
+    }
+}
+
+/// Words that cannot be used as bare collection or field names
+fn is_keyword(word: &str) -> bool {
+    const KEYWORDS: [&str; 19] = [
+        "FROM", "WHERE", "AND", "OR", "ORDER", "BY", "ASC", "DESC", "LIMIT", "SKIP", "SELECT",
+        "CONTAINS", "STARTSWITH", "ENDSWITH", "IN", "NOT", "TRUE", "FALSE", "NULL",
+    ];
+    
+    KEYWORDS.iter().any(|keyword| word.eq_ignore_ascii_case(keyword))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use serde_json::json;
+    
+    /// Parse a query that must be valid
+    fn query(source: &str) -> ParsedQuery {
+        Parser::new(source).and_then(|mut parser| parser.parse_query()).unwrap()
+    }
+    
+    /// Parse a query that must be invalid, returning the error
+    fn error(source: &str) -> ParseError {
+        Parser::new(source).and_then(|mut parser| parser.parse_query()).unwrap_err()
+    }
+    
+    #[test]
+    fn from_clause() {
+        let parsed = query("from users");
+        
+        assert_eq!(parsed.collection, "users");
+        assert!(parsed.query.conditions.is_empty());
+        assert_eq!(query("FROM \"order items\"").collection, "order items");
+    }
+    
+    #[test]
+    fn where_clause() {
+        let parsed = query("FROM users WHERE age >= 30 AND name STARTSWITH 'J' AND role NOT IN ['guest', null]");
+        let conditions = &parsed.query.conditions;
+        
+        assert_eq!(conditions.len(), 3);
+        assert_eq!(conditions[0].field, "age");
+        assert_eq!(conditions[0].operator, ComparisonOperator::Gte);
+        assert_eq!(conditions[0].value, json!(30));
+        assert_eq!(conditions[1].operator, ComparisonOperator::StartsWith);
+        assert_eq!(conditions[1].value, json!("J"));
+        assert_eq!(conditions[2].operator, ComparisonOperator::NotIn);
+        assert_eq!(conditions[2].value, json!(["guest", null]));
+    }
+    
+    #[test]
+    fn order_by_clause() {
+        let parsed = query("FROM users ORDER BY age DESC, name ASC, id");
+        
+        assert_eq!(
+            parsed.query.sort,
+            vec![("age".to_string(), false), ("name".to_string(), true), ("id".to_string(), true)]
+        );
+    }
+    
+    #[test]
+    fn limit_skip_and_select_clauses() {
+        let parsed = query("FROM users SELECT name, age SKIP 20 LIMIT 10");
+        
+        assert_eq!(parsed.query.limit, Some(10));
+        assert_eq!(parsed.query.skip, Some(20));
+        assert_eq!(parsed.query.projection, Some(vec!["name".to_string(), "age".to_string()]));
+    }
+    
+    #[test]
+    fn operators_combine_from_left_to_right() {
+        let parsed = query("FROM users WHERE a = 1 OR b = 1 AND c = 1");
+        
+        assert_eq!(parsed.query.operators, vec![LogicalOperator::Or, LogicalOperator::And]);
+        
+        // (a OR b) AND c, rather than a OR (b AND c)
+        let document = Document::new().set("a", 1).set("b", 0).set("c", 0);
+        
+        assert!(!parsed.query.matches(&document).unwrap());
+    }
+    
+    #[test]
+    fn one_operator_between_each_pair_of_conditions() {
+        for source in ["FROM c", "FROM c WHERE a = 1", "FROM c WHERE a = 1 AND b = 2", "FROM c WHERE a = 1 OR b = 2 AND c = 3 OR d = 4"] {
+            let parsed = query(source);
+            
+            assert_eq!(parsed.query.operators.len(), parsed.query.conditions.len().saturating_sub(1), "{}", source);
+            assert!(parsed.query.validate().is_ok());
+        }
+    }
+    
+    #[test]
+    fn errors_point_at_the_offending_token() {
+        let cases = [
+            ("users", "Expected FROM", Span::new(0, 5)),
+            ("FROM users WHERE age ~ 3", "Unexpected character", Span::new(21, 22)),
+            ("FROM users WHERE age > ", "Expected a value", Span::new(23, 23)),
+            ("FROM users WHERE name = 'Jane", "Unterminated string", Span::new(24, 29)),
+            ("FROM users LIMIT 5 LIMIT 6", "Duplicate LIMIT clause", Span::new(19, 24)),
+            ("FROM users LIMIT -1", "LIMIT requires a non-negative integer", Span::new(17, 19)),
+            ("FROM users WHERE tag IN 'a'", "IN requires a list", Span::new(24, 27)),
+        ];
+        
+        for (source, message, span) in cases {
+            let error = error(source);
+            
+            assert!(error.message.starts_with(message), "{}: {}", source, error.message);
+            assert_eq!(error.span, span, "{}", source);
+        }
+    }
+    
+    #[test]
+    fn errors_render_with_the_source_underlined() {
+        let rendered = error("FROM users\nWHERE age >> 3").render("FROM users\nWHERE age >> 3");
+        
+        assert_eq!(rendered, "Expected a value, found '>' at line 2, column 12\n  WHERE age >> 3\n             ^");
+    }
+}
@@ -0,0 +1,388 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::Result;
+use crate::index;
+use crate::query::{ComparisonOperator, Condition, LogicalOperator, Query};
+
+/// Maximum number of index entries counted when estimating the cost of an index scan
+const ESTIMATE_LIMIT: usize = 10_000;
+
+/// How the documents of a query are found
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Access {
+    /// Read every document in the collection
+    CollectionScan,
+    /// Look up the index entries of a list of exact values
+    IndexLookup { field: String, values: Vec<Value> },
+    /// Scan a range of index entries; each bound is a value and whether it is inclusive
+    IndexRange { field: String, lower: Option<(Value, bool)>, upper: Option<(Value, bool)> },
+    /// Scan the index entries of strings that start with a prefix
+    IndexPrefix { field: String, prefix: String },
+}
+
+impl Access {
+    /// Get the indexed field, if the access uses an index
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            Self::CollectionScan => None,
+            Self::IndexLookup { field, .. } | Self::IndexRange { field, .. } | Self::IndexPrefix { field, .. } => Some(field),
+        }
+    }
+    
+    /// Get the index key ranges to scan, in index order, as `(start, end)` with `end` exclusive
+    pub fn key_ranges(&self, collection_id: u64) -> Vec<(Vec<u8>, Vec<u8>)> {
+        match self {
+            Self::CollectionScan => Vec::new(),
+            Self::IndexLookup { field, values } => {
+                let mut ranges: Vec<(Vec<u8>, Vec<u8>)> = values
+                    .iter()
+                    .map(|value| {
+                        let prefix = index::value_prefix(collection_id, field, value);
+                        let end = index::prefix_end(&prefix);
+                        (prefix, end)
+                    })
+                    .collect();
+                
+                ranges.sort();
+                ranges.dedup();
+                ranges
+            }
+            Self::IndexRange { field, lower, upper } => {
+                let start = match lower {
+                    Some((value, true)) => index::value_prefix(collection_id, field, value),
+                    Some((value, false)) => index::prefix_end(&index::value_prefix(collection_id, field, value)),
+                    None => index::index_prefix(collection_id, field),
+                };
+                
+                let end = match upper {
+                    Some((value, true)) => index::prefix_end(&index::value_prefix(collection_id, field, value)),
+                    Some((value, false)) => index::value_prefix(collection_id, field, value),
+                    None => index::prefix_end(&index::index_prefix(collection_id, field)),
+                };
+                
+                if start < end {
+                    vec![(start, end)]
+                } else {
+                    Vec::new()
+                }
+            }
+            Self::IndexPrefix { field, prefix } => {
+                let start = index::string_prefix(collection_id, field, prefix);
+                let end = index::prefix_end(&start);
+                vec![(start, end)]
+            }
+        }
+    }
+}
+
+/// The plan chosen for a query, along with statistics from running it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryPlan {
+    /// How the documents are found
+    pub access: Access,
+    
+    /// Conditions answered by the index
+    pub index_conditions: Vec<Condition>,
+    
+    /// Conditions checked against every document read
+    pub residual: Vec<Condition>,
+    
+    /// Whether the index returns documents already in the requested sort order
+    pub sort_from_index: bool,
+    
+    /// Estimated number of documents read, capped at 10,000 for index scans
+    pub estimated_documents: usize,
+    
+    /// Number of documents actually read
+    pub scanned_documents: usize,
+    
+    /// Number of documents returned
+    pub returned_documents: usize,
+}
+
+impl QueryPlan {
+    /// Create a plan that reads the whole collection
+    fn collection_scan(query: &Query) -> Self {
+        Self {
+            access: Access::CollectionScan,
+            index_conditions: Vec::new(),
+            residual: query.conditions.clone(),
+            sort_from_index: false,
+            estimated_documents: 0,
+            scanned_documents: 0,
+            returned_documents: 0,
+        }
+    }
+    
+    /// Whether the index must be scanned from the end to produce the sort order
+    pub fn is_reverse(&self, query: &Query) -> bool {
+        self.sort_from_index && query.sort.first().is_some_and(|(_, ascending)| !ascending)
+    }
+    
+    /// Number of matching documents that must be collected before sorting and paging,
+    /// or `None` if every match is needed
+    pub fn match_limit(&self, query: &Query) -> Option<usize> {
+        if self.sort_from_index {
+            return query.limit.map(|limit| query.skip.unwrap_or(0).saturating_add(limit));
+        }
+        
+        query.match_limit()
+    }
+}
+
+/// Choose how to run a query on a collection with the given indexed fields.
+///
+/// Every candidate index access is costed with `count`, which counts the index entries in a
+/// list of key ranges up to a limit, and the cheapest one wins. Without a usable index the
+/// collection is scanned and the estimate is left at zero for the caller to fill in.
+pub fn plan<F>(collection_id: u64, query: &Query, indexes: &[String], count: F) -> Result<QueryPlan>
+where
+    F: Fn(&[(Vec<u8>, Vec<u8>)], usize) -> Result<usize>,
+{
+    let mut best: Option<(usize, bool, Access, Vec<usize>)> = None;
+    
+    for (access, used) in candidates(query, indexes) {
+        let estimate = count(&access.key_ranges(collection_id), ESTIMATE_LIMIT)?;
+        let serves_sort = serves_sort(&access, query);
+        
+        // Prefer fewer entries, then an access that also serves the sort
+        let better = match &best {
+            Some((best_estimate, best_serves_sort, _, _)) => {
+                estimate < *best_estimate || (estimate == *best_estimate && serves_sort && !best_serves_sort)
+            }
+            None => true,
+        };
+        
+        if better {
+            best = Some((estimate, serves_sort, access, used));
+        }
+    }
+    
+    let Some((estimate, serves_sort, access, used)) = best else {
+        return Ok(QueryPlan::collection_scan(query));
+    };
+    
+    let (index_conditions, residual) = query
+        .conditions
+        .iter()
+        .enumerate()
+        .partition::<Vec<_>, _>(|(i, _)| used.contains(i));
+    
+    Ok(QueryPlan {
+        access,
+        index_conditions: index_conditions.into_iter().map(|(_, c)| c.clone()).collect(),
+        residual: residual.into_iter().map(|(_, c)| c.clone()).collect(),
+        sort_from_index: serves_sort,
+        estimated_documents: estimate,
+        scanned_documents: 0,
+        returned_documents: 0,
+    })
+}
+
+/// Check whether an index access returns documents in the query's sort order
+fn serves_sort(access: &Access, query: &Query) -> bool {
+    match (access.field(), query.sort.as_slice()) {
+        (Some(field), [(sort_field, _)]) => field == sort_field,
+        _ => false,
+    }
+}
+
+/// Build every index access that can answer part of a query, with the indexes of the conditions it uses
+fn candidates(query: &Query, indexes: &[String]) -> Vec<(Access, Vec<usize>)> {
+    let mut candidates = Vec::new();
+    
+    for field in indexes {
+        let conditions: Vec<(usize, &Condition)> = required_conditions(query)
+            .filter(|(_, condition)| &condition.field == field)
+            .collect();
+        
+        let mut lower: Option<(Value, bool)> = None;
+        let mut upper: Option<(Value, bool)> = None;
+        let mut range_conditions = Vec::new();
+        
+        for (i, condition) in conditions {
+            let value = &condition.value;
+            
+            match condition.operator {
+                ComparisonOperator::Eq => {
+                    let access = Access::IndexLookup { field: field.clone(), values: vec![value.clone()] };
+                    candidates.push((access, vec![i]));
+                }
+                ComparisonOperator::In => {
+                    if let Value::Array(values) = value {
+                        let access = Access::IndexLookup { field: field.clone(), values: values.clone() };
+                        candidates.push((access, vec![i]));
+                    }
+                }
+                ComparisonOperator::StartsWith => {
+                    if let Value::String(prefix) = value {
+                        let access = Access::IndexPrefix { field: field.clone(), prefix: prefix.clone() };
+                        candidates.push((access, vec![i]));
+                    }
+                }
+                ComparisonOperator::Gt | ComparisonOperator::Gte if !value.is_object() => {
+                    let bound = (value.clone(), condition.operator == ComparisonOperator::Gte);
+                    lower = Some(tighter(lower, bound, true));
+                    range_conditions.push(i);
+                }
+                ComparisonOperator::Lt | ComparisonOperator::Lte if !value.is_object() => {
+                    let bound = (value.clone(), condition.operator == ComparisonOperator::Lte);
+                    upper = Some(tighter(upper, bound, false));
+                    range_conditions.push(i);
+                }
+                _ => {}
+            }
+        }
+        
+        if !range_conditions.is_empty() {
+            let access = Access::IndexRange { field: field.clone(), lower, upper };
+            candidates.push((access, range_conditions));
+        }
+    }
+    
+    candidates
+}
+
+/// Get the conditions that every matching document must satisfy.
+///
+/// Conditions are folded left to right, so a condition is required only when it and
+/// every condition after it are joined with AND.
+fn required_conditions(query: &Query) -> impl Iterator<Item = (usize, &Condition)> {
+    let first = query
+        .operators
+        .iter()
+        .rposition(|operator| *operator == LogicalOperator::Or)
+        .map_or(0, |i| i + 2);
+    
+    query.conditions.iter().enumerate().skip(first)
+}
+
+/// Pick the tighter of two range bounds, comparing them by their index encoding
+fn tighter(current: Option<(Value, bool)>, bound: (Value, bool), is_lower: bool) -> (Value, bool) {
+    let Some(current) = current else {
+        return bound;
+    };
+    
+    let mut current_key = Vec::new();
+    let mut bound_key = Vec::new();
+    index::encode_value(&current.0, &mut current_key);
+    index::encode_value(&bound.0, &mut bound_key);
+    
+    match bound_key.cmp(&current_key) {
+        std::cmp::Ordering::Equal => (bound.0, bound.1 && current.1),
+        std::cmp::Ordering::Greater if is_lower => bound,
+        std::cmp::Ordering::Less if !is_lower => bound,
+        _ => current,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    /// Plan a query, estimating every index scan at the same cost
+    fn plan_with(query: &Query, indexes: &[&str]) -> QueryPlan {
+        let indexes: Vec<String> = indexes.iter().map(|field| field.to_string()).collect();
+        plan(1, query, &indexes, |_, _| Ok(1)).unwrap()
+    }
+    
+    #[test]
+    fn queries_without_a_usable_index_scan_the_collection() {
+        let query = Query::new().filter("x", "=", 1).unwrap().filter("y", "!=", 2).unwrap();
+        
+        let plan = plan_with(&query, &[]);
+        assert_eq!(plan.access, Access::CollectionScan);
+        assert_eq!(plan.residual.len(), 2);
+        
+        // Not-equal cannot be answered from an index
+        let plan = plan_with(&Query::new().filter("y", "!=", 2).unwrap(), &["y"]);
+        assert_eq!(plan.access, Access::CollectionScan);
+    }
+    
+    #[test]
+    fn equality_uses_an_index_lookup() {
+        let query = Query::new().filter("x", "=", 1).unwrap().filter("y", "=", 2).unwrap();
+        let plan = plan_with(&query, &["x"]);
+        
+        assert_eq!(plan.access, Access::IndexLookup { field: "x".to_string(), values: vec![json!(1)] });
+        assert_eq!(plan.index_conditions.len(), 1);
+        assert_eq!(plan.residual.len(), 1);
+        assert_eq!(plan.residual[0].field, "y");
+    }
+    
+    #[test]
+    fn range_conditions_on_a_field_are_merged() {
+        let query = Query::new()
+            .filter("x", ">", 1).unwrap()
+            .filter("x", ">=", 5).unwrap()
+            .filter("x", "<=", 10).unwrap()
+            .filter("x", "<", 20).unwrap();
+        
+        let plan = plan_with(&query, &["x"]);
+        
+        let expected = Access::IndexRange {
+            field: "x".to_string(),
+            lower: Some((json!(5), true)),
+            upper: Some((json!(10), true)),
+        };
+        
+        assert_eq!(plan.access, expected);
+        assert!(plan.residual.is_empty());
+    }
+    
+    #[test]
+    fn empty_ranges_scan_nothing() {
+        let access = Access::IndexRange {
+            field: "x".to_string(),
+            lower: Some((json!(5), false)),
+            upper: Some((json!(5), false)),
+        };
+        
+        assert!(access.key_ranges(1).is_empty());
+    }
+    
+    #[test]
+    fn conditions_before_an_or_are_not_required() {
+        let mut query = Query::new().filter("x", "=", 1).unwrap().filter("y", "=", 2).unwrap();
+        query.operators = vec![LogicalOperator::Or];
+        
+        assert_eq!(plan_with(&query, &["x", "y"]).access, Access::CollectionScan);
+        
+        // (x OR y) AND z
+        let mut query = query.filter("z", "=", 3).unwrap();
+        query.operators = vec![LogicalOperator::Or, LogicalOperator::And];
+        
+        assert_eq!(plan_with(&query, &["x", "y"]).access, Access::CollectionScan);
+        assert_eq!(plan_with(&query, &["z"]).access.field(), Some("z"));
+    }
+    
+    #[test]
+    fn the_cheapest_index_wins() {
+        let query = Query::new().filter("x", "=", 1).unwrap().filter("y", "=", 2).unwrap();
+        let indexes = vec!["x".to_string(), "y".to_string()];
+        
+        let y_prefix = index::index_prefix(1, "y");
+        let count = |ranges: &[(Vec<u8>, Vec<u8>)], _| Ok(if ranges[0].0.starts_with(&y_prefix) { 3 } else { 50 });
+        
+        let plan = plan(1, &query, &indexes, count).unwrap();
+        
+        assert_eq!(plan.access.field(), Some("y"));
+        assert_eq!(plan.estimated_documents, 3);
+    }
+    
+    #[test]
+    fn an_index_on_the_sort_field_serves_the_sort() {
+        let query = Query::new().filter("x", ">", 1).unwrap().sort("x", false).limit(5).skip(2);
+        let plan = plan_with(&query, &["x"]);
+        
+        assert!(plan.sort_from_index);
+        assert!(plan.is_reverse(&query));
+        assert_eq!(plan.match_limit(&query), Some(7));
+        
+        let query = Query::new().filter("x", ">", 1).unwrap().sort("y", true).limit(5);
+        
+        assert!(!plan_with(&query, &["x"]).sort_from_index);
+    }
+}
@@ -0,0 +1,517 @@
+use dashmap::DashMap;
+use log::{debug, warn};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::error::{Result, XLimError};
+use crate::protocol::{read_frame, write_frame, Envelope, Request, Response};
+
+/// Settings for a client connection pool
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Number of connections to keep open
+    pub size: usize,
+    
+    /// How often every connection is checked with a ping
+    pub health_check_interval: Duration,
+    
+    /// How long a health check may wait for its ping before the connection is replaced
+    pub health_check_timeout: Duration,
+    
+    /// Credentials every connection logs in with, if the server requires authentication
+    pub credentials: Option<Credentials>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            size: 4,
+            health_check_interval: Duration::from_secs(30),
+            health_check_timeout: Duration::from_secs(5),
+            credentials: None,
+        }
+    }
+}
+
+/// A username and password for logging in to the server
+#[derive(Clone)]
+pub struct Credentials {
+    /// Name of the user
+    pub username: String,
+    
+    /// Password of the user
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<hidden>")
+            .finish()
+    }
+}
+
+/// Requests sent on a connection that are still waiting for their response, by request ID
+type Pending = DashMap<u64, oneshot::Sender<Result<Response>>>;
+
+/// A connection to the server that can have many requests in flight at once.
+///
+/// Responses are matched to requests by request ID, so they may arrive in any order.
+pub struct Connection {
+    /// Write half of the socket
+    writer: Mutex<OwnedWriteHalf>,
+    
+    /// Requests waiting for a response
+    pending: Arc<Pending>,
+    
+    /// Source of request IDs
+    next_request_id: AtomicU64,
+    
+    /// Set once the connection can no longer be used
+    closed: Arc<AtomicBool>,
+    
+    /// Number of unfinished transactions begun on the connection
+    pins: AtomicUsize,
+    
+    /// Task that reads responses and hands them to the waiting requests
+    reader: JoinHandle<()>,
+}
+
+impl Connection {
+    /// Open a connection to the first reachable address, logging in if credentials are given
+    pub async fn open(addrs: &[SocketAddr], credentials: Option<&Credentials>) -> Result<Self> {
+        let stream = TcpStream::connect(addrs).await?;
+        stream.set_nodelay(true)?;
+        
+        let (reader, writer) = stream.into_split();
+        let pending = Arc::new(Pending::new());
+        let closed = Arc::new(AtomicBool::new(false));
+        
+        let reader = tokio::spawn(read_responses(reader, pending.clone(), closed.clone()));
+        
+        let connection = Self {
+            writer: Mutex::new(writer),
+            pending,
+            next_request_id: AtomicU64::new(1),
+            closed,
+            pins: AtomicUsize::new(0),
+            reader,
+        };
+        
+        if let Some(credentials) = credentials {
+            let request = Request::Auth {
+                username: credentials.username.clone(),
+                password: credentials.password.clone(),
+            };
+            
+            match connection.send(request).await? {
+                Response::Ok => {}
+                response => return Err(response.unexpected()),
+            }
+        }
+        
+        Ok(connection)
+    }
+    
+    /// Check whether the connection has failed or been closed
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+    
+    /// Pin the connection for a transaction, so that health checks leave it open until the
+    /// returned pin is released or dropped. The server rolls a transaction back when its
+    /// connection closes.
+    pub fn pin(self: &Arc<Self>) -> TransactionPin {
+        self.pins.fetch_add(1, Ordering::SeqCst);
+        
+        TransactionPin {
+            connection: self.clone(),
+            released: AtomicBool::new(false),
+        }
+    }
+    
+    /// Check whether a transaction is open on the connection
+    pub fn is_pinned(&self) -> bool {
+        self.pins.load(Ordering::SeqCst) > 0
+    }
+    
+    /// Close the connection and fail every request still waiting on it
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.reader.abort();
+        fail_pending(&self.pending);
+    }
+    
+    /// Send a request and wait for its response
+    pub async fn send(&self, request: Request) -> Result<Response> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        
+        self.pending.insert(request_id, sender);
+        
+        // Checked after registering, so that a reader which has just failed has either
+        // already set the flag or will still see this request when it fails the rest
+        if self.is_closed() {
+            self.pending.remove(&request_id);
+            return Err(XLimError::Connection("Connection is closed".to_string()));
+        }
+        
+        let envelope = Envelope {
+            request_id,
+            message: request,
+        };
+        
+        let written = {
+            let mut writer = self.writer.lock().await;
+            write_frame(&mut *writer, &envelope).await
+        };
+        
+        if let Err(e) = written {
+            self.pending.remove(&request_id);
+            self.close();
+            return Err(e);
+        }
+        
+        receiver
+            .await
+            .map_err(|_| XLimError::Connection("Connection closed before a response was received".to_string()))??
+            .into_result()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Keeps a connection pinned while the transaction that holds it is open
+pub struct TransactionPin {
+    /// Pinned connection
+    connection: Arc<Connection>,
+    
+    /// Set once the pin no longer counts
+    released: AtomicBool,
+}
+
+impl TransactionPin {
+    /// Unpin the connection once its transaction has finished, ahead of dropping the pin
+    pub fn release(&self) {
+        if !self.released.swap(true, Ordering::SeqCst) {
+            self.connection.pins.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Drop for TransactionPin {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Read responses until the connection fails, completing the matching pending requests
+async fn read_responses(mut reader: OwnedReadHalf, pending: Arc<Pending>, closed: Arc<AtomicBool>) {
+    loop {
+        match read_frame::<_, Envelope<Response>>(&mut reader).await {
+            Ok(Some(envelope)) => match pending.remove(&envelope.request_id) {
+                Some((_, sender)) => {
+                    let _ = sender.send(Ok(envelope.message));
+                }
+                None => warn!("Received a response to unknown request {}", envelope.request_id),
+            },
+            Ok(None) => {
+                debug!("Connection closed by server");
+                break;
+            }
+            Err(e) => {
+                warn!("Failed to read response: {}", e);
+                break;
+            }
+        }
+    }
+    
+    closed.store(true, Ordering::SeqCst);
+    fail_pending(&pending);
+}
+
+/// Fail every pending request with a connection error
+fn fail_pending(pending: &Pending) {
+    let request_ids: Vec<u64> = pending.iter().map(|entry| *entry.key()).collect();
+    
+    for request_id in request_ids {
+        if let Some((_, sender)) = pending.remove(&request_id) {
+            let _ = sender.send(Err(XLimError::Connection("Connection closed by server".to_string())));
+        }
+    }
+}
+
+/// A fixed-size pool of connections shared by every clone of a client.
+///
+/// Requests are spread over the connections round-robin. A connection that breaks,
+/// or fails a health check, is replaced the next time its slot is used.
+pub struct Pool {
+    /// Server addresses
+    addrs: Vec<SocketAddr>,
+    
+    /// Pool settings
+    config: PoolConfig,
+    
+    /// Connections, or `None` where a broken connection has been removed
+    slots: Vec<Mutex<Option<Arc<Connection>>>>,
+    
+    /// Slot to use for the next request
+    next_slot: AtomicUsize,
+}
+
+impl Pool {
+    /// Open every connection of a new pool and start its health checks
+    pub async fn connect(addrs: Vec<SocketAddr>, config: PoolConfig) -> Result<Arc<Self>> {
+        if config.size == 0 {
+            return Err(XLimError::Configuration("Connection pool size must be at least 1".to_string()));
+        }
+        
+        let mut slots = Vec::with_capacity(config.size);
+        
+        for _ in 0..config.size {
+            let connection = Connection::open(&addrs, config.credentials.as_ref()).await?;
+            slots.push(Mutex::new(Some(Arc::new(connection))));
+        }
+        
+        let pool = Arc::new(Self {
+            addrs,
+            config,
+            slots,
+            next_slot: AtomicUsize::new(0),
+        });
+        
+        tokio::spawn(check_health(Arc::downgrade(&pool)));
+        
+        Ok(pool)
+    }
+    
+    /// Get an open connection, replacing the one in the next slot if it has broken
+    pub async fn get(&self) -> Result<Arc<Connection>> {
+        let index = self.next_slot.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        let mut slot = self.slots[index].lock().await;
+        
+        if let Some(connection) = slot.as_ref().filter(|c| !c.is_closed()) {
+            return Ok(connection.clone());
+        }
+        
+        debug!("Opening a new connection for pool slot {}", index);
+        
+        let connection = Arc::new(Connection::open(&self.addrs, self.config.credentials.as_ref()).await?);
+        *slot = Some(connection.clone());
+        
+        Ok(connection)
+    }
+    
+    /// Send a request on one of the pool's connections
+    pub async fn send(&self, request: Request) -> Result<Response> {
+        self.get().await?.send(request).await
+    }
+}
+
+/// Ping every connection of a pool periodically, removing the ones that do not answer.
+///
+/// Connections with an open transaction are skipped: closing one would make the server roll
+/// the transaction back, and a connection that really has failed is noticed by its reader.
+/// Stops once the pool has been dropped.
+async fn check_health(pool: Weak<Pool>) {
+    let interval = match pool.upgrade() {
+        Some(pool) => pool.config.health_check_interval,
+        None => return,
+    };
+    
+    loop {
+        tokio::time::sleep(interval).await;
+        
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        
+        for (index, slot) in pool.slots.iter().enumerate() {
+            let Some(connection) = slot.lock().await.clone() else {
+                continue;
+            };
+            
+            if connection.is_pinned() {
+                continue;
+            }
+            
+            let ping = tokio::time::timeout(pool.config.health_check_timeout, connection.send(Request::Ping)).await;
+            
+            if matches!(ping, Ok(Ok(Response::Pong))) {
+                continue;
+            }
+            
+            warn!("Connection in pool slot {} failed its health check; replacing it", index);
+            connection.close();
+            
+            let mut slot = slot.lock().await;
+            
+            if slot.as_ref().is_some_and(|current| Arc::ptr_eq(current, &connection)) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use tokio::net::TcpListener;
+    use uuid::Uuid;
+    
+    /// Accept connections on a free port, handling each with `handle`
+    async fn serve<F, Fut>(handle: F) -> Vec<SocketAddr>
+    where
+        F: Fn(TcpStream) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle(stream));
+            }
+        });
+        
+        vec![addr]
+    }
+    
+    /// Answer every request with a pong until the client disconnects
+    async fn answer_pings(mut stream: TcpStream) {
+        while let Ok(Some(envelope)) = read_frame::<_, Envelope<Request>>(&mut stream).await {
+            write_frame(&mut stream, &Envelope { request_id: envelope.request_id, message: Response::Pong }).await.unwrap();
+        }
+    }
+    
+    /// Health check settings that notice a silent connection quickly
+    fn quick_health_checks() -> PoolConfig {
+        PoolConfig {
+            size: 1,
+            health_check_interval: Duration::from_millis(20),
+            health_check_timeout: Duration::from_millis(20),
+            ..PoolConfig::default()
+        }
+    }
+    
+    #[tokio::test]
+    async fn responses_are_matched_to_requests_by_id() {
+        // Read a batch of requests, then answer them in reverse order
+        let addrs = serve(|mut stream| async move {
+            let mut requests = Vec::new();
+            
+            for _ in 0..10 {
+                requests.push(read_frame::<_, Envelope<Request>>(&mut stream).await.unwrap().unwrap());
+            }
+            
+            for envelope in requests.into_iter().rev() {
+                let Request::Get { id, .. } = envelope.message else {
+                    panic!("unexpected request");
+                };
+                
+                let reply = Envelope { request_id: envelope.request_id, message: Response::DocumentId(id.parse().unwrap()) };
+                write_frame(&mut stream, &reply).await.unwrap();
+            }
+        }).await;
+        
+        let connection = Arc::new(Connection::open(&addrs, None).await.unwrap());
+        let mut requests = Vec::new();
+        
+        for _ in 0..10 {
+            let connection = connection.clone();
+            let id = Uuid::new_v4();
+            
+            requests.push(tokio::spawn(async move {
+                let request = Request::Get { collection: "c".to_string(), id: id.to_string() };
+                (id, connection.send(request).await.unwrap())
+            }));
+        }
+        
+        for request in requests {
+            let (id, response) = request.await.unwrap();
+            assert!(matches!(response, Response::DocumentId(returned) if returned == id));
+        }
+    }
+    
+    #[tokio::test]
+    async fn requests_fail_when_the_server_closes_the_connection() {
+        let addrs = serve(|mut stream| async move {
+            let _ = read_frame::<_, Envelope<Request>>(&mut stream).await;
+        }).await;
+        
+        let connection = Connection::open(&addrs, None).await.unwrap();
+        
+        assert!(matches!(connection.send(Request::Ping).await, Err(XLimError::Connection(_))));
+        assert!(connection.is_closed());
+        assert!(matches!(connection.send(Request::Ping).await, Err(XLimError::Connection(_))));
+    }
+    
+    #[tokio::test]
+    async fn broken_connections_are_replaced() {
+        let addrs = serve(answer_pings).await;
+        let pool = Pool::connect(addrs, PoolConfig { size: 1, ..PoolConfig::default() }).await.unwrap();
+        
+        let first = pool.get().await.unwrap();
+        first.close();
+        
+        let second = pool.get().await.unwrap();
+        
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(matches!(pool.send(Request::Ping).await, Ok(Response::Pong)));
+    }
+    
+    #[tokio::test]
+    async fn health_checks_close_silent_connections() {
+        // Never answer
+        let addrs = serve(|mut stream| async move {
+            while let Ok(Some(_)) = read_frame::<_, Envelope<Request>>(&mut stream).await {}
+        }).await;
+        
+        let pool = Pool::connect(addrs, quick_health_checks()).await.unwrap();
+        let connection = pool.get().await.unwrap();
+        
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        
+        assert!(connection.is_closed());
+    }
+    
+    #[tokio::test]
+    async fn health_checks_leave_pinned_connections_open() {
+        let addrs = serve(|mut stream| async move {
+            while let Ok(Some(_)) = read_frame::<_, Envelope<Request>>(&mut stream).await {}
+        }).await;
+        
+        let pool = Pool::connect(addrs, quick_health_checks()).await.unwrap();
+        let connection = pool.get().await.unwrap();
+        
+        let pin = connection.pin();
+        let other = connection.pin();
+        drop(other);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        
+        assert!(!connection.is_closed());
+        
+        // Releasing twice only unpins once
+        pin.release();
+        pin.release();
+        
+        assert!(!connection.is_pinned());
+        
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        
+        assert!(connection.is_closed());
+    }
+}
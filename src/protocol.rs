@@ -0,0 +1,347 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::auth::Privilege;
+use crate::backup::BackupInfo;
+use crate::cursor::CursorBatch;
+use crate::document::Document;
+use crate::error::{Result, XLimError};
+use crate::lock::LockMode;
+use crate::planner::QueryPlan;
+use crate::query::Query;
+use crate::transaction::{TransactionInfo, TransactionOptions};
+use crate::transfer::{BulkWriteResult, OnConflict};
+use crate::ttl::TtlPolicy;
+
+/// Maximum size of a single frame body in bytes
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// A request sent from a client to the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    /// Check that the server is alive
+    Ping,
+    /// Log in; when authentication is enabled this must succeed before any other request
+    Auth { username: String, password: String },
+    /// Create a user account with roles
+    CreateUser { username: String, password: String, roles: Vec<String> },
+    /// Delete a user account
+    DropUser { username: String },
+    /// Replace a user's password
+    ChangePassword { username: String, password: String },
+    /// Grant roles to a user
+    GrantRoles { username: String, roles: Vec<String> },
+    /// Revoke roles from a user
+    RevokeRoles { username: String, roles: Vec<String> },
+    /// Create a custom role
+    CreateRole { name: String, privileges: Vec<Privilege> },
+    /// Delete a custom role
+    DropRole { name: String },
+    /// Create a collection
+    CreateCollection { name: String },
+    /// Drop a collection and all of its documents
+    DropCollection { name: String },
+    /// Insert a document into a collection
+    Insert { collection: String, document: Document },
+    /// Insert a batch of documents in a single atomic write
+    InsertMany { collection: String, documents: Vec<Document>, on_conflict: OnConflict },
+    /// Get a document by ID
+    Get { collection: String, id: String },
+    /// Replace an existing document
+    Update { collection: String, document: Document },
+    /// Delete a document by ID
+    Delete { collection: String, id: String },
+    /// Find the documents in a collection that match a query
+    Query { collection: String, query: Query },
+    /// Run a query and describe the plan used to answer it
+    Explain { collection: String, query: Query },
+    /// Create a secondary index on a field of a collection
+    CreateIndex { collection: String, field: String },
+    /// Drop a secondary index
+    DropIndex { collection: String, field: String },
+    /// Set the TTL policy of a collection, or remove it with `None`
+    SetTtl { collection: String, policy: Option<TtlPolicy> },
+    /// Begin a transaction
+    Begin { options: TransactionOptions },
+    /// Commit a transaction
+    Commit { transaction_id: Uuid },
+    /// Rollback a transaction
+    Rollback { transaction_id: Uuid },
+    /// Set a savepoint in a transaction
+    Savepoint { transaction_id: Uuid, name: String },
+    /// Undo the operations of a transaction since a savepoint
+    RollbackToSavepoint { transaction_id: Uuid, name: String },
+    /// Remove a savepoint from a transaction
+    ReleaseSavepoint { transaction_id: Uuid, name: String },
+    /// Insert a document as part of a transaction
+    TransactionInsert { transaction_id: Uuid, collection: String, document: Document },
+    /// Update a document as part of a transaction
+    TransactionUpdate { transaction_id: Uuid, collection: String, document: Document },
+    /// Delete a document as part of a transaction
+    TransactionDelete { transaction_id: Uuid, collection: String, id: String },
+    /// Get a document as a transaction sees it
+    TransactionGet { transaction_id: Uuid, collection: String, id: String },
+    /// Find the documents that match a query as a transaction sees them
+    TransactionQuery { transaction_id: Uuid, collection: String, query: Query },
+    /// Lock a document until a transaction ends
+    Lock { transaction_id: Uuid, collection: String, id: String, mode: LockMode },
+    /// List the active transactions
+    ListTransactions,
+    /// Open a cursor over a collection and read its first batch of documents
+    OpenCursor { collection: String, batch_size: usize },
+    /// Read the next batch of documents from a cursor
+    GetMore { cursor_id: Uuid, batch_size: usize },
+    /// Close a cursor before it has been read to the end
+    CloseCursor { cursor_id: Uuid },
+    /// Back up the database into a directory on the server
+    Backup { dir: PathBuf },
+}
+
+/// A response sent from the server to a client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    /// Reply to a ping
+    Pong,
+    /// The request succeeded and has nothing to return
+    Ok,
+    /// ID of an inserted document
+    DocumentId(Uuid),
+    /// A single document
+    Document(Document),
+    /// A list of documents
+    Documents(Vec<Document>),
+    /// The plan used to answer a query
+    Plan(QueryPlan),
+    /// ID of a new transaction
+    TransactionId(Uuid),
+    /// Summaries of the active transactions
+    Transactions(Vec<TransactionInfo>),
+    /// A batch of documents read through a cursor
+    Cursor(CursorBatch),
+    /// Outcome of a bulk insert
+    BulkWrite(BulkWriteResult),
+    /// A backup that was just made
+    Backup(BackupInfo),
+    /// The request failed
+    Error { code: ErrorCode, message: String },
+}
+
+/// Kind of a failed request, so that clients can rebuild the matching `XLimError`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// A document does not exist
+    DocumentNotFound,
+    /// A collection does not exist
+    CollectionNotFound,
+    /// A query is invalid
+    Query,
+    /// A transaction failed
+    Transaction,
+    /// A transaction conflicts with another one and may succeed if retried
+    Conflict,
+    /// The client is not logged in or not allowed to run the request
+    Authentication,
+    /// The request is not valid in the current state
+    InvalidOperation,
+    /// The request took too long
+    Timeout,
+    /// Any other server-side failure
+    Database,
+}
+
+impl Response {
+    /// Build the response for a failed request
+    pub fn error(error: XLimError) -> Self {
+        let (code, message) = match error {
+            XLimError::DocumentNotFound(message) => (ErrorCode::DocumentNotFound, message),
+            XLimError::CollectionNotFound(message) => (ErrorCode::CollectionNotFound, message),
+            XLimError::Query(message) => (ErrorCode::Query, message),
+            XLimError::Transaction(message) => (ErrorCode::Transaction, message),
+            XLimError::Conflict(message) => (ErrorCode::Conflict, message),
+            XLimError::Authentication(message) => (ErrorCode::Authentication, message),
+            XLimError::InvalidOperation(message) => (ErrorCode::InvalidOperation, message),
+            XLimError::Timeout(message) => (ErrorCode::Timeout, message),
+            other => (ErrorCode::Database, other.to_string()),
+        };
+        
+        Self::Error { code, message }
+    }
+    
+    /// Turn an error response into an `Err`, passing every other response through
+    pub fn into_result(self) -> Result<Self> {
+        let Self::Error { code, message } = self else {
+            return Ok(self);
+        };
+        
+        Err(match code {
+            ErrorCode::DocumentNotFound => XLimError::DocumentNotFound(message),
+            ErrorCode::CollectionNotFound => XLimError::CollectionNotFound(message),
+            ErrorCode::Query => XLimError::Query(message),
+            ErrorCode::Transaction => XLimError::Transaction(message),
+            ErrorCode::Conflict => XLimError::Conflict(message),
+            ErrorCode::Authentication => XLimError::Authentication(message),
+            ErrorCode::InvalidOperation => XLimError::InvalidOperation(message),
+            ErrorCode::Timeout => XLimError::Timeout(message),
+            ErrorCode::Database => XLimError::Database(message),
+        })
+    }
+    
+    /// Build the error returned when a response does not match its request
+    pub fn unexpected(self) -> XLimError {
+        XLimError::Connection(format!("Unexpected response from server: {:?}", self))
+    }
+}
+
+/// Envelope carried by every frame, pairing a message with its request ID
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    /// ID chosen by the client and echoed back by the server
+    pub request_id: u64,
+    
+    /// The request or response
+    pub message: T,
+}
+
+/// Write a message as a single frame: a big-endian `u32` length followed by the JSON body.
+///
+/// JSON is used rather than bincode because documents hold `serde_json::Value`s,
+/// which bincode cannot deserialize.
+pub async fn write_frame<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let body = serde_json::to_vec(message)?;
+    
+    if body.len() > MAX_FRAME_SIZE {
+        return Err(XLimError::InvalidOperation(format!(
+            "Frame of {} bytes exceeds the maximum of {} bytes",
+            body.len(),
+            MAX_FRAME_SIZE
+        )));
+    }
+    
+    writer.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await?;
+    
+    Ok(())
+}
+
+/// Read a single frame, returning `None` if the peer closed the connection cleanly
+pub async fn read_frame<R, T>(reader: &mut R) -> Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut header = [0; 4];
+    
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    
+    let length = u32::from_be_bytes(header) as usize;
+    
+    if length > MAX_FRAME_SIZE {
+        return Err(XLimError::Connection(format!(
+            "Frame of {} bytes exceeds the maximum of {} bytes",
+            length, MAX_FRAME_SIZE
+        )));
+    }
+    
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    
+    let message = serde_json::from_slice(&body)?;
+    
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Write messages into a buffer, one frame each
+    async fn frames<T: Serialize>(messages: &[T]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        
+        for message in messages {
+            write_frame(&mut buffer, message).await.unwrap();
+        }
+        
+        buffer
+    }
+    
+    #[tokio::test]
+    async fn frames_round_trip_in_order() {
+        // Larger than the single 4096-byte read the text protocol relied on
+        let document = Document::new().set("big", "x".repeat(100_000));
+        
+        let requests = vec![
+            Envelope { request_id: 1, message: Request::Ping },
+            Envelope { request_id: 2, message: Request::Insert { collection: "c".to_string(), document: document.clone() } },
+        ];
+        
+        let buffer = frames(&requests).await;
+        let mut reader = buffer.as_slice();
+        
+        let first: Envelope<Request> = read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!(first.request_id, 1);
+        assert!(matches!(first.message, Request::Ping));
+        
+        let second: Envelope<Request> = read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!(second.request_id, 2);
+        assert!(matches!(second.message, Request::Insert { document: read, .. } if read.data == document.data));
+        
+        assert!(read_frame::<_, Envelope<Request>>(&mut reader).await.unwrap().is_none());
+    }
+    
+    #[tokio::test]
+    async fn oversized_frames_are_rejected() {
+        let mut buffer = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes().to_vec();
+        buffer.extend_from_slice(b"{}");
+        
+        let result = read_frame::<_, Envelope<Request>>(&mut buffer.as_slice()).await;
+        assert!(matches!(result, Err(XLimError::Connection(_))));
+    }
+    
+    #[tokio::test]
+    async fn truncated_frames_are_errors() {
+        let buffer = frames(&[Envelope { request_id: 1, message: Request::Ping }]).await;
+        
+        // A connection closed in the middle of a frame is not a clean close
+        let result = read_frame::<_, Envelope<Request>>(&mut &buffer[..buffer.len() - 1]).await;
+        assert!(matches!(result, Err(XLimError::Io(_))));
+    }
+    
+    #[test]
+    fn errors_keep_their_kind_across_the_wire() {
+        let errors = [
+            XLimError::DocumentNotFound("d".to_string()),
+            XLimError::CollectionNotFound("c".to_string()),
+            XLimError::Query("q".to_string()),
+            XLimError::Transaction("t".to_string()),
+            XLimError::Conflict("c".to_string()),
+            XLimError::Authentication("a".to_string()),
+            XLimError::InvalidOperation("i".to_string()),
+            XLimError::Timeout("t".to_string()),
+        ];
+        
+        for error in errors {
+            let expected = error.to_string();
+            let conflict = error.is_conflict();
+            let returned = Response::error(error).into_result().unwrap_err();
+            
+            assert_eq!(returned.to_string(), expected);
+            assert_eq!(returned.is_conflict(), conflict);
+        }
+        
+        let returned = Response::error(XLimError::Storage("s".to_string())).into_result().unwrap_err();
+        assert!(matches!(returned, XLimError::Database(_)));
+    }
+}
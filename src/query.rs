@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::str::FromStr;
 
 use crate::document::Document;
 use crate::error::{Result, XLimError};
@@ -33,9 +33,11 @@ pub enum ComparisonOperator {
     NotIn,
 }
 
-impl ComparisonOperator {
+impl FromStr for ComparisonOperator {
+    type Err = XLimError;
+    
     /// Parse a comparison operator from a string
-    pub fn from_str(s: &str) -> Result<Self> {
+    fn from_str(s: &str) -> Result<Self> {
         match s {
             "=" | "==" | "eq" => Ok(Self::Eq),
             "!=" | "<>" | "ne" => Ok(Self::Ne),
@@ -51,7 +53,9 @@ impl ComparisonOperator {
             _ => Err(XLimError::Query(format!("Invalid comparison operator: {}", s))),
         }
     }
-    
+}
+
+impl ComparisonOperator {
     /// Apply the comparison operator to two values
     pub fn apply(&self, left: &Value, right: &Value) -> Result<bool> {
         match self {
@@ -88,16 +92,20 @@ pub enum LogicalOperator {
     Or,
 }
 
-impl LogicalOperator {
+impl FromStr for LogicalOperator {
+    type Err = XLimError;
+    
     /// Parse a logical operator from a string
-    pub fn from_str(s: &str) -> Result<Self> {
+    fn from_str(s: &str) -> Result<Self> {
         match s.to_lowercase().as_str() {
             "and" | "&&" => Ok(Self::And),
             "or" | "||" => Ok(Self::Or),
             _ => Err(XLimError::Query(format!("Invalid logical operator: {}", s))),
         }
     }
-    
+}
+
+impl LogicalOperator {
     /// Apply the logical operator to two boolean values
     pub fn apply(&self, left: bool, right: bool) -> bool {
         match self {
@@ -232,6 +240,22 @@ impl Query {
         self
     }
     
+    /// Check that the query is well formed, with one logical operator between each pair of
+    /// conditions. Queries built with [`Query::filter`] always are, but ones received from
+    /// clients may not be.
+    pub fn validate(&self) -> Result<()> {
+        let expected = self.conditions.len().saturating_sub(1);
+        
+        if self.operators.len() != expected {
+            return Err(XLimError::Query(format!(
+                "Query has {} conditions and {} logical operators, but needs {}",
+                self.conditions.len(), self.operators.len(), expected,
+            )));
+        }
+        
+        Ok(())
+    }
+    
     /// Check if a document matches the query
     pub fn matches(&self, document: &Document) -> Result<bool> {
         if self.conditions.is_empty() {
@@ -242,7 +266,8 @@ impl Query {
         
         for i in 1..self.conditions.len() {
             let condition_result = self.conditions[i].matches(document)?;
-            let operator = &self.operators[i - 1];
+            let operator = self.operators.get(i - 1)
+                .ok_or_else(|| XLimError::Query(format!("Missing logical operator before condition {}", i + 1)))?;
             
             result = operator.apply(result, condition_result);
         }
@@ -253,11 +278,26 @@ impl Query {
     /// Apply the query to a list of documents
     pub fn apply(&self, documents: Vec<Document>) -> Result<Vec<Document>> {
         // Filter documents
-        let mut results: Vec<Document> = documents
+        let results: Vec<Document> = documents
             .into_iter()
             .filter(|doc| self.matches(doc).unwrap_or(false))
             .collect();
         
+        self.finish(results)
+    }
+    
+    /// Number of matching documents that must be collected before sorting and paging,
+    /// or `None` if every match is needed
+    pub fn match_limit(&self) -> Option<usize> {
+        if !self.sort.is_empty() {
+            return None;
+        }
+        
+        self.limit.map(|limit| self.skip.unwrap_or(0).saturating_add(limit))
+    }
+    
+    /// Sort, skip, limit and project documents that already matched the query
+    pub fn finish(&self, mut results: Vec<Document>) -> Result<Vec<Document>> {
         // Sort documents
         if !self.sort.is_empty() {
             results.sort_by(|a, b| {
@@ -300,6 +340,7 @@ impl Query {
                     new_doc.id = doc.id;
                     new_doc.created_at = doc.created_at;
                     new_doc.updated_at = doc.updated_at;
+                    new_doc.expires_at = doc.expires_at;
                     
                     for field in projection {
                         if let Some(value) = doc.get(field) {
@@ -316,6 +357,12 @@ impl Query {
     }
 }
 
+impl Default for Query {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// A query builder for creating queries
 pub struct QueryBuilder {
     query: Query,
@@ -331,37 +378,37 @@ impl QueryBuilder {
     
     /// Add a filter condition to the query
     pub fn filter<T: Into<Value>>(&mut self, field: &str, operator: &str, value: T) -> Result<&mut Self> {
-        self.query = self.query.filter(field, operator, value)?;
+        self.query = self.query.clone().filter(field, operator, value)?;
         Ok(self)
     }
     
     /// Add a logical operator to the query
     pub fn logical_operator(&mut self, operator: &str) -> Result<&mut Self> {
-        self.query = self.query.logical_operator(operator)?;
+        self.query = self.query.clone().logical_operator(operator)?;
         Ok(self)
     }
     
     /// Add a sort field to the query
     pub fn sort(&mut self, field: &str, ascending: bool) -> &mut Self {
-        self.query = self.query.sort(field, ascending);
+        self.query = self.query.clone().sort(field, ascending);
         self
     }
     
     /// Set the maximum number of results to return
     pub fn limit(&mut self, limit: usize) -> &mut Self {
-        self.query = self.query.limit(limit);
+        self.query = self.query.clone().limit(limit);
         self
     }
     
     /// Set the number of results to skip
     pub fn skip(&mut self, skip: usize) -> &mut Self {
-        self.query = self.query.skip(skip);
+        self.query = self.query.clone().skip(skip);
         self
     }
     
     /// Set the fields to include in the results
     pub fn project(&mut self, fields: Vec<&str>) -> &mut Self {
-        self.query = self.query.project(fields);
+        self.query = self.query.clone().project(fields);
         self
     }
     
@@ -371,6 +418,12 @@ impl QueryBuilder {
     }
 }
 
+impl Default for QueryBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // Helper functions for comparison operations
 
 fn compare_values(left: &Value, right: &Value, expected: Ordering) -> Result<bool> {
@@ -474,4 +527,41 @@ fn apply_in(left: &Value, right: &Value) -> Result<bool> {
         Value::Array(arr) => Ok(arr.contains(left)),
         _ => Err(XLimError::Query("In operator requires an array as the right operand".to_string())),
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn built_queries_are_valid() {
+        let query = Query::new()
+            .filter("age", ">", 30).unwrap()
+            .filter("name", "=", "Jane").unwrap();
+        
+        assert!(query.validate().is_ok());
+        assert!(Query::new().validate().is_ok());
+    }
+    
+    #[test]
+    fn missing_operators_are_rejected() {
+        let mut query = Query::new()
+            .filter("age", ">", 30).unwrap()
+            .filter("name", "=", "Jane").unwrap();
+        query.operators.clear();
+        
+        assert!(matches!(query.validate(), Err(XLimError::Query(_))));
+        
+        let document = Document::new().set("age", 40).set("name", "Jane");
+        
+        assert!(matches!(query.matches(&document), Err(XLimError::Query(_))));
+    }
+    
+    #[test]
+    fn extra_operators_are_rejected() {
+        let mut query = Query::new().filter("age", ">", 30).unwrap();
+        query.operators.push(LogicalOperator::And);
+        
+        assert!(matches!(query.validate(), Err(XLimError::Query(_))));
+    }
+}
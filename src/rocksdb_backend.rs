@@ -0,0 +1,167 @@
+use rocksdb::{BlockBasedOptions, Cache, ColumnFamily, ColumnFamilyDescriptor, Options, ReadOptions, SliceTransform, WriteOptions, DB};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::backend::{BatchOperation, Direction, KeyValueIter, Keyspace, Snapshot, StorageBackend, WriteBatch};
+use crate::backup::{self, BackupInfo};
+use crate::error::{Result, XLimError};
+use crate::storage::COLLECTION_ID_LENGTH;
+
+/// A RocksDB snapshot, kept with the database it belongs to
+struct RocksDbSnapshot {
+    /// RocksDB snapshot, which really borrows from `_db`
+    inner: rocksdb::Snapshot<'static>,
+    
+    /// Keeps the database open for as long as the snapshot exists
+    _db: Arc<DB>,
+}
+
+/// Backend that stores each keyspace in its own RocksDB column family
+pub struct RocksDbBackend {
+    /// RocksDB instance
+    db: Arc<DB>,
+}
+
+impl RocksDbBackend {
+    /// Open the database at a path, creating it if it does not exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        // Create RocksDB options
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        options.set_keep_log_file_num(10);
+        options.set_max_open_files(1000);
+        options.set_use_fsync(false);
+        options.set_bytes_per_sync(8388608); // 8MB
+        options.optimize_for_point_lookup(64 * 1024 * 1024); // 64MB
+        options.set_table_cache_num_shard_bits(6);
+        options.set_max_write_buffer_number(6);
+        options.set_write_buffer_size(128 * 1024 * 1024); // 128MB
+        options.set_target_file_size_base(64 * 1024 * 1024); // 64MB
+        options.set_min_write_buffer_number_to_merge(2);
+        options.set_level_zero_stop_writes_trigger(36);
+        options.set_level_zero_slowdown_writes_trigger(28);
+        options.set_compaction_style(rocksdb::DBCompactionStyle::Level);
+        options.set_max_background_jobs(6);
+        
+        // Define column families
+        let cf_names = ["default", "collections", "documents", "indexes", "metadata"];
+        let cf_descriptors: Vec<ColumnFamilyDescriptor> = cf_names
+            .iter()
+            .map(|name| match *name {
+                "documents" => ColumnFamilyDescriptor::new(*name, document_options(&options)),
+                _ => ColumnFamilyDescriptor::new(*name, options.clone()),
+            })
+            .collect();
+        
+        // Open the database
+        let db = DB::open_cf_descriptors(&options, path, cf_descriptors)
+            .map_err(|e| XLimError::Storage(format!("Failed to open database: {}", e)))?;
+        
+        Ok(Self {
+            db: Arc::new(db),
+        })
+    }
+    
+    /// Get the column family of a keyspace
+    fn cf(&self, keyspace: Keyspace) -> Result<&ColumnFamily> {
+        self.db.cf_handle(keyspace.name())
+            .ok_or_else(|| XLimError::Storage(format!("Column family {} not found", keyspace.name())))
+    }
+}
+
+impl StorageBackend for RocksDbBackend {
+    fn get(&self, keyspace: Keyspace, key: &[u8], snapshot: Option<&Snapshot>) -> Result<Option<Vec<u8>>> {
+        self.db.get_cf_opt(self.cf(keyspace)?, key, &read_options(snapshot)?)
+            .map_err(|e| XLimError::Storage(format!("Failed to read from {}: {}", keyspace.name(), e)))
+    }
+    
+    fn write(&self, batch: WriteBatch, sync: bool) -> Result<()> {
+        let mut rocks_batch = rocksdb::WriteBatch::default();
+        
+        for operation in batch.into_operations() {
+            match operation {
+                BatchOperation::Put(keyspace, key, value) => rocks_batch.put_cf(self.cf(keyspace)?, key, value),
+                BatchOperation::Delete(keyspace, key) => rocks_batch.delete_cf(self.cf(keyspace)?, key),
+            }
+        }
+        
+        let mut write_options = WriteOptions::default();
+        write_options.set_sync(sync);
+        
+        self.db.write_opt(rocks_batch, &write_options)
+            .map_err(|e| XLimError::Storage(format!("Failed to write batch: {}", e)))
+    }
+    
+    fn scan<'a>(&'a self, keyspace: Keyspace, prefix: &[u8], start: &[u8], direction: Direction, snapshot: Option<&'a Snapshot>) -> Result<KeyValueIter<'a>> {
+        let mut options = read_options(snapshot)?;
+        
+        // Scans within one collection's documents can use the prefix bloom filters; any other
+        // scan of the documents, such as one over keys older than the prefix extractor, can't
+        if keyspace == Keyspace::Documents && prefix.len() >= COLLECTION_ID_LENGTH {
+            options.set_prefix_same_as_start(true);
+        } else {
+            options.set_total_order_seek(true);
+        }
+        
+        let mode = match direction {
+            Direction::Forward => rocksdb::IteratorMode::From(start, rocksdb::Direction::Forward),
+            Direction::Reverse => rocksdb::IteratorMode::From(start, rocksdb::Direction::Reverse),
+        };
+        
+        let prefix = prefix.to_vec();
+        
+        let iter = self.db.iterator_cf_opt(self.cf(keyspace)?, options, mode)
+            .map(move |item| item.map_err(|e| XLimError::Storage(format!("Failed to read from {}: {}", keyspace.name(), e))))
+            .take_while(move |item| item.as_ref().map_or(true, |(key, _)| key.starts_with(&prefix)));
+        
+        Ok(Box::new(iter))
+    }
+    
+    fn snapshot(&self) -> Snapshot {
+        let inner = self.db.snapshot();
+        
+        // SAFETY: the snapshot borrows the `DB` inside the `Arc`, which does not move and is
+        // kept alive by `_db`. Fields are dropped in declaration order, so the snapshot is
+        // released before the reference to the database.
+        let inner = unsafe { std::mem::transmute::<rocksdb::Snapshot<'_>, rocksdb::Snapshot<'static>>(inner) };
+        
+        Snapshot::new(RocksDbSnapshot {
+            inner,
+            _db: self.db.clone(),
+        })
+    }
+    
+    fn create_backup(&self, dir: &Path) -> Result<BackupInfo> {
+        backup::create_backup(&self.db, dir)
+    }
+}
+
+/// Options for the documents column family.
+///
+/// Document keys start with their collection's ID, which the prefix extractor hands to the
+/// bloom filters, so a scan of one collection skips the files and blocks that hold none of
+/// its documents. Point lookups still use whole-key filters.
+fn document_options(options: &Options) -> Options {
+    let mut table_options = BlockBasedOptions::default();
+    table_options.set_bloom_filter(10.0, false);
+    table_options.set_whole_key_filtering(true);
+    table_options.set_block_cache(&Cache::new_lru_cache(64 * 1024 * 1024)); // 64MB
+    
+    let mut options = options.clone();
+    options.set_prefix_extractor(SliceTransform::create_fixed_prefix(COLLECTION_ID_LENGTH));
+    options.set_memtable_prefix_bloom_ratio(0.1);
+    options.set_block_based_table_factory(&table_options);
+    options
+}
+
+/// Options for reading from a snapshot, or from the latest data if there is none
+fn read_options(snapshot: Option<&Snapshot>) -> Result<ReadOptions> {
+    let mut options = ReadOptions::default();
+    
+    if let Some(snapshot) = snapshot {
+        options.set_snapshot(&snapshot.downcast::<RocksDbSnapshot>()?.inner);
+    }
+    
+    Ok(options)
+}
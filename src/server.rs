@@ -0,0 +1,701 @@
+use log::{debug, error, info};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, Semaphore};
+
+use crate::auth::{Action, Role, UserStore};
+use crate::config::{BackendKind, Config};
+use crate::cursor::{reap_cursors, CursorManager};
+use crate::error::{Result, XLimError};
+use crate::lock::LockMode;
+use crate::protocol::{read_frame, write_frame, Envelope, Request, Response};
+use crate::storage::StorageEngine;
+use crate::transaction::{reap_transactions, TransactionManager};
+use crate::transfer::OnConflict;
+use crate::ttl::reap_expired;
+
+/// Maximum number of requests from one connection that are executed at the same time
+const MAX_IN_FLIGHT: usize = 64;
+
+/// The XLim database server
+pub struct Server {
+    /// Server configuration
+    config: Config,
+    
+    /// Storage engine
+    storage: Arc<StorageEngine>,
+    
+    /// Transaction manager
+    transactions: Arc<TransactionManager>,
+    
+    /// Open cursors
+    cursors: Arc<CursorManager>,
+    
+    /// User accounts
+    users: Arc<UserStore>,
+}
+
+impl Server {
+    /// Create a new server, opening the configured storage backend
+    pub fn new(config: Config) -> Result<Self> {
+        let storage = Arc::new(match config.backend {
+            BackendKind::RocksDb => StorageEngine::new(config.db_path())?,
+            BackendKind::Memory => StorageEngine::in_memory()?,
+        });
+        let transactions = Arc::new(TransactionManager::new(storage.clone(), config.sync_commits, config.transaction_timeout(), config.lock_timeout()));
+        let cursors = Arc::new(CursorManager::new(storage.clone(), config.cursor_timeout()));
+        let users = Arc::new(UserStore::new(storage.clone()));
+        
+        Ok(Self {
+            config,
+            storage,
+            transactions,
+            cursors,
+            users,
+        })
+    }
+    
+    /// Accept client connections until the process is interrupted
+    pub async fn start(&self) -> Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", self.config.port)).await?;
+        self.run(listener).await
+    }
+    
+    /// Serve the connections accepted by a listener until the process is interrupted
+    async fn run(&self, listener: TcpListener) -> Result<()> {
+        let connection_slots = Arc::new(Semaphore::new(self.config.max_connections));
+        
+        info!("XLim server listening on {}", listener.local_addr()?);
+        
+        if self.config.auth_enabled {
+            info!("Authentication is required");
+        }
+        
+        tokio::spawn(reap_transactions(Arc::downgrade(&self.transactions)));
+        tokio::spawn(reap_cursors(Arc::downgrade(&self.cursors)));
+        tokio::spawn(reap_expired(Arc::downgrade(&self.storage), self.config.ttl_interval()));
+        
+        let mut next_connection_id = 0;
+        
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, addr) = accepted?;
+                    
+                    let permit = match connection_slots.clone().try_acquire_owned() {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            error!("Rejecting connection from {}: too many connections", addr);
+                            continue;
+                        }
+                    };
+                    
+                    next_connection_id += 1;
+                    
+                    let handler = CommandHandler {
+                        storage: self.storage.clone(),
+                        transactions: self.transactions.clone(),
+                        cursors: self.cursors.clone(),
+                        users: self.users.clone(),
+                        auth_enabled: self.config.auth_enabled,
+                        connection_id: next_connection_id,
+                    };
+                    
+                    tokio::spawn(async move {
+                        if let Err(e) = handler.serve(stream, addr).await {
+                            error!("Connection {} failed: {}", addr, e);
+                        }
+                        
+                        // Transactions the client left open can never be finished now
+                        let rolled_back = handler.transactions.rollback_owned_by(handler.connection_id);
+                        
+                        if rolled_back > 0 {
+                            info!("Rolled back {} transactions left open by {}", rolled_back, addr);
+                        }
+                        
+                        handler.cursors.close_owned_by(handler.connection_id);
+                        
+                        drop(permit);
+                    });
+                }
+                _ = tokio::signal::ctrl_c() => {
+                    info!("Shutting down XLim server");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Executes client requests against the storage engine and transaction manager
+#[derive(Clone)]
+struct CommandHandler {
+    /// Storage engine
+    storage: Arc<StorageEngine>,
+    
+    /// Transaction manager
+    transactions: Arc<TransactionManager>,
+    
+    /// Open cursors
+    cursors: Arc<CursorManager>,
+    
+    /// User accounts
+    users: Arc<UserStore>,
+    
+    /// Whether requests other than `Auth` are refused until the client logs in
+    auth_enabled: bool,
+    
+    /// Identifies the connection, so that its transactions can be rolled back and its cursors
+    /// closed when it closes
+    connection_id: u64,
+}
+
+impl CommandHandler {
+    /// Serve requests from a single client until it disconnects.
+    ///
+    /// Requests are executed concurrently and each response is written as soon as it is ready,
+    /// so clients match responses to requests by request ID.
+    async fn serve(&self, stream: TcpStream, addr: SocketAddr) -> Result<()> {
+        debug!("Accepted connection from {}", addr);
+        
+        let (mut reader, writer) = stream.into_split();
+        let writer = Arc::new(Mutex::new(writer));
+        let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+        
+        let served = self.read_requests(&mut reader, &writer, &in_flight, addr).await;
+        
+        // The connection's transactions and cursors are cleaned up once this returns, so
+        // requests that are still running must finish first
+        let _ = in_flight.acquire_many(MAX_IN_FLIGHT as u32).await;
+        
+        served
+    }
+    
+    /// Read requests until the client disconnects, spawning a task for each one that holds
+    /// an `in_flight` permit until its response is sent
+    async fn read_requests(&self, reader: &mut OwnedReadHalf, writer: &Arc<Mutex<OwnedWriteHalf>>, in_flight: &Arc<Semaphore>, addr: SocketAddr) -> Result<()> {
+        // Name of the user this connection is logged in as
+        let mut user: Option<String> = None;
+        
+        while let Some(envelope) = read_frame::<_, Envelope<Request>>(reader).await? {
+            // Logging in is handled in order, so that requests sent after it see the result
+            if let Request::Auth { username, password } = envelope.message {
+                let response = match self.authenticate(username, password).await {
+                    Ok(username) => {
+                        user = Some(username);
+                        Response::Ok
+                    }
+                    Err(e) => {
+                        user = None;
+                        Response::error(e)
+                    }
+                };
+                
+                send_response(writer, envelope.request_id, response, addr).await;
+                continue;
+            }
+            
+            if self.auth_enabled && user.is_none() {
+                let error = XLimError::Authentication("Authentication required".to_string());
+                send_response(writer, envelope.request_id, Response::error(error), addr).await;
+                continue;
+            }
+            
+            let permit = in_flight.clone().acquire_owned().await
+                .map_err(|e| XLimError::Unknown(format!("Request limiter closed: {}", e)))?;
+            
+            let handler = self.clone();
+            let writer = writer.clone();
+            let user = user.clone();
+            
+            tokio::spawn(async move {
+                // Storage calls block, so they run on the blocking thread pool
+                let response = match tokio::task::spawn_blocking(move || handler.execute(user.as_deref(), envelope.message)).await {
+                    Ok(Ok(response)) => response,
+                    Ok(Err(e)) => Response::error(e),
+                    Err(e) => Response::error(XLimError::Unknown(format!("Request failed: {}", e))),
+                };
+                
+                send_response(&writer, envelope.request_id, response, addr).await;
+                drop(permit);
+            });
+        }
+        
+        debug!("Connection closed by {}", addr);
+        
+        Ok(())
+    }
+    
+    /// Check a client's credentials, returning the name it is logged in as
+    async fn authenticate(&self, username: String, password: String) -> Result<String> {
+        if !self.auth_enabled {
+            return Ok(username);
+        }
+        
+        // Password hashing is deliberately slow, so it runs on the blocking thread pool
+        let users = self.users.clone();
+        
+        let user = tokio::task::spawn_blocking(move || users.authenticate(&username, &password))
+            .await
+            .map_err(|e| XLimError::Unknown(format!("Authentication failed: {}", e)))??;
+        
+        debug!("Authenticated user {}", user.username);
+        
+        Ok(user.username)
+    }
+    
+    /// Check that the logged-in user may run a request.
+    ///
+    /// Grants are looked up for every request, so changes to a user's roles apply immediately.
+    fn authorize(&self, user: Option<&str>, request: &Request) -> Result<()> {
+        if !self.auth_enabled {
+            return Ok(());
+        }
+        
+        let user = user.ok_or_else(|| XLimError::Authentication("Authentication required".to_string()))?;
+        
+        let (action, collection) = match request {
+            Request::Ping | Request::Auth { .. } => return Ok(()),
+            // Cursors can only be used by the connection that opened them, which was allowed
+            // to read the collection
+            Request::GetMore { .. } | Request::CloseCursor { .. } => return Ok(()),
+            // Everyone may change their own password
+            Request::ChangePassword { username, .. } if username == user => return Ok(()),
+            Request::CreateUser { .. }
+            | Request::DropUser { .. }
+            | Request::ChangePassword { .. }
+            | Request::GrantRoles { .. }
+            | Request::RevokeRoles { .. }
+            | Request::CreateRole { .. }
+            | Request::DropRole { .. } => (Action::ManageUsers, None),
+            Request::CreateCollection { name } => (Action::CreateCollection, Some(name)),
+            Request::DropCollection { name } => (Action::DropCollection, Some(name)),
+            // Replacing existing documents rewrites them, so it takes both actions
+            Request::InsertMany { collection, on_conflict: OnConflict::Replace, .. } => {
+                self.users.authorize(user, Action::Update, Some(collection))?;
+                (Action::Insert, Some(collection))
+            }
+            Request::Insert { collection, .. }
+            | Request::InsertMany { collection, .. }
+            | Request::TransactionInsert { collection, .. } => (Action::Insert, Some(collection)),
+            Request::Get { collection, .. }
+            | Request::OpenCursor { collection, .. }
+            | Request::Query { collection, .. }
+            | Request::Explain { collection, .. }
+            | Request::TransactionGet { collection, .. }
+            | Request::TransactionQuery { collection, .. }
+            | Request::Lock { collection, mode: LockMode::Shared, .. } => (Action::Find, Some(collection)),
+            Request::Update { collection, .. }
+            | Request::TransactionUpdate { collection, .. }
+            | Request::Lock { collection, mode: LockMode::Exclusive, .. } => (Action::Update, Some(collection)),
+            Request::Delete { collection, .. }
+            | Request::TransactionDelete { collection, .. } => (Action::Delete, Some(collection)),
+            Request::CreateIndex { collection, .. } => (Action::CreateIndex, Some(collection)),
+            Request::DropIndex { collection, .. } => (Action::DropIndex, Some(collection)),
+            Request::SetTtl { collection, .. } => (Action::SetTtl, Some(collection)),
+            Request::Begin { .. }
+            | Request::Commit { .. }
+            | Request::Rollback { .. }
+            | Request::Savepoint { .. }
+            | Request::RollbackToSavepoint { .. }
+            | Request::ReleaseSavepoint { .. } => (Action::Transaction, None),
+            Request::ListTransactions => (Action::ServerStatus, None),
+            Request::Backup { .. } => (Action::Backup, None),
+        };
+        
+        self.users.authorize(user, action, collection.map(String::as_str))
+    }
+    
+    /// Execute a single request on behalf of the logged-in user
+    fn execute(&self, user: Option<&str>, request: Request) -> Result<Response> {
+        self.authorize(user, &request)?;
+        
+        match request {
+            Request::Ping => Ok(Response::Pong),
+            Request::Auth { .. } => Err(XLimError::InvalidOperation("Auth must be handled by the connection".to_string())),
+            Request::CreateUser { username, password, roles } => {
+                self.users.add_user(&username, &password, &roles)?;
+                Ok(Response::Ok)
+            }
+            Request::DropUser { username } => {
+                self.users.remove_user(&username)?;
+                Ok(Response::Ok)
+            }
+            Request::ChangePassword { username, password } => {
+                self.users.change_password(&username, &password)?;
+                Ok(Response::Ok)
+            }
+            Request::GrantRoles { username, roles } => {
+                self.users.grant_roles(&username, &roles)?;
+                Ok(Response::Ok)
+            }
+            Request::RevokeRoles { username, roles } => {
+                self.users.revoke_roles(&username, &roles)?;
+                Ok(Response::Ok)
+            }
+            Request::CreateRole { name, privileges } => {
+                self.users.create_role(&Role { name, privileges })?;
+                Ok(Response::Ok)
+            }
+            Request::DropRole { name } => {
+                self.users.drop_role(&name)?;
+                Ok(Response::Ok)
+            }
+            Request::CreateCollection { name } => {
+                self.storage.create_collection(&name)?;
+                Ok(Response::Ok)
+            }
+            Request::DropCollection { name } => {
+                self.storage.delete_collection(&name)?;
+                Ok(Response::Ok)
+            }
+            Request::Insert { collection, document } => {
+                self.storage.insert_document(&collection, &document)?;
+                Ok(Response::DocumentId(document.id))
+            }
+            Request::InsertMany { collection, documents, on_conflict } => {
+                let result = self.storage.insert_many(&collection, &documents, on_conflict)?;
+                Ok(Response::BulkWrite(result))
+            }
+            Request::Get { collection, id } => {
+                let document = self.storage.get_document(&collection, &id)?;
+                Ok(Response::Document(document))
+            }
+            Request::Update { collection, document } => {
+                self.storage.update_document(&collection, &document)?;
+                Ok(Response::Ok)
+            }
+            Request::Delete { collection, id } => {
+                self.storage.delete_document(&collection, &id)?;
+                Ok(Response::Ok)
+            }
+            Request::OpenCursor { collection, batch_size } => {
+                let batch = self.cursors.open(&collection, batch_size, Some(self.connection_id))?;
+                Ok(Response::Cursor(batch))
+            }
+            Request::GetMore { cursor_id, batch_size } => {
+                let batch = self.cursors.get_more(cursor_id, batch_size, Some(self.connection_id))?;
+                Ok(Response::Cursor(batch))
+            }
+            Request::CloseCursor { cursor_id } => {
+                self.cursors.close(cursor_id, Some(self.connection_id))?;
+                Ok(Response::Ok)
+            }
+            Request::Query { collection, query } => {
+                query.validate()?;
+                let documents = self.storage.find_documents(&collection, &query)?;
+                Ok(Response::Documents(documents))
+            }
+            Request::Explain { collection, query } => {
+                query.validate()?;
+                let plan = self.storage.explain_query(&collection, &query)?;
+                Ok(Response::Plan(plan))
+            }
+            Request::CreateIndex { collection, field } => {
+                self.storage.create_index(&collection, &field)?;
+                Ok(Response::Ok)
+            }
+            Request::DropIndex { collection, field } => {
+                self.storage.drop_index(&collection, &field)?;
+                Ok(Response::Ok)
+            }
+            Request::SetTtl { collection, policy } => {
+                self.storage.set_ttl(&collection, policy)?;
+                Ok(Response::Ok)
+            }
+            Request::Begin { options } => {
+                let transaction = self.transactions.begin(options, Some(self.connection_id))?;
+                Ok(Response::TransactionId(transaction.id))
+            }
+            Request::Commit { transaction_id } => {
+                self.transactions.commit(transaction_id, Some(self.connection_id))?;
+                Ok(Response::Ok)
+            }
+            Request::Rollback { transaction_id } => {
+                self.transactions.rollback(transaction_id, Some(self.connection_id))?;
+                Ok(Response::Ok)
+            }
+            Request::TransactionInsert { transaction_id, collection, document } => {
+                let id = document.id;
+                self.transactions.insert(transaction_id, Some(self.connection_id), &collection, document)?;
+                Ok(Response::DocumentId(id))
+            }
+            Request::TransactionUpdate { transaction_id, collection, document } => {
+                self.transactions.update(transaction_id, Some(self.connection_id), &collection, document)?;
+                Ok(Response::Ok)
+            }
+            Request::TransactionDelete { transaction_id, collection, id } => {
+                self.transactions.delete(transaction_id, Some(self.connection_id), &collection, &id)?;
+                Ok(Response::Ok)
+            }
+            Request::Savepoint { transaction_id, name } => {
+                self.transactions.savepoint(transaction_id, Some(self.connection_id), &name)?;
+                Ok(Response::Ok)
+            }
+            Request::RollbackToSavepoint { transaction_id, name } => {
+                self.transactions.rollback_to(transaction_id, Some(self.connection_id), &name)?;
+                Ok(Response::Ok)
+            }
+            Request::ReleaseSavepoint { transaction_id, name } => {
+                self.transactions.release(transaction_id, Some(self.connection_id), &name)?;
+                Ok(Response::Ok)
+            }
+            Request::Lock { transaction_id, collection, id, mode } => {
+                self.transactions.lock(transaction_id, Some(self.connection_id), &collection, &id, mode)?;
+                Ok(Response::Ok)
+            }
+            Request::ListTransactions => {
+                let transactions = self.transactions.get_active_transactions();
+                Ok(Response::Transactions(transactions.iter().map(|t| t.info()).collect()))
+            }
+            Request::TransactionGet { transaction_id, collection, id } => {
+                let document = self.transactions.get(transaction_id, Some(self.connection_id), &collection, &id)?;
+                Ok(Response::Document(document))
+            }
+            Request::TransactionQuery { transaction_id, collection, query } => {
+                query.validate()?;
+                let documents = self.transactions.find(transaction_id, Some(self.connection_id), &collection, &query)?;
+                Ok(Response::Documents(documents))
+            }
+            Request::Backup { dir } => {
+                let backup = self.storage.create_backup(&dir)?;
+                Ok(Response::Backup(backup))
+            }
+        }
+    }
+}
+
+/// Write the response to a request, logging instead of failing if the client has gone away
+async fn send_response(writer: &Mutex<OwnedWriteHalf>, request_id: u64, response: Response, addr: SocketAddr) {
+    let reply = Envelope {
+        request_id,
+        message: response,
+    };
+    
+    if let Err(e) = write_frame(&mut *writer.lock().await, &reply).await {
+        debug!("Failed to send response to {}: {}", addr, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::document::Document;
+    use serde_json::json;
+    
+    /// Start a server with an in-memory database on a free port, returning its address
+    async fn start(auth_enabled: bool) -> (Arc<Server>, SocketAddr) {
+        let config = Config {
+            backend: BackendKind::Memory,
+            auth_enabled,
+            ..Config::default()
+        };
+        
+        let server = Arc::new(Server::new(config).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        
+        let running = server.clone();
+        tokio::spawn(async move { running.run(listener).await });
+        
+        (server, addr)
+    }
+    
+    #[tokio::test]
+    async fn documents_round_trip_through_the_server() {
+        let (_, addr) = start(false).await;
+        let client = Client::connect(addr).await.unwrap();
+        
+        let users = client.create_collection("users").await.unwrap();
+        let id = users.insert(Document::new().set("name", "Ann").set("age", 30)).await.unwrap();
+        
+        let mut document = users.get(&id.to_string()).await.unwrap();
+        assert_eq!(document.get("name"), Some(&json!("Ann")));
+        
+        document = document.set("age", 31);
+        users.update(document).await.unwrap();
+        assert_eq!(users.get(&id.to_string()).await.unwrap().get("age"), Some(&json!(31)));
+        assert_eq!(users.list().await.unwrap().len(), 1);
+        
+        users.delete(&id.to_string()).await.unwrap();
+        assert!(matches!(users.get(&id.to_string()).await, Err(XLimError::DocumentNotFound(_))));
+        
+        client.drop_collection("users").await.unwrap();
+        assert!(matches!(users.insert(Document::new()).await, Err(XLimError::CollectionNotFound(_))));
+    }
+    
+    #[tokio::test]
+    async fn requests_wait_for_the_client_to_log_in() {
+        let (server, addr) = start(true).await;
+        server.users.add_user("admin", "secret", &["admin".to_string()]).unwrap();
+        
+        match Client::connect(addr).await {
+            Err(XLimError::Authentication(message)) => assert_eq!(message, "Authentication required"),
+            other => panic!("connected without logging in: {:?}", other.err()),
+        }
+        
+        let wrong = Client::connect_with_credentials(addr, "admin", "wrong").await;
+        assert!(matches!(wrong, Err(XLimError::Authentication(_))));
+        
+        let client = Client::connect_with_credentials(addr, "admin", "secret").await.unwrap();
+        client.create_collection("c").await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn users_without_a_privilege_are_refused() {
+        let (server, addr) = start(true).await;
+        let role = Role { name: "orderEntry".to_string(), privileges: vec!["orders:find,insert".parse().unwrap()] };
+        server.users.create_role(&role).unwrap();
+        server.users.add_user("admin", "secret", &["admin".to_string()]).unwrap();
+        server.users.add_user("ann", "secret", &["orderEntry".to_string()]).unwrap();
+        
+        let admin = Client::connect_with_credentials(addr, "admin", "secret").await.unwrap();
+        admin.create_collection("orders").await.unwrap();
+        admin.create_collection("customers").await.unwrap();
+        
+        let ann = Client::connect_with_credentials(addr, "ann", "secret").await.unwrap();
+        let orders = ann.collection("orders").await;
+        let id = orders.insert(Document::new().set("total", 10)).await.unwrap();
+        
+        let mut document = orders.get(&id.to_string()).await.unwrap();
+        document = document.set("total", 0);
+        
+        assert!(matches!(orders.update(document.clone()).await, Err(XLimError::Authentication(_))));
+        assert!(matches!(orders.delete(&id.to_string()).await, Err(XLimError::Authentication(_))));
+        assert!(matches!(ann.collection("customers").await.list().await, Err(XLimError::Authentication(_))));
+        assert!(matches!(ann.create_collection("other").await, Err(XLimError::Authentication(_))));
+        assert!(matches!(ann.add_user("eve", "secret", &["admin"]).await, Err(XLimError::Authentication(_))));
+        
+        // Inserting cannot be used to rewrite an existing document
+        assert!(matches!(orders.insert(document.clone()).await, Err(XLimError::InvalidOperation(_))));
+        
+        let replace = orders.insert_many(vec![document.clone()], OnConflict::Replace).await;
+        assert!(matches!(replace, Err(XLimError::Authentication(_))));
+        
+        let transaction = ann.begin_transaction().await;
+        assert!(matches!(transaction, Err(XLimError::Authentication(_))));
+        
+        assert_eq!(orders.get(&id.to_string()).await.unwrap().get("total"), Some(&json!(10)));
+        
+        // Users may always change their own password
+        ann.change_password("ann", "new secret").await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn transaction_writes_are_seen_by_others_only_after_commit() {
+        let (_, addr) = start(false).await;
+        let client = Client::connect(addr).await.unwrap();
+        let users = client.create_collection("users").await.unwrap();
+        
+        let transaction = client.begin_transaction().await.unwrap();
+        let id = transaction.collection("users").insert(Document::new().set("name", "Ann")).await.unwrap();
+        
+        assert_eq!(transaction.collection("users").get(&id.to_string()).await.unwrap().get("name"), Some(&json!("Ann")));
+        assert!(matches!(users.get(&id.to_string()).await, Err(XLimError::DocumentNotFound(_))));
+        
+        transaction.commit().await.unwrap();
+        assert_eq!(users.get(&id.to_string()).await.unwrap().get("name"), Some(&json!("Ann")));
+        
+        let transaction = client.begin_transaction().await.unwrap();
+        transaction.collection("users").delete(&id.to_string()).await.unwrap();
+        transaction.rollback().await.unwrap();
+        assert!(users.get(&id.to_string()).await.is_ok());
+        assert!(transaction.commit().await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn with_transaction_retries_conflicts() {
+        let (_, addr) = start(false).await;
+        let client = Client::connect(addr).await.unwrap();
+        let counters = client.create_collection("counters").await.unwrap();
+        let id = counters.insert(Document::new().set("n", 0)).await.unwrap().to_string();
+        
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let n = client.with_transaction(|transaction| {
+            let (counters, id, attempts) = (counters.clone(), id.clone(), &attempts);
+            
+            async move {
+                let collection = transaction.collection("counters");
+                let document = collection.get(&id).await?;
+                
+                // Another client changes the counter during the first attempt
+                if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                    counters.update(document.clone().set("n", 10)).await?;
+                }
+                
+                let n = document.get("n").and_then(|n| n.as_i64()).unwrap() + 1;
+                collection.update(document.set("n", n)).await?;
+                Ok(n)
+            }
+        }).await.unwrap();
+        
+        assert_eq!(attempts.into_inner(), 2);
+        assert_eq!(n, 11);
+        assert_eq!(counters.get(&id).await.unwrap().get("n"), Some(&json!(11)));
+        
+        let failed: Result<()> = client.with_transaction(|transaction| {
+            let id = id.clone();
+            
+            async move {
+                transaction.collection("counters").delete(&id).await?;
+                Err(XLimError::InvalidOperation("changed my mind".to_string()))
+            }
+        }).await;
+        assert!(matches!(failed, Err(XLimError::InvalidOperation(_))));
+        assert_eq!(counters.list().await.unwrap().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn transactions_are_rolled_back_after_their_requests_finish() {
+        let (server, addr) = start(false).await;
+        let client = Client::connect(addr).await.unwrap();
+        let id = client.create_collection("c").await.unwrap().insert(Document::new()).await.unwrap().to_string();
+        
+        let holder = client.begin_transaction().await.unwrap();
+        holder.lock("c", &id, LockMode::Exclusive).await.unwrap();
+        
+        // A connection that leaves while its transaction waits for the lock
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        write_frame(&mut stream, &Envelope { request_id: 1, message: Request::Begin { options: Default::default() } }).await.unwrap();
+        
+        let transaction_id = match read_frame::<_, Envelope<Response>>(&mut stream).await.unwrap().unwrap().message {
+            Response::TransactionId(transaction_id) => transaction_id,
+            response => panic!("unexpected response: {:?}", response),
+        };
+        
+        let lock = Request::Lock { transaction_id, collection: "c".to_string(), id: id.clone(), mode: LockMode::Exclusive };
+        write_frame(&mut stream, &Envelope { request_id: 2, message: lock }).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        drop(stream);
+        
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(server.transactions.get_active_transactions().len(), 2);
+        
+        holder.commit().await.unwrap();
+        
+        for _ in 0..50 {
+            if server.transactions.get_active_transactions().is_empty() {
+                return;
+            }
+            
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        
+        panic!("the closed connection's transaction was not rolled back");
+    }
+    
+    #[tokio::test]
+    async fn failed_requests_leave_the_connection_usable() {
+        let (_, addr) = start(false).await;
+        let client = Client::connect(addr).await.unwrap();
+        
+        assert!(client.create_collection("c").await.is_ok());
+        assert!(matches!(client.create_collection("c").await, Err(XLimError::InvalidOperation(_))));
+        assert!(client.collection("c").await.get("not-a-uuid").await.is_err());
+        
+        client.ping().await.unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde_json::Value;
+use std::path::PathBuf;
+
+use xlim::client::{Client, Collection, Transaction, TransactionCollection};
+use xlim::document::Document;
+use xlim::error::{Result, XLimError};
+use xlim::parser;
+use xlim::query::Query;
+
+/// Help text printed by the `help` command
+const HELP: &str = "\
+Collections:
+  use <collection>              Switch to a collection
+  create <collection>           Create a collection and switch to it
+  drop <collection>             Drop a collection and all of its documents
+
+Documents (in the current collection, and in the open transaction if there is one):
+  insert <json>                 Insert a document, e.g. insert {\"name\": \"Jane\"}
+  get <id>                      Print a document
+  update <id> <json>            Merge fields into a document
+  delete <id>                   Delete a document
+  list                          Print every document
+  find [clauses]                Run a query, e.g. find WHERE age > 25 LIMIT 10
+
+Queries:
+  FROM <collection> [clauses]   Run a query in the XLim query language
+
+Transactions:
+  begin                         Begin a transaction
+  commit                        Commit the current transaction
+  rollback                      Roll back the current transaction
+  savepoint <name>              Set a savepoint in the current transaction
+  rollback to <name>            Undo the current transaction's changes since a savepoint
+  release <name>                Remove a savepoint
+
+  help                          Show this help
+  exit, quit                    Leave the shell";
+
+/// An interactive shell connected to a server
+pub struct Shell {
+    /// Connection to the server
+    client: Client,
+    
+    /// Collection that document commands apply to
+    collection: Option<Collection>,
+    
+    /// Transaction begun with `begin`, if any
+    transaction: Option<Transaction>,
+}
+
+impl Shell {
+    /// Create a shell for a connected client
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            collection: None,
+            transaction: None,
+        }
+    }
+    
+    /// Read and run commands until the user exits
+    pub async fn run(&mut self) -> Result<()> {
+        let mut editor = DefaultEditor::new()
+            .map_err(|e| XLimError::Unknown(format!("Failed to start line editor: {}", e)))?;
+        
+        let history = history_path();
+        
+        if let Some(path) = &history {
+            // A missing history file just means this is the first session
+            let _ = editor.load_history(path);
+        }
+        
+        println!("Connected to XLim. Type \"help\" for a list of commands.");
+        
+        loop {
+            let line = match tokio::task::block_in_place(|| editor.readline(&self.prompt())) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(XLimError::Unknown(format!("Failed to read line: {}", e))),
+            };
+            
+            let line = line.trim();
+            
+            if line.is_empty() {
+                continue;
+            }
+            
+            let _ = editor.add_history_entry(line);
+            
+            if matches!(line, "exit" | "quit") {
+                break;
+            }
+            
+            if let Err(e) = self.execute(line).await {
+                eprintln!("Error: {}", e);
+            }
+        }
+        
+        if let Some(path) = &history {
+            let _ = editor.save_history(path);
+        }
+        
+        if self.transaction.is_some() {
+            println!("Warning: leaving with an open transaction");
+        }
+        
+        Ok(())
+    }
+    
+    /// Build the prompt, showing the current collection and whether a transaction is open
+    fn prompt(&self) -> String {
+        let collection = self.collection.as_ref().map(Collection::name).unwrap_or("");
+        let transaction = if self.transaction.is_some() { "*" } else { "" };
+        
+        format!("xlim:{}{}> ", collection, transaction)
+    }
+    
+    /// Run a single command
+    async fn execute(&mut self, line: &str) -> Result<()> {
+        let (command, args) = match line.split_once(char::is_whitespace) {
+            Some((command, args)) => (command, args.trim()),
+            None => (line, ""),
+        };
+        
+        match command.to_lowercase().as_str() {
+            "help" => println!("{}", HELP),
+            "use" => {
+                let name = required(args, "use <collection>")?;
+                self.collection = Some(self.client.collection(name).await);
+            }
+            "create" => {
+                let name = required(args, "create <collection>")?;
+                self.collection = Some(self.client.create_collection(name).await?);
+                println!("Created collection {}", name);
+            }
+            "drop" => {
+                let name = required(args, "drop <collection>")?;
+                self.client.drop_collection(name).await?;
+                
+                if self.collection.as_ref().is_some_and(|c| c.name() == name) {
+                    self.collection = None;
+                }
+                
+                println!("Dropped collection {}", name);
+            }
+            "insert" => {
+                let document = parse_document(required(args, "insert <json>")?)?;
+                
+                let id = match self.in_transaction()? {
+                    Some(collection) => collection.insert(document).await?,
+                    None => self.current()?.insert(document).await?,
+                };
+                
+                println!("Inserted {}", id);
+            }
+            "get" => {
+                let id = required(args, "get <id>")?;
+                
+                let document = match self.in_transaction()? {
+                    Some(collection) => collection.get(id).await?,
+                    None => self.current()?.get(id).await?,
+                };
+                
+                println!("{}", document.to_json_pretty()?);
+            }
+            "update" => {
+                let (id, json) = required(args, "update <id> <json>")?
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| XLimError::InvalidOperation("Usage: update <id> <json>".to_string()))?;
+                
+                let changes = parse_document(json)?;
+                
+                match self.in_transaction()? {
+                    Some(collection) => {
+                        let mut document = collection.get(id).await?;
+                        document.merge(&changes);
+                        collection.update(document).await?;
+                    }
+                    None => {
+                        let collection = self.current()?;
+                        let mut document = collection.get(id).await?;
+                        document.merge(&changes);
+                        collection.update(document).await?;
+                    }
+                }
+                
+                println!("Updated {}", id);
+            }
+            "delete" => {
+                let id = required(args, "delete <id>")?;
+                
+                match self.in_transaction()? {
+                    Some(collection) => collection.delete(id).await?,
+                    None => self.current()?.delete(id).await?,
+                }
+                
+                println!("Deleted {}", id);
+            }
+            "list" => {
+                let documents = match self.in_transaction()? {
+                    Some(collection) => collection.find(Query::new()).await?,
+                    None => self.current()?.list().await?,
+                };
+                
+                print_documents(&documents)?;
+            }
+            "find" => {
+                let parsed = parser::parse(&format!("FROM {} {}", self.current()?.name(), args))?;
+                
+                let documents = match self.in_transaction()? {
+                    Some(collection) => collection.find(parsed.query).await?,
+                    None => self.current()?.query(parsed.query).await?,
+                };
+                
+                print_documents(&documents)?;
+            }
+            "from" => {
+                let parsed = parser::parse(line)?;
+                let collection = self.client.collection(&parsed.collection).await;
+                let documents = collection.query(parsed.query).await?;
+                print_documents(&documents)?;
+            }
+            "begin" => {
+                if self.transaction.is_some() {
+                    return Err(XLimError::Transaction("A transaction is already open".to_string()));
+                }
+                
+                let transaction = self.client.begin_transaction().await?;
+                println!("Began transaction {}", transaction.id());
+                self.transaction = Some(transaction);
+            }
+            "commit" => {
+                let transaction = self.transaction.take()
+                    .ok_or_else(|| XLimError::Transaction("No transaction is open".to_string()))?;
+                
+                transaction.commit().await?;
+                println!("Committed transaction {}", transaction.id());
+            }
+            "rollback" if !args.is_empty() => {
+                let name = args.strip_prefix("to ")
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .ok_or_else(|| XLimError::InvalidOperation("Usage: rollback to <name>".to_string()))?;
+                
+                self.open_transaction()?.rollback_to(name).await?;
+                println!("Rolled back to savepoint {}", name);
+            }
+            "savepoint" => {
+                let name = required(args, "savepoint <name>")?;
+                self.open_transaction()?.savepoint(name).await?;
+                println!("Set savepoint {}", name);
+            }
+            "release" => {
+                let name = required(args, "release <name>")?;
+                self.open_transaction()?.release(name).await?;
+                println!("Released savepoint {}", name);
+            }
+            "rollback" => {
+                let transaction = self.transaction.take()
+                    .ok_or_else(|| XLimError::Transaction("No transaction is open".to_string()))?;
+                
+                transaction.rollback().await?;
+                println!("Rolled back transaction {}", transaction.id());
+            }
+            _ => {
+                return Err(XLimError::InvalidOperation(format!("Unknown command: {} (type \"help\" for a list of commands)", command)));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Get the current collection
+    fn current(&self) -> Result<&Collection> {
+        self.collection.as_ref()
+            .ok_or_else(|| XLimError::InvalidOperation("No collection selected; run \"use <collection>\" first".to_string()))
+    }
+    
+    /// Get the open transaction
+    fn open_transaction(&self) -> Result<&Transaction> {
+        self.transaction.as_ref()
+            .ok_or_else(|| XLimError::Transaction("No transaction is open".to_string()))
+    }
+    
+    /// Get the current collection inside the open transaction, if there is one
+    fn in_transaction(&self) -> Result<Option<TransactionCollection>> {
+        let name = self.current()?.name();
+        Ok(self.transaction.as_ref().map(|transaction| transaction.collection(name)))
+    }
+}
+
+/// Check that a command was given an argument
+fn required<'a>(args: &'a str, usage: &str) -> Result<&'a str> {
+    if args.is_empty() {
+        return Err(XLimError::InvalidOperation(format!("Usage: {}", usage)));
+    }
+    
+    Ok(args)
+}
+
+/// Parse a JSON object into a new document
+fn parse_document(json: &str) -> Result<Document> {
+    match serde_json::from_str(json)? {
+        Value::Object(data) => {
+            let mut document = Document::new();
+            document.data = data;
+            Ok(document)
+        }
+        _ => Err(XLimError::InvalidOperation("Documents must be JSON objects".to_string())),
+    }
+}
+
+/// Pretty-print documents followed by their count
+fn print_documents(documents: &[Document]) -> Result<()> {
+    for document in documents {
+        println!("{}", document.to_json_pretty()?);
+    }
+    
+    println!("({} documents)", documents.len());
+    
+    Ok(())
+}
+
+/// Location of the history file in the user's home directory
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".xlim_history"))
+}
@@ -1,80 +1,181 @@
 use dashmap::DashMap;
-use log::{debug, error, info};
-use rocksdb::{ColumnFamilyDescriptor, Options, DB};
+use log::{debug, error, info, warn};
 use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
+use crate::backend::{Direction, KeyValueIter, Keyspace, StorageBackend, WriteBatch};
+use crate::backup::BackupInfo;
+use crate::codec::{self, Stored};
 use crate::document::{Collection, Document};
 use crate::error::{Result, XLimError};
+use crate::index;
+use crate::memory_backend::MemoryBackend;
+use crate::planner::{self, Access, QueryPlan};
+use crate::query::Query;
+use crate::rocksdb_backend::RocksDbBackend;
+use crate::transaction::{CommitRecord, CommitState, Operation, OperationType};
+use crate::transfer::{BulkWriteResult, OnConflict};
+use crate::ttl::{Expiry, TtlPolicy, TTL_METADATA_KEY};
+use uuid::Uuid;
+
+pub use crate::backend::Snapshot;
+
+/// Prefix of the metadata keys that hold commit records
+const COMMIT_PREFIX: &str = "commit:";
+
+/// Metadata key of the storage format every stored value has been migrated to
+const FORMAT_KEY: &str = "storage_format";
+
+/// Number of values rewritten per batch when migrating to a new storage format
+const MIGRATION_BATCH_SIZE: usize = 1000;
+
+/// Metadata key of the layout every document and index key has been migrated to
+const LAYOUT_KEY: &str = "key_layout";
+
+/// Layout of keys written by this version: document keys are the collection ID, then the
+/// document ID, and index keys start with the collection ID too
+const CURRENT_LAYOUT: u8 = 2;
+
+/// Metadata key of the next ID to give a collection
+const NEXT_COLLECTION_ID_KEY: &str = "next_collection_id";
+
+/// Number of expired documents deleted per batch when purging
+const PURGE_BATCH_SIZE: usize = 1000;
+
+/// Length of the collection ID that starts every document key
+pub const COLLECTION_ID_LENGTH: usize = 8;
+
+/// Collection, stored version and final version of a document changed by a transaction
+type Change<'a> = (&'a str, Option<Document>, Option<Document>);
+
+/// Lazily reads the documents of a collection in key order, skipping expired ones
+pub struct DocumentCursor<'a> {
+    /// Scan of the collection's documents
+    iter: KeyValueIter<'a>,
+    
+    /// Key of the document the cursor resumes after, skipped if it is read first
+    skip: Option<Vec<u8>>,
+    
+    /// Decides which documents have expired
+    expiry: Expiry,
+    
+    /// Set once the cursor has passed the last document of the collection
+    done: bool,
+}
+
+impl Iterator for DocumentCursor<'_> {
+    type Item = Result<Document>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        
+        for item in self.iter.by_ref() {
+            let (key, value) = match item {
+                Ok(item) => item,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            
+            if self.skip.take().is_some_and(|skip| *key == *skip) {
+                continue;
+            }
+            
+            match codec::decode(&value) {
+                Ok(document) if self.expiry.is_expired(&document) => continue,
+                result => return Some(result),
+            }
+        }
+        
+        self.done = true;
+        
+        None
+    }
+}
 
 /// Storage engine for the database
 pub struct StorageEngine {
-    /// RocksDB instance
-    db: Arc<DB>,
+    /// Backend the data is kept in
+    backend: Arc<dyn StorageBackend>,
     
     /// Cache of collections
     collections: DashMap<String, Collection>,
+    
+    /// Held for reading by document writes and for writing while a collection's indexes or
+    /// TTL policy change
+    index_lock: RwLock<()>,
+    
+    /// Held by every document write, so that a write cannot land between a transaction's
+    /// conflict checks and its commit
+    write_lock: Mutex<()>,
+    
+    /// ID to give the next collection created
+    next_collection_id: AtomicU64,
+    
+    /// IDs of the collections that may hold documents with their own expiry time, which
+    /// purges scan even if the collection has no TTL policy
+    expiring: Mutex<HashSet<u64>>,
 }
 
 impl StorageEngine {
-    /// Create a new storage engine
+    /// Create a storage engine over a RocksDB database at a path
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        // Create RocksDB options
-        let mut options = Options::default();
-        options.create_if_missing(true);
-        options.create_missing_column_families(true);
-        options.set_keep_log_file_num(10);
-        options.set_max_open_files(1000);
-        options.set_use_fsync(false);
-        options.set_bytes_per_sync(8388608); // 8MB
-        options.optimize_for_point_lookup(64 * 1024 * 1024); // 64MB
-        options.set_table_cache_num_shard_bits(6);
-        options.set_max_write_buffer_number(6);
-        options.set_write_buffer_size(128 * 1024 * 1024); // 128MB
-        options.set_target_file_size_base(64 * 1024 * 1024); // 64MB
-        options.set_min_write_buffer_number_to_merge(2);
-        options.set_level_zero_stop_writes_trigger(36);
-        options.set_level_zero_slowdown_writes_trigger(28);
-        options.set_compaction_style(rocksdb::DBCompactionStyle::Level);
-        options.set_max_background_jobs(6);
-        options.set_max_background_compactions(4);
-        options.set_max_background_flushes(2);
-        
-        // Define column families
-        let cf_names = vec!["default", "collections", "documents", "indexes", "metadata"];
-        let cf_descriptors: Vec<ColumnFamilyDescriptor> = cf_names
-            .iter()
-            .map(|name| ColumnFamilyDescriptor::new(*name, options.clone()))
-            .collect();
-        
-        // Open the database
-        let db = DB::open_cf_descriptors(&options, path, cf_descriptors)
-            .map_err(|e| XLimError::Storage(format!("Failed to open database: {}", e)))?;
+        Self::with_backend(Arc::new(RocksDbBackend::open(path)?))
+    }
+    
+    /// Create a storage engine that keeps everything in memory, and loses it when dropped
+    pub fn in_memory() -> Result<Self> {
+        Self::with_backend(Arc::new(MemoryBackend::new()))
+    }
+    
+    /// Create a storage engine over any backend
+    pub fn with_backend(backend: Arc<dyn StorageBackend>) -> Result<Self> {
+        let storage = Self {
+            backend,
+            collections: DashMap::new(),
+            index_lock: RwLock::new(()),
+            write_lock: Mutex::new(()),
+            next_collection_id: AtomicU64::new(1),
+            expiring: Mutex::new(HashSet::new()),
+        };
         
-        let db = Arc::new(db);
+        // Collections can only be decoded once they are in the current format
+        storage.migrate_storage_format()?;
         
         // Load collections
-        let collections = DashMap::new();
-        let cf_collections = db.cf_handle("collections")
-            .ok_or_else(|| XLimError::Storage("Collections column family not found".to_string()))?;
-        
-        let iter = db.iterator_cf(&cf_collections, rocksdb::IteratorMode::Start);
-        for item in iter {
-            let (key, value) = item.map_err(|e| XLimError::Storage(format!("Failed to read collection: {}", e)))?;
+        for item in storage.backend.scan(Keyspace::Collections, &[], &[], Direction::Forward, None)? {
+            let (key, value) = item?;
             let key_str = String::from_utf8_lossy(&key).to_string();
-            let collection: Collection = bincode::deserialize(&value)
-                .map_err(|e| XLimError::Storage(format!("Failed to deserialize collection: {}", e)))?;
+            let collection: Collection = codec::decode(&value)?;
             
-            collections.insert(key_str, collection);
+            storage.collections.insert(key_str, collection);
         }
         
-        info!("Loaded {} collections from storage", collections.len());
+        info!("Loaded {} collections from storage", storage.collections.len());
         
-        Ok(Self {
-            db,
-            collections,
-        })
+        // Until a purge has scanned them, any collection may hold documents that expire
+        storage.expiring.lock().unwrap().extend(storage.collections.iter().map(|collection| collection.id));
+        
+        // Never reuse the ID of a collection, even one that was dropped
+        let stored_next_id = storage.get_metadata::<u64>(NEXT_COLLECTION_ID_KEY)?.unwrap_or(1);
+        let loaded_next_id = storage.collections.iter().map(|collection| collection.id + 1).max().unwrap_or(1);
+        storage.next_collection_id.store(stored_next_id.max(loaded_next_id), Ordering::SeqCst);
+        
+        storage.migrate_key_layout()?;
+        
+        let recovered = storage.recover_commits()?;
+        
+        if recovered > 0 {
+            info!("Finished {} commits interrupted by a crash", recovered);
+        }
+        
+        Ok(storage)
     }
     
     /// Get a collection by name
@@ -88,21 +189,23 @@ impl StorageEngine {
     
     /// Create a new collection
     pub fn create_collection(&self, name: &str) -> Result<Collection> {
+        // Held until the collection is cached, so that no one else can create it in between
+        let _index_guard = self.index_lock.write().unwrap();
+        let _write_guard = self.write_lock.lock().unwrap();
+        
         if self.collections.contains_key(name) {
             return Err(XLimError::InvalidOperation(format!("Collection '{}' already exists", name)));
         }
         
-        let collection = Collection::new(name);
-        
-        // Serialize and store the collection
-        let cf_collections = self.db.cf_handle("collections")
-            .ok_or_else(|| XLimError::Storage("Collections column family not found".to_string()))?;
+        let mut collection = Collection::new(name);
+        collection.id = self.next_collection_id.fetch_add(1, Ordering::SeqCst);
         
-        let serialized = bincode::serialize(&collection)
-            .map_err(|e| XLimError::Storage(format!("Failed to serialize collection: {}", e)))?;
+        // Serialize and store the collection, with the ID counter so the ID is never reused
+        let mut batch = WriteBatch::default();
+        batch.put(Keyspace::Collections, name, codec::encode(&collection)?);
+        batch.put(Keyspace::Metadata, NEXT_COLLECTION_ID_KEY, bincode::serialize(&(collection.id + 1))?);
         
-        self.db.put_cf(&cf_collections, name.as_bytes(), serialized)
-            .map_err(|e| XLimError::Storage(format!("Failed to store collection: {}", e)))?;
+        self.backend.write(batch, false)?;
         
         // Add to cache
         self.collections.insert(name.to_string(), collection.clone());
@@ -112,112 +215,144 @@ impl StorageEngine {
         Ok(collection)
     }
     
-    /// Delete a collection
+    /// Delete a collection, with all of its documents and index entries in one atomic write
     pub fn delete_collection(&self, name: &str) -> Result<()> {
-        if !self.collections.contains_key(name) {
-            return Err(XLimError::CollectionNotFound(name.to_string()));
-        }
-        
-        // Remove from storage
-        let cf_collections = self.db.cf_handle("collections")
-            .ok_or_else(|| XLimError::Storage("Collections column family not found".to_string()))?;
+        // Keeps every write out until the collection is gone
+        let _index_guard = self.index_lock.write().unwrap();
         
-        self.db.delete_cf(&cf_collections, name.as_bytes())
-            .map_err(|e| XLimError::Storage(format!("Failed to delete collection: {}", e)))?;
+        let collection = self.get_collection(name)?;
         
-        // Remove from cache
-        self.collections.remove(name);
+        let mut batch = WriteBatch::default();
+        batch.delete(Keyspace::Collections, name);
         
         // Delete all documents in the collection
-        let cf_documents = self.db.cf_handle("documents")
-            .ok_or_else(|| XLimError::Storage("Documents column family not found".to_string()))?;
+        let prefix = collection.id.to_be_bytes();
         
-        let prefix = format!("{}:", name);
-        let iter = self.db.iterator_cf(&cf_documents, rocksdb::IteratorMode::From(prefix.as_bytes(), rocksdb::Direction::Forward));
-        
-        for item in iter {
-            let (key, _) = item.map_err(|e| XLimError::Storage(format!("Failed to read document: {}", e)))?;
-            let key_str = String::from_utf8_lossy(&key).to_string();
-            
-            if !key_str.starts_with(&prefix) {
-                break;
-            }
+        for item in self.backend.scan(Keyspace::Documents, &prefix, &prefix, Direction::Forward, None)? {
+            let (key, _) = item?;
             
-            self.db.delete_cf(&cf_documents, key)
-                .map_err(|e| XLimError::Storage(format!("Failed to delete document: {}", e)))?;
+            batch.delete(Keyspace::Documents, key);
         }
         
+        // Delete all index entries of the collection
+        self.delete_index_entries(&mut batch, &index::collection_prefix(collection.id))?;
+        
+        self.backend.write(batch, false)?;
+        
+        // Remove from cache
+        self.collections.remove(name);
+        
         info!("Deleted collection: {}", name);
         
         Ok(())
     }
     
-    /// Insert a document into a collection
+    /// Insert a document into a collection.
+    ///
+    /// Fails if a document with the same ID exists; replacing one is an update.
     pub fn insert_document(&self, collection_name: &str, document: &Document) -> Result<()> {
-        if !self.collections.contains_key(collection_name) {
-            return Err(XLimError::CollectionNotFound(collection_name.to_string()));
+        let key = self.document_key(collection_name, &document.id)?;
+        let serialized = codec::encode(document)?;
+        
+        let _index_guard = self.index_lock.read().unwrap();
+        let _write_guard = self.write_lock.lock().unwrap();
+        
+        if self.read_live_document(collection_name, &key, None)?.is_some() {
+            return Err(XLimError::InvalidOperation(format!("Document already exists: {}", document.id)));
         }
         
-        let cf_documents = self.db.cf_handle("documents")
-            .ok_or_else(|| XLimError::Storage("Documents column family not found".to_string()))?;
+        // An expired document with the same ID may still be stored, and its index entries must go
+        let existing = self.read_document(&key, None)?;
         
-        let key = format!("{}:{}", collection_name, document.id);
-        let serialized = bincode::serialize(document)
-            .map_err(|e| XLimError::Storage(format!("Failed to serialize document: {}", e)))?;
+        let mut batch = WriteBatch::default();
+        self.update_index_entries(&mut batch, collection_name, existing.as_ref(), Some(document))?;
+        self.track_expiry(collection_name, document);
+        batch.put(Keyspace::Documents, key, serialized);
         
-        self.db.put_cf(&cf_documents, key.as_bytes(), serialized)
-            .map_err(|e| XLimError::Storage(format!("Failed to store document: {}", e)))?;
+        self.backend.write(batch, false)?;
         
         debug!("Inserted document {} into collection {}", document.id, collection_name);
         
         Ok(())
     }
     
-    /// Get a document from a collection
-    pub fn get_document(&self, collection_name: &str, document_id: &str) -> Result<Document> {
-        if !self.collections.contains_key(collection_name) {
-            return Err(XLimError::CollectionNotFound(collection_name.to_string()));
+    /// Insert a batch of documents into a collection in a single atomic write.
+    ///
+    /// A document whose ID is taken, by a stored document or an earlier one in the batch, is
+    /// left out unless `on_conflict` is [`OnConflict::Replace`], and with
+    /// [`OnConflict::Abort`] nothing is written if any document is. The documents left out are
+    /// listed in the result either way.
+    pub fn insert_many(&self, collection_name: &str, documents: &[Document], on_conflict: OnConflict) -> Result<BulkWriteResult> {
+        let _index_guard = self.index_lock.read().unwrap();
+        let _write_guard = self.write_lock.lock().unwrap();
+        
+        let mut result = BulkWriteResult::default();
+        let mut operations = Vec::with_capacity(documents.len());
+        let mut seen = HashSet::with_capacity(documents.len());
+        
+        for (position, document) in documents.iter().enumerate() {
+            let key = self.document_key(collection_name, &document.id)?;
+            let exists = !seen.insert(document.id) || self.read_live_document(collection_name, &key, None)?.is_some();
+            
+            match (exists, on_conflict) {
+                (false, _) => result.inserted += 1,
+                (true, OnConflict::Replace) => result.replaced += 1,
+                (true, _) => {
+                    result.conflicts.push(position);
+                    continue;
+                }
+            }
+            
+            operations.push(Operation {
+                op_type: if exists { OperationType::Update } else { OperationType::Insert },
+                collection: collection_name.to_string(),
+                document_id: document.id,
+                document: Some(document.clone()),
+            });
         }
         
-        let cf_documents = self.db.cf_handle("documents")
-            .ok_or_else(|| XLimError::Storage("Documents column family not found".to_string()))?;
+        if on_conflict == OnConflict::Abort && !result.conflicts.is_empty() {
+            result.inserted = 0;
+            return Ok(result);
+        }
         
-        let key = format!("{}:{}", collection_name, document_id);
-        let value = self.db.get_cf(&cf_documents, key.as_bytes())
-            .map_err(|e| XLimError::Storage(format!("Failed to read document: {}", e)))?
-            .ok_or_else(|| XLimError::DocumentNotFound(document_id.to_string()))?;
+        if !operations.is_empty() {
+            let batch = self.operations_batch(&operations)?;
+            self.backend.write(batch, false)?;
+        }
+        
+        debug!("Inserted {} and replaced {} documents in collection {}", result.inserted, result.replaced, collection_name);
         
-        let document: Document = bincode::deserialize(&value)
-            .map_err(|e| XLimError::Storage(format!("Failed to deserialize document: {}", e)))?;
+        Ok(result)
+    }
+    
+    /// Get a document from a collection
+    pub fn get_document(&self, collection_name: &str, document_id: &str) -> Result<Document> {
+        let key = self.document_key(collection_name, &parse_document_id(document_id)?)?;
         
-        Ok(document)
+        self.read_live_document(collection_name, &key, None)?
+            .ok_or_else(|| XLimError::DocumentNotFound(document_id.to_string()))
     }
     
     /// Update a document in a collection
     pub fn update_document(&self, collection_name: &str, document: &Document) -> Result<()> {
-        if !self.collections.contains_key(collection_name) {
-            return Err(XLimError::CollectionNotFound(collection_name.to_string()));
-        }
-        
-        let cf_documents = self.db.cf_handle("documents")
-            .ok_or_else(|| XLimError::Storage("Documents column family not found".to_string()))?;
+        let key = self.document_key(collection_name, &document.id)?;
         
-        let key = format!("{}:{}", collection_name, document.id);
+        let _index_guard = self.index_lock.read().unwrap();
+        let _write_guard = self.write_lock.lock().unwrap();
         
         // Check if document exists
-        let exists = self.db.get_cf(&cf_documents, key.as_bytes())
-            .map_err(|e| XLimError::Storage(format!("Failed to read document: {}", e)))?
-            .is_some();
+        let existing = self.read_live_document(collection_name, &key, None)?
+            .ok_or_else(|| XLimError::DocumentNotFound(document.id.to_string()))?;
         
-        if !exists {
-            return Err(XLimError::DocumentNotFound(document.id.to_string()));
-        }
+        let serialized = codec::encode(document)?;
         
-        let serialized = bincode::serialize(document)
-            .map_err(|e| XLimError::Storage(format!("Failed to serialize document: {}", e)))?;
+        let mut batch = WriteBatch::default();
+        self.update_index_entries(&mut batch, collection_name, Some(&existing), Some(document))?;
+        self.track_expiry(collection_name, document);
+        batch.put(Keyspace::Documents, key, serialized);
         
-        self.db.put_cf(&cf_documents, key.as_bytes(), serialized)
-            .map_err(|e| XLimError::Storage(format!("Failed to update document: {}", e)))?;
+        self.backend.write(batch, false)?;
         
         debug!("Updated document {} in collection {}", document.id, collection_name);
         
@@ -226,103 +361,1348 @@ impl StorageEngine {
     
     /// Delete a document from a collection
     pub fn delete_document(&self, collection_name: &str, document_id: &str) -> Result<()> {
-        if !self.collections.contains_key(collection_name) {
-            return Err(XLimError::CollectionNotFound(collection_name.to_string()));
+        let key = self.document_key(collection_name, &parse_document_id(document_id)?)?;
+        
+        let _index_guard = self.index_lock.read().unwrap();
+        let _write_guard = self.write_lock.lock().unwrap();
+        
+        // Check if document exists
+        let existing = self.read_live_document(collection_name, &key, None)?
+            .ok_or_else(|| XLimError::DocumentNotFound(document_id.to_string()))?;
+        
+        let mut batch = WriteBatch::default();
+        self.update_index_entries(&mut batch, collection_name, Some(&existing), None)?;
+        batch.delete(Keyspace::Documents, key);
+        
+        self.backend.write(batch, false)?;
+        
+        debug!("Deleted document {} from collection {}", document_id, collection_name);
+        
+        Ok(())
+    }
+    
+    /// Apply the operations of a transaction atomically.
+    ///
+    /// `validate` runs first, while no other write can happen, and can refuse the commit.
+    /// Every operation is then validated against the current data, and the effects of earlier
+    /// operations in the list, before anything is written. A pending commit record with the
+    /// operations is written next; the documents, their index entries and the completion of
+    /// the record are then written in a single batch, so either all of the operations land or
+    /// none do, and a commit cut short by a crash is finished by [`StorageEngine::new`]. With
+    /// `sync`, the write-ahead log is flushed to disk before returning.
+    pub fn apply_operations<F: FnOnce() -> Result<()>>(&self, transaction_id: Uuid, operations: &[Operation], sync: bool, validate: F) -> Result<()> {
+        let _index_guard = self.index_lock.read().unwrap();
+        let _write_guard = self.write_lock.lock().unwrap();
+        
+        validate()?;
+        
+        let batch = self.operations_batch(operations)?;
+        
+        let record = CommitRecord {
+            transaction_id,
+            operations: operations.to_vec(),
+            state: CommitState::Pending,
+        };
+        
+        self.write_commit_record(None, &record, sync)?;
+        
+        let result = self.finish_commit(batch, record, sync);
+        
+        // The caller is told the commit failed, so it must not be replayed on recovery
+        if result.is_err() {
+            let _ = self.delete_metadata(&commit_key(&transaction_id));
         }
         
-        let cf_documents = self.db.cf_handle("documents")
-            .ok_or_else(|| XLimError::Storage("Documents column family not found".to_string()))?;
+        result
+    }
+    
+    /// Validate a list of operations against the current data and build the batch that
+    /// applies them.
+    ///
+    /// Must be called while holding the index and write locks.
+    fn operations_batch(&self, operations: &[Operation]) -> Result<WriteBatch> {
+        // Stored and final version of every document touched, by storage key
+        let mut changes: HashMap<Vec<u8>, Change> = HashMap::new();
+        // Storage keys in the order they were first touched, so the batch is deterministic
+        let mut order = Vec::new();
         
-        let key = format!("{}:{}", collection_name, document_id);
+        for operation in operations {
+            let key = self.document_key(&operation.collection, &operation.document_id)?;
+            
+            if !changes.contains_key(&key) {
+                // An expired document no longer exists, but its index entries must still go
+                let stored = self.read_document(&key, None)?;
+                let expiry = Expiry::of(&self.get_collection(&operation.collection)?);
+                let live = stored.clone().filter(|document| !expiry.is_expired(document));
+                
+                changes.insert(key.clone(), (&operation.collection, stored, live));
+                order.push(key.clone());
+            }
+            
+            let (_, _, current) = changes.get_mut(&key).unwrap();
+            
+            *current = match operation.op_type {
+                OperationType::Insert => {
+                    let document = operation.document.clone()
+                        .ok_or_else(|| XLimError::Transaction("Insert operation missing document".to_string()))?;
+                    
+                    if current.is_some() {
+                        return Err(XLimError::InvalidOperation(format!("Document already exists: {}", operation.document_id)));
+                    }
+                    
+                    Some(document)
+                }
+                OperationType::Update => {
+                    let document = operation.document.clone()
+                        .ok_or_else(|| XLimError::Transaction("Update operation missing document".to_string()))?;
+                    
+                    if current.is_none() {
+                        return Err(XLimError::DocumentNotFound(operation.document_id.to_string()));
+                    }
+                    
+                    Some(document)
+                }
+                OperationType::Delete => {
+                    if current.is_none() {
+                        return Err(XLimError::DocumentNotFound(operation.document_id.to_string()));
+                    }
+                    
+                    None
+                }
+            };
+        }
         
-        // Check if document exists
-        let exists = self.db.get_cf(&cf_documents, key.as_bytes())
-            .map_err(|e| XLimError::Storage(format!("Failed to read document: {}", e)))?
-            .is_some();
+        let mut batch = WriteBatch::default();
         
-        if !exists {
-            return Err(XLimError::DocumentNotFound(document_id.to_string()));
+        for key in &order {
+            let (collection_name, stored, current) = &changes[key];
+            
+            // Index entries only change between the stored and final versions, whatever happened in between
+            self.update_index_entries(&mut batch, collection_name, stored.as_ref(), current.as_ref())?;
+            
+            match current {
+                Some(document) => {
+                    let serialized = codec::encode(document)?;
+                    
+                    self.track_expiry(collection_name, document);
+                    batch.put(Keyspace::Documents, key.clone(), serialized);
+                }
+                None => batch.delete(Keyspace::Documents, key.clone()),
+            }
         }
         
-        self.db.delete_cf(&cf_documents, key.as_bytes())
-            .map_err(|e| XLimError::Storage(format!("Failed to delete document: {}", e)))?;
+        debug!("Prepared {} operations on {} documents", operations.len(), order.len());
         
-        debug!("Deleted document {} from collection {}", document_id, collection_name);
+        Ok(batch)
+    }
+    
+    /// Write the batch of a pending commit along with the completion of its record, then
+    /// remove the record
+    fn finish_commit(&self, mut batch: WriteBatch, mut record: CommitRecord, sync: bool) -> Result<()> {
+        let key = commit_key(&record.transaction_id);
+        
+        record.operations.clear();
+        record.state = CommitState::Applied;
+        self.write_commit_record(Some(&mut batch), &record, false)?;
+        
+        self.backend.write(batch, sync)?;
+        
+        // The commit is complete at this point; a record left behind is removed on recovery
+        if let Err(e) = self.delete_metadata(&key) {
+            warn!("Failed to remove commit record of transaction {}: {}", record.transaction_id, e);
+        }
         
         Ok(())
     }
     
-    /// List all documents in a collection
-    pub fn list_documents(&self, collection_name: &str) -> Result<Vec<Document>> {
-        if !self.collections.contains_key(collection_name) {
-            return Err(XLimError::CollectionNotFound(collection_name.to_string()));
+    /// Write a commit record, into a batch if one is given or else on its own
+    fn write_commit_record(&self, batch: Option<&mut WriteBatch>, record: &CommitRecord, sync: bool) -> Result<()> {
+        let serialized = codec::encode(record)?;
+        let key = commit_key(&record.transaction_id);
+        
+        match batch {
+            Some(batch) => batch.put(Keyspace::Metadata, key, serialized),
+            None => {
+                let mut batch = WriteBatch::default();
+                batch.put(Keyspace::Metadata, key, serialized);
+                
+                self.backend.write(batch, sync)?;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Rewrite collections and documents stored in an older format in the current one.
+    ///
+    /// Fails without changing anything if the database was written in a newer format than
+    /// this version supports.
+    fn migrate_storage_format(&self) -> Result<()> {
+        match self.get_metadata::<u8>(FORMAT_KEY)? {
+            Some(format) if format == codec::CURRENT_FORMAT => return Ok(()),
+            Some(format) if format > codec::CURRENT_FORMAT => {
+                return Err(XLimError::Storage(format!(
+                    "The database uses storage format {}, but this version only supports up to {}",
+                    format, codec::CURRENT_FORMAT,
+                )));
+            }
+            _ => {}
         }
         
-        let cf_documents = self.db.cf_handle("documents")
-            .ok_or_else(|| XLimError::Storage("Documents column family not found".to_string()))?;
+        let migrated = self.migrate_keyspace::<Collection>(Keyspace::Collections)?
+            + self.migrate_keyspace::<Document>(Keyspace::Documents)?;
         
-        let prefix = format!("{}:", collection_name);
-        let iter = self.db.iterator_cf(&cf_documents, rocksdb::IteratorMode::From(prefix.as_bytes(), rocksdb::Direction::Forward));
+        self.store_metadata(FORMAT_KEY, &codec::CURRENT_FORMAT)?;
         
-        let mut documents = Vec::new();
+        if migrated > 0 {
+            info!("Migrated {} stored values to storage format {}", migrated, codec::CURRENT_FORMAT);
+        }
         
-        for item in iter {
-            let (key, value) = item.map_err(|e| XLimError::Storage(format!("Failed to read document: {}", e)))?;
-            let key_str = String::from_utf8_lossy(&key).to_string();
+        Ok(())
+    }
+    
+    /// Rewrite the values of a keyspace that are not in the current format, returning how
+    /// many there were.
+    ///
+    /// Values that cannot be decoded in any known format are left as they are, with a warning,
+    /// rather than making the whole database unusable.
+    fn migrate_keyspace<T: Stored>(&self, keyspace: Keyspace) -> Result<usize> {
+        let mut batch = WriteBatch::default();
+        let mut migrated = 0;
+        
+        for item in self.backend.scan(keyspace, &[], &[], Direction::Forward, None)? {
+            let (key, value) = item?;
             
-            if !key_str.starts_with(&prefix) {
-                break;
+            // Bincode values can start with the current format byte too
+            if codec::is_current(&value) && codec::decode::<T>(&value).is_ok() {
+                continue;
             }
             
-            let document: Document = bincode::deserialize(&value)
-                .map_err(|e| XLimError::Storage(format!("Failed to deserialize document: {}", e)))?;
+            let value: T = match codec::decode_unversioned(&value) {
+                Ok(value) => value,
+                Err(e) => {
+                    warn!("Leaving {:?} value {} unmigrated: {}", keyspace, String::from_utf8_lossy(&key), e);
+                    continue;
+                }
+            };
             
-            documents.push(document);
+            batch.put(keyspace, key, codec::encode(&value)?);
+            migrated += 1;
+            
+            if batch.len() >= MIGRATION_BATCH_SIZE {
+                self.backend.write(std::mem::take(&mut batch), false)?;
+            }
         }
         
-        Ok(documents)
+        self.backend.write(batch, false)?;
+        
+        Ok(migrated)
     }
     
-    /// Store a value in the metadata column family
-    pub fn store_metadata<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
-        let cf_metadata = self.db.cf_handle("metadata")
-            .ok_or_else(|| XLimError::Storage("Metadata column family not found".to_string()))?;
+    /// Migrate document and index keys written in an older layout to the current one
+    fn migrate_key_layout(&self) -> Result<()> {
+        let layout = match self.get_metadata::<u8>(LAYOUT_KEY)? {
+            Some(layout) if layout > CURRENT_LAYOUT => {
+                return Err(XLimError::Storage(format!(
+                    "The database uses key layout {}, but this version only supports up to {}",
+                    layout, CURRENT_LAYOUT,
+                )));
+            }
+            layout => layout.unwrap_or(0),
+        };
         
-        let serialized = bincode::serialize(value)
-            .map_err(|e| XLimError::Storage(format!("Failed to serialize metadata: {}", e)))?;
+        if layout < 1 {
+            self.migrate_document_keys()?;
+            self.store_metadata(LAYOUT_KEY, &1u8)?;
+        }
+        
+        if layout < 2 {
+            self.migrate_index_keys()?;
+        }
+        
+        self.store_metadata(LAYOUT_KEY, &CURRENT_LAYOUT)
+    }
+    
+    /// Give collections created before collection IDs existed an ID, and move their documents
+    /// from the old `{collection}:{document ID}` text keys to keys starting with the ID
+    fn migrate_document_keys(&self) -> Result<()> {
+        let mut names: Vec<String> = self.collections
+            .iter()
+            .filter(|collection| collection.id == 0)
+            .map(|collection| collection.name.clone())
+            .collect();
+        names.sort();
+        
+        for name in names {
+            let id = self.next_collection_id.fetch_add(1, Ordering::SeqCst);
+            
+            if let Some(mut collection) = self.collections.get_mut(&name) {
+                collection.id = id;
+                
+                let mut batch = WriteBatch::default();
+                batch.put(Keyspace::Collections, name.as_bytes(), codec::encode(&*collection)?);
+                
+                self.backend.write(batch, false)?;
+            }
+        }
         
-        self.db.put_cf(&cf_metadata, key.as_bytes(), serialized)
-            .map_err(|e| XLimError::Storage(format!("Failed to store metadata: {}", e)))?;
+        self.store_metadata(NEXT_COLLECTION_ID_KEY, &self.next_collection_id.load(Ordering::SeqCst))?;
+        
+        let mut batch = WriteBatch::default();
+        let mut migrated = 0;
+        
+        for item in self.backend.scan(Keyspace::Documents, &[], &[], Direction::Forward, None)? {
+            let (key, value) = item?;
+            
+            let Some((collection_name, document_id)) = std::str::from_utf8(&key).ok().and_then(|key| key.rsplit_once(':')) else {
+                continue;
+            };
+            
+            let Ok(document_id) = Uuid::parse_str(document_id) else {
+                continue;
+            };
+            
+            let Ok(new_key) = self.document_key(collection_name, &document_id) else {
+                warn!("Leaving document {} of missing collection {} in place", document_id, collection_name);
+                continue;
+            };
+            
+            batch.put(Keyspace::Documents, new_key, value);
+            batch.delete(Keyspace::Documents, key);
+            migrated += 1;
+            
+            if batch.len() >= MIGRATION_BATCH_SIZE {
+                self.backend.write(std::mem::take(&mut batch), false)?;
+            }
+        }
+        
+        self.backend.write(batch, false)?;
+        
+        if migrated > 0 {
+            info!("Moved {} documents to keys starting with their collection's ID", migrated);
+        }
         
         Ok(())
     }
     
-    /// Get a value from the metadata column family
-    pub fn get_metadata<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
-        let cf_metadata = self.db.cf_handle("metadata")
-            .ok_or_else(|| XLimError::Storage("Metadata column family not found".to_string()))?;
+    /// Rebuild every index, whose entries used to start with their collection's name instead
+    /// of its ID
+    fn migrate_index_keys(&self) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        self.delete_index_entries(&mut batch, &[])?;
         
-        let value = self.db.get_cf(&cf_metadata, key.as_bytes())
-            .map_err(|e| XLimError::Storage(format!("Failed to read metadata: {}", e)))?;
+        let removed = batch.len();
         
-        if let Some(value) = value {
-            let deserialized: T = bincode::deserialize(&value)
-                .map_err(|e| XLimError::Storage(format!("Failed to deserialize metadata: {}", e)))?;
+        for collection in self.collections.iter() {
+            for field in collection.indexes() {
+                self.build_index_entries(&mut batch, &collection, &field)?;
+            }
+        }
+        
+        self.backend.write(batch, false)?;
+        
+        if removed > 0 {
+            info!("Rebuilt indexes that had {} entries keyed by collection name", removed);
+        }
+        
+        Ok(())
+    }
+    
+    /// Finish the commits that were interrupted before their operations were applied, and
+    /// remove the records of completed ones, returning how many commits were finished.
+    ///
+    /// A pending record was written after its transaction passed every check, and nothing
+    /// else was written after it, so its operations can be replayed as they were.
+    fn recover_commits(&self) -> Result<usize> {
+        let mut records = Vec::new();
+        let prefix = COMMIT_PREFIX.as_bytes();
+        
+        for item in self.backend.scan(Keyspace::Metadata, prefix, prefix, Direction::Forward, None)? {
+            let (_, value) = item?;
+            // Records written before formats were versioned were never migrated
+            let record: CommitRecord = codec::decode_unversioned(&value)?;
             
-            Ok(Some(deserialized))
-        } else {
-            Ok(None)
+            records.push(record);
+        }
+        
+        let _index_guard = self.index_lock.read().unwrap();
+        let _write_guard = self.write_lock.lock().unwrap();
+        let mut recovered = 0;
+        
+        for record in records {
+            if record.state == CommitState::Applied {
+                self.delete_metadata(&commit_key(&record.transaction_id))?;
+                continue;
+            }
+            
+            match self.operations_batch(&record.operations) {
+                Ok(batch) => {
+                    info!("Replaying interrupted commit of transaction {}", record.transaction_id);
+                    self.finish_commit(batch, record, true)?;
+                    recovered += 1;
+                }
+                Err(e) => {
+                    error!("Discarding interrupted commit of transaction {}: {}", record.transaction_id, e);
+                    self.delete_metadata(&commit_key(&record.transaction_id))?;
+                }
+            }
         }
+        
+        Ok(recovered)
     }
     
-    /// Delete a value from the metadata column family
-    pub fn delete_metadata(&self, key: &str) -> Result<()> {
-        let cf_metadata = self.db.cf_handle("metadata")
-            .ok_or_else(|| XLimError::Storage("Metadata column family not found".to_string()))?;
+    /// List all documents in a collection.
+    ///
+    /// Every document is loaded into memory; use [`StorageEngine::cursor`] for large collections.
+    pub fn list_documents(&self, collection_name: &str) -> Result<Vec<Document>> {
+        let mut documents = Vec::new();
+        
+        self.scan_documents(collection_name, None, &mut |document| {
+            documents.push(document);
+            true
+        })?;
+        
+        Ok(documents)
+    }
+    
+    /// Create a secondary index on a field and build it from the existing documents
+    pub fn create_index(&self, collection_name: &str, field: &str) -> Result<()> {
+        let _index_guard = self.index_lock.write().unwrap();
+        
+        let mut collection = self.get_collection(collection_name)?;
+        let mut indexes = collection.indexes();
+        
+        if indexes.iter().any(|f| f == field) {
+            return Err(XLimError::InvalidOperation(format!("Index on '{}' already exists in collection '{}'", field, collection_name)));
+        }
+        
+        let mut batch = WriteBatch::default();
+        self.build_index_entries(&mut batch, &collection, field)?;
+        
+        // Record the index in the collection so it is reloaded on startup
+        indexes.push(field.to_string());
+        collection.set_metadata("indexes", indexes);
+        
+        let serialized = codec::encode(&collection)?;
+        
+        batch.put(Keyspace::Collections, collection_name, serialized);
+        
+        self.backend.write(batch, false)?;
+        
+        self.collections.insert(collection_name.to_string(), collection);
+        
+        info!("Created index on {} in collection {}", field, collection_name);
+        
+        Ok(())
+    }
+    
+    /// Drop a secondary index and all of its entries
+    pub fn drop_index(&self, collection_name: &str, field: &str) -> Result<()> {
+        let _index_guard = self.index_lock.write().unwrap();
+        
+        let mut collection = self.get_collection(collection_name)?;
+        let mut indexes = collection.indexes();
+        
+        if !indexes.iter().any(|f| f == field) {
+            return Err(XLimError::InvalidOperation(format!("No index on '{}' in collection '{}'", field, collection_name)));
+        }
+        
+        indexes.retain(|f| f != field);
+        collection.set_metadata("indexes", indexes);
+        
+        let mut batch = WriteBatch::default();
+        batch.put(Keyspace::Collections, collection_name, codec::encode(&collection)?);
+        self.delete_index_entries(&mut batch, &index::index_prefix(collection.id, field))?;
         
-        self.db.delete_cf(&cf_metadata, key.as_bytes())
-            .map_err(|e| XLimError::Storage(format!("Failed to delete metadata: {}", e)))?;
+        self.backend.write(batch, false)?;
+        
+        self.collections.insert(collection_name.to_string(), collection);
+        
+        info!("Dropped index on {} in collection {}", field, collection_name);
+        
+        Ok(())
+    }
+    
+    /// Set the TTL policy that a collection's documents expire under, or remove it with `None`.
+    ///
+    /// Documents that have expired under the new policy are hidden at once, and purged later.
+    pub fn set_ttl(&self, collection_name: &str, policy: Option<TtlPolicy>) -> Result<()> {
+        let _index_guard = self.index_lock.write().unwrap();
+        
+        let mut collection = self.get_collection(collection_name)?;
+        
+        match &policy {
+            Some(policy) => {
+                policy.validate()?;
+                collection.set_metadata(TTL_METADATA_KEY, serde_json::to_value(policy)?);
+            }
+            None => {
+                collection.remove_metadata(TTL_METADATA_KEY);
+            }
+        }
+        
+        let mut batch = WriteBatch::default();
+        batch.put(Keyspace::Collections, collection_name, codec::encode(&collection)?);
+        
+        self.backend.write(batch, false)?;
+        
+        self.collections.insert(collection_name.to_string(), collection);
+        
+        match policy {
+            Some(policy) => info!("Documents in collection {} now expire {} seconds after {}", collection_name, policy.expire_after_secs, policy.field),
+            None => info!("Removed the TTL policy of collection {}", collection_name),
+        }
         
         Ok(())
     }
-} 
\ No newline at end of file
+    
+    /// Delete every expired document, returning how many there were.
+    ///
+    /// Reads already skip expired documents, so this only frees the space they take up.
+    /// Collections without a TTL policy are skipped unless a document with its own expiry
+    /// time has been written to them, and documents that cannot be decoded are left alone.
+    pub fn purge_expired(&self) -> Result<usize> {
+        let names: Vec<String> = self.collections.iter().map(|collection| collection.key().clone()).collect();
+        let mut purged = 0;
+        
+        for name in names {
+            let Ok(collection) = self.get_collection(&name) else {
+                continue;
+            };
+            
+            let taken = {
+                // Writes either land before the scan below or mark the collection again
+                let _write_guard = self.write_lock.lock().unwrap();
+                self.expiring.lock().unwrap().remove(&collection.id)
+            };
+            
+            if !taken && collection.ttl().is_none() {
+                continue;
+            }
+            
+            match self.purge_collection(&collection) {
+                Ok((count, still_expiring)) => {
+                    purged += count;
+                    
+                    if still_expiring {
+                        self.expiring.lock().unwrap().insert(collection.id);
+                    }
+                }
+                Err(e) => {
+                    self.expiring.lock().unwrap().insert(collection.id);
+                    return Err(e);
+                }
+            }
+        }
+        
+        Ok(purged)
+    }
+    
+    /// Delete the expired documents of a collection, returning how many there were and
+    /// whether any documents left have their own expiry time
+    fn purge_collection(&self, collection: &Collection) -> Result<(usize, bool)> {
+        let expiry = Expiry::of(collection);
+        let prefix = collection.id.to_be_bytes();
+        let mut expired = Vec::new();
+        let mut purged = 0;
+        let mut still_expiring = false;
+        
+        for item in self.backend.scan(Keyspace::Documents, &prefix, &prefix, Direction::Forward, None)? {
+            let (key, value) = item?;
+            
+            let document: Document = match codec::decode(&value) {
+                Ok(document) => document,
+                Err(e) => {
+                    let document_id = Uuid::from_slice(&key[COLLECTION_ID_LENGTH..]).unwrap_or_default();
+                    warn!("Skipping undecodable document {} in collection {}: {}", document_id, collection.name, e);
+                    continue;
+                }
+            };
+            
+            if expiry.is_expired(&document) {
+                expired.push(key.into_vec());
+            } else if document.expires_at.is_some() {
+                still_expiring = true;
+            }
+            
+            if expired.len() >= PURGE_BATCH_SIZE {
+                purged += self.delete_expired(&collection.name, &expiry, std::mem::take(&mut expired))?;
+            }
+        }
+        
+        purged += self.delete_expired(&collection.name, &expiry, expired)?;
+        
+        Ok((purged, still_expiring))
+    }
+    
+    /// Remember that a collection may hold documents that expire, if a document written to it
+    /// has its own expiry time
+    fn track_expiry(&self, collection_name: &str, document: &Document) {
+        if document.expires_at.is_none() {
+            return;
+        }
+        
+        if let Some(collection) = self.collections.get(collection_name) {
+            self.expiring.lock().unwrap().insert(collection.id);
+        }
+    }
+    
+    /// Delete the documents at a list of storage keys that are still expired, with their index
+    /// entries, returning how many were deleted
+    fn delete_expired(&self, collection_name: &str, expiry: &Expiry, keys: Vec<Vec<u8>>) -> Result<usize> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        
+        let _index_guard = self.index_lock.read().unwrap();
+        let _write_guard = self.write_lock.lock().unwrap();
+        
+        let mut batch = WriteBatch::default();
+        let mut deleted = 0;
+        
+        for key in keys {
+            // The document may have been replaced or deleted since it was found
+            let Some(document) = self.read_document(&key, None)? else {
+                continue;
+            };
+            
+            if !expiry.is_expired(&document) {
+                continue;
+            }
+            
+            self.update_index_entries(&mut batch, collection_name, Some(&document), None)?;
+            batch.delete(Keyspace::Documents, key);
+            deleted += 1;
+        }
+        
+        self.backend.write(batch, false)?;
+        
+        debug!("Purged {} expired documents from collection {}", deleted, collection_name);
+        
+        Ok(deleted)
+    }
+    
+    /// Find the documents in a collection that match a query
+    pub fn find_documents(&self, collection_name: &str, query: &Query) -> Result<Vec<Document>> {
+        let (documents, _) = self.run_query(collection_name, query, None, &HashMap::new())?;
+        Ok(documents)
+    }
+    
+    /// Run a query and return the plan that was used, with its statistics
+    pub fn explain_query(&self, collection_name: &str, query: &Query) -> Result<QueryPlan> {
+        let (_, mut plan) = self.run_query(collection_name, query, None, &HashMap::new())?;
+        
+        if plan.access == Access::CollectionScan {
+            plan.estimated_documents = self.count_documents(collection_name)?;
+        }
+        
+        Ok(plan)
+    }
+    
+    /// Take a snapshot that later reads can be made from
+    pub fn snapshot(&self) -> Snapshot {
+        self.backend.snapshot()
+    }
+    
+    /// Back up the database into a backup directory without stopping reads or writes.
+    ///
+    /// The backup holds every write committed before it began, including the commit records
+    /// of transactions, which are recovered as usual when a restored database is opened.
+    pub fn create_backup(&self, dir: &Path) -> Result<BackupInfo> {
+        let backup = self.backend.create_backup(dir)?;
+        
+        info!("Created backup {} in {} ({} files, {} bytes)", backup.id, dir.display(), backup.files, backup.size);
+        
+        Ok(backup)
+    }
+    
+    /// Get a document as it was when a snapshot was taken
+    pub fn get_document_at(&self, snapshot: &Snapshot, collection_name: &str, document_id: &str) -> Result<Document> {
+        let key = self.document_key(collection_name, &parse_document_id(document_id)?)?;
+        
+        self.read_live_document(collection_name, &key, Some(snapshot))?
+            .ok_or_else(|| XLimError::DocumentNotFound(document_id.to_string()))
+    }
+    
+    /// Check whether a document has been written, or deleted, since a snapshot was taken
+    pub fn changed_since(&self, snapshot: &Snapshot, collection_name: &str, document_id: &Uuid) -> Result<bool> {
+        let key = self.document_key(collection_name, document_id)?;
+        
+        let then = self.backend.get(Keyspace::Documents, &key, Some(snapshot))?;
+        let now = self.backend.get(Keyspace::Documents, &key, None)?;
+        
+        Ok(then != now)
+    }
+    
+    /// Find the documents that matched a query when a snapshot was taken.
+    ///
+    /// `overlay` holds documents changed since the snapshot, by ID, with `None` for deleted
+    /// ones; they replace the versions in the snapshot.
+    pub fn find_documents_at(&self, snapshot: &Snapshot, collection_name: &str, query: &Query, overlay: &HashMap<Uuid, Option<Document>>) -> Result<Vec<Document>> {
+        let (documents, _) = self.run_query(collection_name, query, Some(snapshot), overlay)?;
+        Ok(documents)
+    }
+    
+    /// Plan and run a query, optionally against a snapshot overlaid with changed documents.
+    ///
+    /// Documents read through an index are still checked against the whole query,
+    /// because index keys compare numbers as `f64` and may match a few extra documents.
+    fn run_query(&self, collection_name: &str, query: &Query, snapshot: Option<&Snapshot>, overlay: &HashMap<Uuid, Option<Document>>) -> Result<(Vec<Document>, QueryPlan)> {
+        query.validate()?;
+        
+        let collection = self.get_collection(collection_name)?;
+        let expiry = Expiry::of(&collection);
+        
+        let mut plan = planner::plan(collection.id, query, &collection.indexes(), |ranges, limit| {
+            self.count_index_entries(ranges, limit, snapshot)
+        })?;
+        
+        // Changed documents are matched up front, and their stored versions skipped below
+        let mut matches: Vec<Document> = overlay
+            .values()
+            .flatten()
+            .filter(|document| !expiry.is_expired(document) && query.matches(document).unwrap_or(false))
+            .cloned()
+            .collect();
+        let overlay_matches = matches.len();
+        
+        // Scanning can stop as soon as enough stored matches have been found. Changed documents
+        // may sort anywhere among them, so they do not count towards the limit.
+        let match_limit = plan.match_limit(query);
+        let mut scanned = 0;
+        
+        let mut collect = |document: Document| {
+            if overlay.contains_key(&document.id) {
+                return true;
+            }
+            
+            scanned += 1;
+            
+            if query.matches(&document).unwrap_or(false) {
+                matches.push(document);
+            }
+            
+            match_limit.is_none_or(|limit| matches.len() - overlay_matches < limit)
+        };
+        
+        if plan.access == Access::CollectionScan {
+            self.scan_documents(collection_name, snapshot, &mut collect)?;
+        } else {
+            let mut ranges = plan.access.key_ranges(collection.id);
+            let reverse = plan.is_reverse(query);
+            
+            if reverse {
+                ranges.reverse();
+            }
+            
+            for (start, end) in &ranges {
+                if !self.scan_index(collection_name, start, end, reverse, snapshot, &mut collect)? {
+                    break;
+                }
+            }
+        }
+        
+        plan.scanned_documents = scanned;
+        
+        let results = query.finish(matches)?;
+        plan.returned_documents = results.len();
+        
+        Ok((results, plan))
+    }
+    
+    /// Open a cursor over the live documents of a collection, in key order, from a snapshot if
+    /// one is given. With `after`, the cursor starts just past that document, so that a scan
+    /// can be resumed where it left off.
+    pub fn cursor<'a>(&'a self, collection_name: &str, after: Option<&Uuid>, snapshot: Option<&'a Snapshot>) -> Result<DocumentCursor<'a>> {
+        let collection = self.get_collection(collection_name)?;
+        let collection_id = collection.id;
+        let prefix = collection_id.to_be_bytes();
+        
+        let start = match after {
+            Some(document_id) => document_key(collection_id, document_id),
+            None => prefix.to_vec(),
+        };
+        
+        let iter = self.backend.scan(Keyspace::Documents, &prefix, &start, Direction::Forward, snapshot)?;
+        
+        Ok(DocumentCursor {
+            iter,
+            skip: after.map(|_| start),
+            expiry: Expiry::of(&collection),
+            done: false,
+        })
+    }
+    
+    /// Pass every document of a collection to `visit` until it returns false
+    fn scan_documents<F: FnMut(Document) -> bool>(&self, collection_name: &str, snapshot: Option<&Snapshot>, visit: &mut F) -> Result<()> {
+        for document in self.cursor(collection_name, None, snapshot)? {
+            if !visit(document?) {
+                break;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Pass the live documents referenced by the index entries in `[start, end)` to `visit`.
+    ///
+    /// Returns false if `visit` asked to stop.
+    fn scan_index<F: FnMut(Document) -> bool>(&self, collection_name: &str, start: &[u8], end: &[u8], reverse: bool, snapshot: Option<&Snapshot>, visit: &mut F) -> Result<bool> {
+        let collection = self.get_collection(collection_name)?;
+        let collection_id = collection.id;
+        let expiry = Expiry::of(&collection);
+        
+        let iter = match reverse {
+            false => self.backend.scan(Keyspace::Indexes, &[], start, Direction::Forward, snapshot)?,
+            true => self.backend.scan(Keyspace::Indexes, &[], end, Direction::Reverse, snapshot)?,
+        };
+        
+        for item in iter {
+            let (key, _) = item?;
+            
+            if &*key >= end {
+                // Only a reverse scan can start at or past the end
+                continue;
+            }
+            
+            if &*key < start {
+                break;
+            }
+            
+            let Some(document_id) = index::document_id(&key) else {
+                continue;
+            };
+            
+            // The document may have been deleted since the index entry was read
+            if let Some(document) = self.read_document(&document_key(collection_id, &document_id), snapshot)? {
+                if expiry.is_expired(&document) {
+                    continue;
+                }
+                
+                if !visit(document) {
+                    return Ok(false);
+                }
+            }
+        }
+        
+        Ok(true)
+    }
+    
+    /// Count the index entries in a list of key ranges, stopping at `limit`
+    fn count_index_entries(&self, ranges: &[(Vec<u8>, Vec<u8>)], limit: usize, snapshot: Option<&Snapshot>) -> Result<usize> {
+        let mut count = 0;
+        
+        for (start, end) in ranges {
+            for item in self.backend.scan(Keyspace::Indexes, &[], start, Direction::Forward, snapshot)? {
+                if count >= limit {
+                    return Ok(count);
+                }
+                
+                let (key, _) = item?;
+                
+                if &*key >= end.as_slice() {
+                    break;
+                }
+                
+                count += 1;
+            }
+        }
+        
+        Ok(count)
+    }
+    
+    /// Count the documents in a collection
+    fn count_documents(&self, collection_name: &str) -> Result<usize> {
+        let mut count = 0;
+        
+        self.scan_documents(collection_name, None, &mut |_| {
+            count += 1;
+            true
+        })?;
+        
+        Ok(count)
+    }
+    
+    /// Build the storage key of a document in a collection
+    fn document_key(&self, collection_name: &str, document_id: &Uuid) -> Result<Vec<u8>> {
+        let collection = self.collections.get(collection_name)
+            .ok_or_else(|| XLimError::CollectionNotFound(collection_name.to_string()))?;
+        
+        Ok(document_key(collection.id, document_id))
+    }
+    
+    /// Read and deserialize a document by its storage key, from a snapshot if one is given
+    fn read_document(&self, key: &[u8], snapshot: Option<&Snapshot>) -> Result<Option<Document>> {
+        match self.backend.get(Keyspace::Documents, key, snapshot)? {
+            Some(value) => {
+                let document = codec::decode(&value)?;
+                
+                Ok(Some(document))
+            }
+            None => Ok(None),
+        }
+    }
+    
+    /// Read a document by its storage key like [`StorageEngine::read_document`], treating it as
+    /// missing if it has expired
+    fn read_live_document(&self, collection_name: &str, key: &[u8], snapshot: Option<&Snapshot>) -> Result<Option<Document>> {
+        let expiry = Expiry::of(&self.get_collection(collection_name)?);
+        
+        Ok(self.read_document(key, snapshot)?.filter(|document| !expiry.is_expired(document)))
+    }
+    
+    /// Add the index changes that replace `old` with `new` to a write batch
+    fn update_index_entries(&self, batch: &mut WriteBatch, collection_name: &str, old: Option<&Document>, new: Option<&Document>) -> Result<()> {
+        let (collection_id, indexes) = match self.collections.get(collection_name) {
+            Some(collection) => (collection.id, collection.indexes()),
+            None => return Ok(()),
+        };
+        
+        if indexes.is_empty() {
+            return Ok(());
+        }
+        
+        for field in &indexes {
+            if let Some((document, value)) = old.and_then(|d| d.get(field).map(|v| (d, v))) {
+                batch.delete(Keyspace::Indexes, index::index_key(collection_id, field, value, &document.id));
+            }
+            
+            if let Some((document, value)) = new.and_then(|d| d.get(field).map(|v| (d, v))) {
+                batch.put(Keyspace::Indexes, index::index_key(collection_id, field, value, &document.id), []);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Add the entries of an index on every stored document of a collection to a batch,
+    /// including documents that have expired but not been purged yet, which the index must
+    /// still cover in case their expiry is lifted
+    fn build_index_entries(&self, batch: &mut WriteBatch, collection: &Collection, field: &str) -> Result<()> {
+        let prefix = collection.id.to_be_bytes();
+        
+        for item in self.backend.scan(Keyspace::Documents, &prefix, &prefix, Direction::Forward, None)? {
+            let (_, value) = item?;
+            let document: Document = codec::decode(&value)?;
+            
+            if let Some(value) = document.get(field) {
+                batch.put(Keyspace::Indexes, index::index_key(collection.id, field, value, &document.id), []);
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Add the deletion of every index entry whose key starts with a prefix to a batch
+    fn delete_index_entries(&self, batch: &mut WriteBatch, prefix: &[u8]) -> Result<()> {
+        for item in self.backend.scan(Keyspace::Indexes, prefix, prefix, Direction::Forward, None)? {
+            let (key, _) = item?;
+            
+            batch.delete(Keyspace::Indexes, key);
+        }
+        
+        Ok(())
+    }
+    
+    /// Store a value in the metadata keyspace
+    pub fn store_metadata<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        let serialized = bincode::serialize(value)
+            .map_err(|e| XLimError::Storage(format!("Failed to serialize metadata: {}", e)))?;
+        
+        let mut batch = WriteBatch::default();
+        batch.put(Keyspace::Metadata, key, serialized);
+        
+        self.backend.write(batch, false)
+    }
+    
+    /// Get a value from the metadata keyspace
+    pub fn get_metadata<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let value = self.backend.get(Keyspace::Metadata, key.as_bytes(), None)?;
+        
+        if let Some(value) = value {
+            let deserialized: T = bincode::deserialize(&value)
+                .map_err(|e| XLimError::Storage(format!("Failed to deserialize metadata: {}", e)))?;
+            
+            Ok(Some(deserialized))
+        } else {
+            Ok(None)
+        }
+    }
+    
+    /// Delete a value from the metadata keyspace
+    pub fn delete_metadata(&self, key: &str) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.delete(Keyspace::Metadata, key);
+        
+        self.backend.write(batch, false)
+    }
+}
+
+/// Build the storage key of a document: its collection's ID, big-endian, then its own ID
+fn document_key(collection_id: u64, document_id: &Uuid) -> Vec<u8> {
+    let mut key = Vec::with_capacity(COLLECTION_ID_LENGTH + 16);
+    key.extend_from_slice(&collection_id.to_be_bytes());
+    key.extend_from_slice(document_id.as_bytes());
+    key
+}
+
+/// Parse a document ID given as text, which cannot name an existing document if it is invalid
+fn parse_document_id(document_id: &str) -> Result<Uuid> {
+    Uuid::parse_str(document_id).map_err(|_| XLimError::DocumentNotFound(document_id.to_string()))
+}
+
+/// Metadata key of a transaction's commit record
+fn commit_key(transaction_id: &Uuid) -> String {
+    format!("{}{}", COMMIT_PREFIX, transaction_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeDelta, Utc};
+    use serde_json::{json, Value};
+    
+    /// Values of a field in a list of documents
+    fn values(documents: &[Document], field: &str) -> Vec<Value> {
+        documents.iter().map(|document| document.get(field).cloned().unwrap_or(Value::Null)).collect()
+    }
+    
+    #[test]
+    fn explain_reports_the_documents_a_plan_reads() {
+        let storage = StorageEngine::in_memory().unwrap();
+        storage.create_collection("c").unwrap();
+        storage.create_index("c", "x").unwrap();
+        
+        for x in 0..50 {
+            storage.insert_document("c", &Document::new().set("x", x).set("m", x % 5)).unwrap();
+        }
+        
+        let lookup = storage.explain_query("c", &Query::new().filter("x", "=", 7).unwrap()).unwrap();
+        assert!(matches!(lookup.access, Access::IndexLookup { .. }));
+        assert_eq!((lookup.scanned_documents, lookup.returned_documents), (1, 1));
+        
+        let query = Query::new().filter("x", ">", 10).unwrap().filter("x", "<=", 20).unwrap().filter("m", "=", 0).unwrap();
+        let range = storage.explain_query("c", &query).unwrap();
+        assert!(matches!(range.access, Access::IndexRange { .. }));
+        assert_eq!((range.scanned_documents, range.returned_documents), (10, 2));
+        assert_eq!(values(&storage.find_documents("c", &query).unwrap(), "x"), vec![json!(15), json!(20)]);
+        
+        let query = Query::new().filter("x", ">=", 0).unwrap().sort("x", false).limit(3);
+        let sorted = storage.explain_query("c", &query).unwrap();
+        assert!(sorted.sort_from_index);
+        assert_eq!(sorted.scanned_documents, 3);
+        assert_eq!(values(&storage.find_documents("c", &query).unwrap(), "x"), vec![json!(49), json!(48), json!(47)]);
+        
+        let scan = storage.explain_query("c", &Query::new().filter("m", "=", 1).unwrap()).unwrap();
+        assert_eq!(scan.access, Access::CollectionScan);
+        assert_eq!((scan.estimated_documents, scan.scanned_documents, scan.returned_documents), (50, 50, 10));
+    }
+    
+    #[test]
+    fn inserts_do_not_replace_documents() {
+        let storage = StorageEngine::in_memory().unwrap();
+        storage.create_collection("c").unwrap();
+        
+        let document = Document::new().set("x", 1);
+        storage.insert_document("c", &document).unwrap();
+        
+        let replacement = document.clone().set("x", 2);
+        assert!(matches!(storage.insert_document("c", &replacement), Err(XLimError::InvalidOperation(_))));
+        
+        let insert = Operation {
+            op_type: OperationType::Insert,
+            collection: "c".to_string(),
+            document_id: document.id,
+            document: Some(replacement.clone()),
+        };
+        
+        assert!(storage.apply_operations(Uuid::new_v4(), &[insert], false, || Ok(())).is_err());
+        assert_eq!(storage.get_document("c", &document.id.to_string()).unwrap().get("x"), Some(&json!(1)));
+        
+        // Only an explicit replace rewrites it
+        let result = storage.insert_many("c", &[replacement], OnConflict::Replace).unwrap();
+        
+        assert_eq!((result.inserted, result.replaced), (0, 1));
+        assert_eq!(storage.get_document("c", &document.id.to_string()).unwrap().get("x"), Some(&json!(2)));
+    }
+    
+    /// An operation of a transaction on a document
+    fn operation(op_type: OperationType, document: &Document) -> Operation {
+        Operation {
+            op_type,
+            collection: "c".to_string(),
+            document_id: document.id,
+            document: (op_type != OperationType::Delete).then(|| document.clone()),
+        }
+    }
+    
+    #[test]
+    fn commits_apply_every_operation_or_none() {
+        let storage = StorageEngine::in_memory().unwrap();
+        storage.create_collection("c").unwrap();
+        storage.create_index("c", "x").unwrap();
+        
+        let a = Document::new().set("x", 1);
+        let missing = Document::new().set("x", 2);
+        
+        // The update of a missing document fails the insert before it too
+        let operations = [operation(OperationType::Insert, &a), operation(OperationType::Update, &missing)];
+        let result = storage.apply_operations(Uuid::new_v4(), &operations, false, || Ok(()));
+        
+        assert!(matches!(result, Err(XLimError::DocumentNotFound(_))));
+        assert!(keys(&storage, Keyspace::Documents).is_empty());
+        assert!(keys(&storage, Keyspace::Indexes).is_empty());
+        
+        // So does a refused validation
+        let operations = [operation(OperationType::Insert, &a)];
+        let result = storage.apply_operations(Uuid::new_v4(), &operations, false, || Err(XLimError::Conflict("refused".to_string())));
+        
+        assert!(result.is_err());
+        assert!(keys(&storage, Keyspace::Documents).is_empty());
+        
+        let b = Document::new().set("x", 3);
+        let operations = [
+            operation(OperationType::Insert, &a),
+            operation(OperationType::Update, &a.clone().set("x", 5)),
+            operation(OperationType::Insert, &b),
+            operation(OperationType::Delete, &b),
+        ];
+        
+        storage.apply_operations(Uuid::new_v4(), &operations, true, || Ok(())).unwrap();
+        
+        // Only the final version of each document is indexed
+        let collection = storage.get_collection("c").unwrap();
+        
+        assert_eq!(keys(&storage, Keyspace::Indexes), vec![index::index_key(collection.id, "x", &json!(5), &a.id)]);
+        assert_eq!(values(&storage.list_documents("c").unwrap(), "x"), vec![json!(5)]);
+        
+        // No commit record is left behind, whether the commit succeeded or not
+        let prefix = COMMIT_PREFIX.as_bytes();
+        let records = storage.backend.scan(Keyspace::Metadata, prefix, prefix, Direction::Forward, None).unwrap().count();
+        
+        assert_eq!(records, 0);
+    }
+    
+    #[test]
+    fn interrupted_commits_are_finished_on_open() {
+        let backend = Arc::new(MemoryBackend::new());
+        let storage = StorageEngine::with_backend(backend.clone()).unwrap();
+        storage.create_collection("c").unwrap();
+        storage.create_index("c", "x").unwrap();
+        
+        let a = Document::new().set("x", 1);
+        storage.insert_document("c", &a).unwrap();
+        
+        let b = Document::new().set("x", 2);
+        let records = [
+            (CommitState::Pending, vec![operation(OperationType::Update, &a.clone().set("x", 5)), operation(OperationType::Insert, &b)]),
+            // Already applied, so only its record is left to remove
+            (CommitState::Applied, vec![operation(OperationType::Delete, &a)]),
+            // Can no longer be applied, and is discarded
+            (CommitState::Pending, vec![operation(OperationType::Insert, &a.clone().set("x", 7))]),
+        ];
+        
+        for (state, operations) in records {
+            let record = CommitRecord { transaction_id: Uuid::new_v4(), operations, state };
+            storage.write_commit_record(None, &record, true).unwrap();
+        }
+        
+        drop(storage);
+        let storage = StorageEngine::with_backend(backend).unwrap();
+        
+        let query = Query::new().filter("x", ">", 0).unwrap().sort("x", true);
+        assert_eq!(values(&storage.find_documents("c", &query).unwrap(), "x"), vec![json!(2), json!(5)]);
+        assert_eq!(keys(&storage, Keyspace::Indexes).len(), 2);
+        
+        let prefix = COMMIT_PREFIX.as_bytes();
+        let records = storage.backend.scan(Keyspace::Metadata, prefix, prefix, Direction::Forward, None).unwrap().count();
+        
+        assert_eq!(records, 0);
+    }
+    
+    #[test]
+    fn changed_documents_do_not_count_towards_the_limit() {
+        let storage = StorageEngine::in_memory().unwrap();
+        storage.create_collection("c").unwrap();
+        storage.create_index("c", "x").unwrap();
+        
+        for x in 1..=3 {
+            storage.insert_document("c", &Document::new().set("x", x)).unwrap();
+        }
+        
+        let snapshot = storage.snapshot();
+        let inserted = Document::new().set("x", 100);
+        let overlay = HashMap::from([(inserted.id, Some(inserted))]);
+        
+        let ascending = Query::new().filter("x", ">", 0).unwrap().sort("x", true).limit(2);
+        let documents = storage.find_documents_at(&snapshot, "c", &ascending, &overlay).unwrap();
+        
+        assert_eq!(values(&documents, "x"), vec![json!(1), json!(2)]);
+        
+        let descending = Query::new().filter("x", ">", 0).unwrap().sort("x", false).limit(2);
+        let documents = storage.find_documents_at(&snapshot, "c", &descending, &overlay).unwrap();
+        
+        assert_eq!(values(&documents, "x"), vec![json!(100), json!(3)]);
+    }
+    
+    /// Keys of every entry in a keyspace
+    fn keys(storage: &StorageEngine, keyspace: Keyspace) -> Vec<Vec<u8>> {
+        storage.backend
+            .scan(keyspace, &[], &[], Direction::Forward, None)
+            .unwrap()
+            .map(|item| item.unwrap().0.to_vec())
+            .collect()
+    }
+    
+    #[test]
+    fn a_collection_is_created_only_once() {
+        let storage = Arc::new(StorageEngine::in_memory().unwrap());
+        
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let storage = storage.clone();
+                std::thread::spawn(move || storage.create_collection("c"))
+            })
+            .collect();
+        
+        let results: Vec<_> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        let created: Vec<_> = results.iter().filter_map(|result| result.as_ref().ok()).collect();
+        
+        assert_eq!(created.len(), 1);
+        assert!(results.iter().all(|result| result.is_ok() || matches!(result, Err(XLimError::InvalidOperation(_)))));
+        assert_eq!(storage.get_collection("c").unwrap().id, created[0].id);
+        assert_eq!(keys(&storage, Keyspace::Collections).len(), 1);
+    }
+    
+    #[test]
+    fn deleting_a_collection_removes_its_index_entries() {
+        let storage = StorageEngine::in_memory().unwrap();
+        storage.create_collection("c").unwrap();
+        storage.create_index("c", "x").unwrap();
+        storage.create_collection("other").unwrap();
+        storage.create_index("other", "x").unwrap();
+        
+        storage.insert_document("c", &Document::new().set("x", 1)).unwrap();
+        storage.insert_document("other", &Document::new().set("x", 1)).unwrap();
+        
+        storage.delete_collection("c").unwrap();
+        
+        let other = storage.get_collection("other").unwrap();
+        
+        assert_eq!(keys(&storage, Keyspace::Indexes).len(), 1);
+        assert!(keys(&storage, Keyspace::Indexes)[0].starts_with(&index::collection_prefix(other.id)));
+        assert_eq!(keys(&storage, Keyspace::Documents).len(), 1);
+        
+        // A new collection with the same name starts out empty
+        storage.create_collection("c").unwrap();
+        storage.create_index("c", "x").unwrap();
+        
+        let query = Query::new().filter("x", "=", 1).unwrap();
+        
+        assert!(storage.find_documents("c", &query).unwrap().is_empty());
+    }
+    
+    #[test]
+    fn index_entries_keyed_by_collection_name_are_rebuilt() {
+        let backend = Arc::new(MemoryBackend::new());
+        let document = Document::new().set("x", 7);
+        
+        {
+            let storage = StorageEngine::with_backend(backend.clone()).unwrap();
+            storage.create_collection("c").unwrap();
+            storage.create_index("c", "x").unwrap();
+            storage.insert_document("c", &document).unwrap();
+        }
+        
+        // Go back to index entries that start with the collection's name
+        let mut batch = WriteBatch::default();
+        
+        for key in keys(&StorageEngine::with_backend(backend.clone()).unwrap(), Keyspace::Indexes) {
+            batch.delete(Keyspace::Indexes, key);
+        }
+        
+        batch.put(Keyspace::Indexes, b"c\x00\x01x\x00\x01stale".to_vec(), []);
+        batch.put(Keyspace::Metadata, LAYOUT_KEY, bincode::serialize(&1u8).unwrap());
+        backend.write(batch, false).unwrap();
+        
+        let storage = StorageEngine::with_backend(backend).unwrap();
+        let collection = storage.get_collection("c").unwrap();
+        
+        assert_eq!(keys(&storage, Keyspace::Indexes), vec![index::index_key(collection.id, "x", &json!(7), &document.id)]);
+        assert_eq!(storage.get_metadata::<u8>(LAYOUT_KEY).unwrap(), Some(CURRENT_LAYOUT));
+        
+        let query = Query::new().filter("x", "=", 7).unwrap();
+        
+        assert_eq!(storage.find_documents("c", &query).unwrap().len(), 1);
+    }
+    
+    #[test]
+    fn indexes_cover_expired_documents() {
+        let storage = StorageEngine::in_memory().unwrap();
+        storage.create_collection("c").unwrap();
+        storage.set_ttl("c", Some(TtlPolicy { field: "ts".to_string(), expire_after_secs: 60 })).unwrap();
+        storage.insert_document("c", &Document::new().set("x", 1).set("ts", 0)).unwrap();
+        
+        let query = Query::new().filter("x", "=", 1).unwrap();
+        
+        assert!(storage.find_documents("c", &query).unwrap().is_empty());
+        
+        storage.create_index("c", "x").unwrap();
+        
+        // Lifting the policy brings the document back, and the index must find it
+        storage.set_ttl("c", None).unwrap();
+        
+        let (documents, plan) = storage.run_query("c", &query, None, &HashMap::new()).unwrap();
+        
+        assert!(matches!(plan.access, Access::IndexLookup { .. }));
+        assert_eq!(documents.len(), 1);
+    }
+    
+    #[test]
+    fn purges_skip_undecodable_documents() {
+        let storage = StorageEngine::in_memory().unwrap();
+        let collection = storage.create_collection("c").unwrap();
+        storage.set_ttl("c", Some(TtlPolicy { field: "ts".to_string(), expire_after_secs: 60 })).unwrap();
+        
+        let corrupt = document_key(collection.id, &Uuid::new_v4());
+        let mut batch = WriteBatch::default();
+        batch.put(Keyspace::Documents, corrupt.clone(), b"not a document".to_vec());
+        storage.backend.write(batch, false).unwrap();
+        
+        storage.insert_document("c", &Document::new().set("ts", 0)).unwrap();
+        storage.insert_document("c", &Document::new().set("ts", Utc::now().timestamp())).unwrap();
+        
+        assert_eq!(storage.purge_expired().unwrap(), 1);
+        assert_eq!(keys(&storage, Keyspace::Documents).len(), 2);
+        assert!(keys(&storage, Keyspace::Documents).contains(&corrupt));
+    }
+    
+    #[test]
+    fn purges_scan_only_collections_whose_documents_expire() {
+        let storage = StorageEngine::in_memory().unwrap();
+        let plain = storage.create_collection("plain").unwrap();
+        let expiring = storage.create_collection("expiring").unwrap();
+        let is_tracked = |id| storage.expiring.lock().unwrap().contains(&id);
+        
+        storage.insert_document("plain", &Document::new()).unwrap();
+        
+        let later = Document::new().expire_at(Utc::now() + TimeDelta::hours(1));
+        storage.insert_document("expiring", &later).unwrap();
+        storage.insert_document("expiring", &Document::new().expire_at(Utc::now())).unwrap();
+        
+        assert!(!is_tracked(plain.id));
+        assert!(is_tracked(expiring.id));
+        
+        // The collection is scanned again as long as a document in it can still expire
+        assert_eq!(storage.purge_expired().unwrap(), 1);
+        assert!(is_tracked(expiring.id));
+        
+        let mut kept = later.clone();
+        kept.expires_at = None;
+        storage.update_document("expiring", &kept).unwrap();
+        
+        assert_eq!(storage.purge_expired().unwrap(), 0);
+        assert!(!is_tracked(expiring.id));
+        assert_eq!(keys(&storage, Keyspace::Documents).len(), 2);
+    }
+}
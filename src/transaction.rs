@@ -1,11 +1,24 @@
 use chrono::{DateTime, Utc};
+use log::info;
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 use uuid::Uuid;
 
+use crate::codec::Stored;
 use crate::document::Document;
 use crate::error::{Result, XLimError};
-use crate::storage::StorageEngine;
+use crate::lock::{LockKey, LockManager, LockMode};
+use crate::query::Query;
+use crate::storage::{Snapshot, StorageEngine};
+
+/// How often the reaper looks for expired transactions
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a transaction that timed out is remembered, so that later requests for it get a
+/// timeout error instead of "not found"
+const TIMED_OUT_RETENTION: Duration = Duration::from_secs(600);
 
 /// Transaction operation types
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -34,6 +47,98 @@ pub struct Operation {
     pub document: Option<Document>,
 }
 
+/// Progress of a transaction's commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CommitState {
+    /// The commit was accepted, but its operations may not have been applied yet
+    Pending,
+    /// The operations have been applied
+    Applied,
+}
+
+/// Record of a transaction's commit, written before its operations are applied so that a
+/// commit interrupted by a crash can be finished when the database is opened again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitRecord {
+    /// Transaction ID
+    pub transaction_id: Uuid,
+    
+    /// Operations to apply
+    pub operations: Vec<Operation>,
+    
+    /// Progress of the commit
+    pub state: CommitState,
+}
+
+impl Stored for CommitRecord {
+    /// Commit records were first written as JSON, so none were ever written with bincode
+    type Bincode = CommitRecord;
+}
+
+/// How strictly a transaction is isolated from transactions that commit while it runs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IsolationLevel {
+    /// Reads come from a snapshot, and the commit fails if another commit has changed any
+    /// document the transaction writes
+    #[default]
+    Snapshot,
+    /// Like `Snapshot`, but the commit also fails if another commit has changed any document
+    /// or query result the transaction read
+    Serializable,
+}
+
+/// Options chosen when a transaction begins
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransactionOptions {
+    /// Isolation level
+    pub isolation: IsolationLevel,
+    
+    /// How long the transaction may stay open, instead of the server's default
+    pub timeout: Option<Duration>,
+}
+
+/// Summary of an active transaction, for administrators
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionInfo {
+    /// Transaction ID
+    pub id: Uuid,
+    
+    /// Isolation level
+    pub isolation: IsolationLevel,
+    
+    /// Time since the transaction began
+    pub age: Duration,
+    
+    /// Number of buffered operations
+    pub operations: usize,
+    
+    /// Time left before the transaction is aborted, if it has a timeout
+    pub expires_in: Option<Duration>,
+    
+    /// Number of documents the transaction has locked
+    #[serde(default)]
+    pub locks: usize,
+}
+
+/// A named point in a transaction that it can be rolled back to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Savepoint {
+    /// Name of the savepoint
+    pub name: String,
+    
+    /// Number of operations the transaction had when the savepoint was set
+    pub operations: usize,
+}
+
+/// Something a transaction read, which must not change before it commits if it is serializable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Read {
+    /// A document read by ID
+    Document { collection: String, document_id: Uuid },
+    /// The results of a query
+    Query { collection: String, query: Query },
+}
+
 /// A database transaction
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
@@ -48,6 +153,30 @@ pub struct Transaction {
     
     /// Whether the transaction has been committed
     pub committed: bool,
+    
+    /// Isolation level
+    pub isolation: IsolationLevel,
+    
+    /// What the transaction has read, tracked for serializable transactions
+    pub reads: Vec<Read>,
+    
+    /// Time after which the transaction is aborted
+    pub expires_at: Option<DateTime<Utc>>,
+    
+    /// Connection that began the transaction, which rolls it back when it closes
+    pub owner: Option<u64>,
+    
+    /// Savepoints, oldest first
+    pub savepoints: Vec<Savepoint>,
+    
+    /// View of the database taken when the transaction began, which its reads come from
+    #[serde(skip)]
+    pub snapshot: Option<Arc<Snapshot>>,
+    
+    /// Documents the transaction has locked, with a view of the database taken when each
+    /// lock was granted, which reads of the document come from instead
+    #[serde(skip)]
+    pub locks: HashMap<LockKey, Arc<Snapshot>>,
 }
 
 impl Transaction {
@@ -58,6 +187,13 @@ impl Transaction {
             created_at: Utc::now(),
             operations: Vec::new(),
             committed: false,
+            isolation: IsolationLevel::default(),
+            reads: Vec::new(),
+            expires_at: None,
+            owner: None,
+            savepoints: Vec::new(),
+            snapshot: None,
+            locks: HashMap::new(),
         }
     }
     
@@ -102,6 +238,224 @@ impl Transaction {
         
         self
     }
+    
+    /// Set a savepoint at the current end of the transaction.
+    ///
+    /// A savepoint with the same name as an earlier one hides it until it is released.
+    pub fn savepoint(&mut self, name: &str) -> &mut Self {
+        self.savepoints.push(Savepoint {
+            name: name.to_string(),
+            operations: self.operations.len(),
+        });
+        
+        self
+    }
+    
+    /// Undo every operation since a savepoint was set. The savepoint itself is kept, and
+    /// savepoints set after it are removed.
+    pub fn rollback_to(&mut self, name: &str) -> Result<()> {
+        let index = self.savepoint_index(name)?;
+        
+        self.operations.truncate(self.savepoints[index].operations);
+        self.savepoints.truncate(index + 1);
+        
+        Ok(())
+    }
+    
+    /// Remove a savepoint, and every savepoint set after it, keeping their operations
+    pub fn release(&mut self, name: &str) -> Result<()> {
+        let index = self.savepoint_index(name)?;
+        
+        self.savepoints.truncate(index);
+        
+        Ok(())
+    }
+    
+    /// Find the most recent savepoint with a name
+    fn savepoint_index(&self, name: &str) -> Result<usize> {
+        self.savepoints
+            .iter()
+            .rposition(|savepoint| savepoint.name == name)
+            .ok_or_else(|| XLimError::Transaction(format!("Savepoint not found: {}", name)))
+    }
+    
+    /// Check whether the transaction has run past its timeout
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| Utc::now() >= expires_at)
+    }
+    
+    /// Summarize the transaction
+    pub fn info(&self) -> TransactionInfo {
+        let now = Utc::now();
+        
+        TransactionInfo {
+            id: self.id,
+            isolation: self.isolation,
+            age: (now - self.created_at).to_std().unwrap_or_default(),
+            operations: self.operations.len(),
+            expires_in: self.expires_at.map(|expires_at| (expires_at - now).to_std().unwrap_or_default()),
+            locks: self.locks.len(),
+        }
+    }
+    
+    /// Get a document as the transaction sees it: its own writes over the snapshot
+    pub fn get(&mut self, storage: &StorageEngine, collection: &str, document_id: &str) -> Result<Document> {
+        if let (IsolationLevel::Serializable, Ok(document_id)) = (self.isolation, Uuid::parse_str(document_id)) {
+            self.reads.push(Read::Document {
+                collection: collection.to_string(),
+                document_id,
+            });
+        }
+        
+        self.read(storage, collection, document_id)
+    }
+    
+    /// Get a document as the transaction sees it, without recording the read
+    fn read(&self, storage: &StorageEngine, collection: &str, document_id: &str) -> Result<Document> {
+        let overlay = self.overlay(collection);
+        
+        let id = Uuid::parse_str(document_id).ok();
+        
+        if let Some(change) = id.and_then(|id| overlay.get(&id)) {
+            return change.clone().ok_or_else(|| XLimError::DocumentNotFound(document_id.to_string()));
+        }
+        
+        if let Some(snapshot) = id.and_then(|id| self.locks.get(&(collection.to_string(), id))) {
+            return storage.get_document_at(snapshot, collection, document_id);
+        }
+        
+        match &self.snapshot {
+            Some(snapshot) => storage.get_document_at(snapshot, collection, document_id),
+            None => storage.get_document(collection, document_id),
+        }
+    }
+    
+    /// Find the documents that match a query as the transaction sees them
+    pub fn find(&mut self, storage: &StorageEngine, collection: &str, query: &Query) -> Result<Vec<Document>> {
+        let mut overlay = self.overlay(collection);
+        
+        // Locked documents are seen as they were when locked, like when they are read by ID
+        for ((locked_collection, document_id), snapshot) in &self.locks {
+            if locked_collection == collection && !overlay.contains_key(document_id) {
+                let document = match storage.get_document_at(snapshot, collection, &document_id.to_string()) {
+                    Ok(document) => Some(document),
+                    Err(XLimError::DocumentNotFound(_)) => None,
+                    Err(e) => return Err(e),
+                };
+                
+                overlay.insert(*document_id, document);
+            }
+        }
+        
+        if self.isolation == IsolationLevel::Serializable {
+            self.reads.push(Read::Query {
+                collection: collection.to_string(),
+                query: query.clone(),
+            });
+        }
+        
+        match &self.snapshot {
+            Some(snapshot) => storage.find_documents_at(snapshot, collection, query, &overlay),
+            None => {
+                let snapshot = storage.snapshot();
+                storage.find_documents_at(&snapshot, collection, query, &overlay)
+            }
+        }
+    }
+    
+    /// Documents the transaction writes, by collection and ID
+    pub fn write_set(&self) -> HashSet<(&str, Uuid)> {
+        self.operations
+            .iter()
+            .map(|operation| (operation.collection.as_str(), operation.document_id))
+            .collect()
+    }
+    
+    /// Check that nothing the transaction depends on was changed by a commit after its
+    /// snapshot was taken, or for locked documents after they were locked.
+    ///
+    /// Must be called while no other write can happen.
+    fn check_conflicts(&self, storage: &StorageEngine) -> Result<()> {
+        let Some(snapshot) = &self.snapshot else {
+            return Ok(());
+        };
+        
+        for (collection, document_id) in self.write_set() {
+            if storage.changed_since(self.snapshot_of(collection, document_id, snapshot), collection, &document_id)? {
+                return Err(XLimError::Conflict(format!("document {} in collection {} was changed by another transaction", document_id, collection)));
+            }
+        }
+        
+        if self.isolation != IsolationLevel::Serializable {
+            return Ok(());
+        }
+        
+        for read in &self.reads {
+            match read {
+                Read::Document { collection, document_id } => {
+                    if storage.changed_since(self.snapshot_of(collection, *document_id, snapshot), collection, document_id)? {
+                        return Err(XLimError::Conflict(format!("document {} in collection {} was changed after it was read", document_id, collection)));
+                    }
+                }
+                Read::Query { collection, query } => {
+                    // The query is run again on the latest data; any difference, including a
+                    // newly matching document, is a conflict
+                    let before = storage.find_documents_at(snapshot, collection, query, &HashMap::new())?;
+                    let after = storage.find_documents(collection, query)?;
+                    
+                    if serde_json::to_vec(&before)? != serde_json::to_vec(&after)? {
+                        return Err(XLimError::Conflict(format!("the results of a query on collection {} changed after it was run", collection)));
+                    }
+                }
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// The snapshot reads of a document come from: the one taken when it was locked, if it
+    /// is, or else the transaction's
+    fn snapshot_of<'a>(&'a self, collection: &str, document_id: Uuid, snapshot: &'a Snapshot) -> &'a Snapshot {
+        self.locks
+            .get(&(collection.to_string(), document_id))
+            .map_or(snapshot, |locked| locked.as_ref())
+    }
+    
+    /// The latest version of every document the transaction has written in a collection,
+    /// with `None` for deleted ones
+    fn overlay(&self, collection: &str) -> HashMap<Uuid, Option<Document>> {
+        self.operations
+            .iter()
+            .filter(|operation| operation.collection == collection)
+            .map(|operation| (operation.document_id, operation.document.clone()))
+            .collect()
+    }
+}
+
+impl Default for Transaction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A transaction in the manager's list, with what is needed to find and expire it without
+/// waiting for a request that is using it
+struct ActiveTransaction {
+    /// The transaction, locked by whichever request is using it
+    transaction: Arc<Mutex<Transaction>>,
+    
+    /// Time after which the transaction is aborted
+    expires_at: Option<DateTime<Utc>>,
+    
+    /// Connection that began the transaction
+    owner: Option<u64>,
+}
+
+impl ActiveTransaction {
+    /// Check whether the transaction has run past its timeout
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| Utc::now() >= expires_at)
+    }
 }
 
 /// Transaction manager for handling database transactions
@@ -109,66 +463,89 @@ pub struct TransactionManager {
     /// Storage engine
     storage: Arc<StorageEngine>,
     
-    /// Active transactions
-    active_transactions: Mutex<Vec<Transaction>>,
+    /// Active transactions, by ID. The list is only locked to find a transaction; each
+    /// transaction has its own lock, which is held while a request uses it.
+    active_transactions: Mutex<HashMap<Uuid, ActiveTransaction>>,
+    
+    /// Transactions aborted because they timed out, with the time they were aborted
+    timed_out: Mutex<HashMap<Uuid, DateTime<Utc>>>,
+    
+    /// Whether commits wait for the write-ahead log to be flushed to disk
+    sync_commits: bool,
+    
+    /// How long a transaction may stay open unless it asks for a different timeout
+    default_timeout: Duration,
+    
+    /// Document locks held by transactions
+    locks: LockManager,
+    
+    /// How long a transaction waits for a document lock
+    lock_timeout: Duration,
 }
 
 impl TransactionManager {
     /// Create a new transaction manager
-    pub fn new(storage: Arc<StorageEngine>) -> Self {
+    pub fn new(storage: Arc<StorageEngine>, sync_commits: bool, default_timeout: Duration, lock_timeout: Duration) -> Self {
         Self {
             storage,
-            active_transactions: Mutex::new(Vec::new()),
+            active_transactions: Mutex::new(HashMap::new()),
+            timed_out: Mutex::new(HashMap::new()),
+            sync_commits,
+            default_timeout,
+            locks: LockManager::new(),
+            lock_timeout,
         }
     }
     
-    /// Begin a new transaction
-    pub fn begin(&self) -> Transaction {
-        let transaction = Transaction::new();
+    /// Begin a new transaction, reading from a snapshot of the database as it is now.
+    ///
+    /// Only requests from the connection `owner` can use the transaction, and it is rolled
+    /// back when that connection closes. Without an owner, only requests without one can use it.
+    pub fn begin(&self, options: TransactionOptions, owner: Option<u64>) -> Result<Transaction> {
+        let timeout = options.timeout.unwrap_or(self.default_timeout);
+        let timeout = chrono::Duration::from_std(timeout)
+            .map_err(|_| XLimError::InvalidOperation(format!("Transaction timeout is too long: {:?}", timeout)))?;
+        
+        let mut transaction = Transaction::new();
+        transaction.isolation = options.isolation;
+        transaction.expires_at = Some(transaction.created_at + timeout);
+        transaction.owner = owner;
+        transaction.snapshot = Some(Arc::new(self.storage.snapshot()));
+        
+        let active = ActiveTransaction {
+            transaction: Arc::new(Mutex::new(transaction.clone())),
+            expires_at: transaction.expires_at,
+            owner,
+        };
         
         let mut active_transactions = self.active_transactions.lock().unwrap();
-        active_transactions.push(transaction.clone());
+        active_transactions.insert(transaction.id, active);
         
-        transaction
+        Ok(transaction)
     }
     
-    /// Commit a transaction
-    pub fn commit(&self, transaction_id: Uuid) -> Result<()> {
-        let mut active_transactions = self.active_transactions.lock().unwrap();
-        
-        let transaction_index = active_transactions
-            .iter()
-            .position(|t| t.id == transaction_id)
-            .ok_or_else(|| XLimError::Transaction(format!("Transaction not found: {}", transaction_id)))?;
-        
-        let mut transaction = active_transactions.remove(transaction_index);
+    /// Commit a transaction.
+    ///
+    /// The operations are applied atomically: if any of them fails, none are written and the
+    /// transaction is discarded. The commit also fails, with [`XLimError::Conflict`], if
+    /// another commit has changed a document the
+    /// transaction writes since it began, or for serializable transactions anything it read.
+    pub fn commit(&self, transaction_id: Uuid, owner: Option<u64>) -> Result<()> {
+        // Other transactions can carry on while this one is applied; the storage engine's
+        // write lock keeps commits in order
+        let active = self.take(transaction_id, owner)?;
+        let mut transaction = active.transaction.lock().unwrap();
         
         if transaction.committed {
             return Err(XLimError::Transaction(format!("Transaction already committed: {}", transaction_id)));
         }
         
-        // Execute operations
-        for operation in &transaction.operations {
-            match operation.op_type {
-                OperationType::Insert => {
-                    if let Some(document) = &operation.document {
-                        self.storage.insert_document(&operation.collection, document)?;
-                    } else {
-                        return Err(XLimError::Transaction("Insert operation missing document".to_string()));
-                    }
-                }
-                OperationType::Update => {
-                    if let Some(document) = &operation.document {
-                        self.storage.update_document(&operation.collection, document)?;
-                    } else {
-                        return Err(XLimError::Transaction("Update operation missing document".to_string()));
-                    }
-                }
-                OperationType::Delete => {
-                    self.storage.delete_document(&operation.collection, &operation.document_id.to_string())?;
-                }
-            }
-        }
+        let result = self.storage.apply_operations(transaction_id, &transaction.operations, self.sync_commits, || transaction.check_conflicts(&self.storage));
+        
+        // Locks are released only once the writes are visible, so that whoever gets them next
+        // reads the new versions
+        self.locks.release_all(transaction_id);
+        result?;
         
         // Mark as committed
         transaction.committed = true;
@@ -177,38 +554,387 @@ impl TransactionManager {
     }
     
     /// Rollback a transaction
-    pub fn rollback(&self, transaction_id: Uuid) -> Result<()> {
+    pub fn rollback(&self, transaction_id: Uuid, owner: Option<u64>) -> Result<()> {
+        let active = self.take(transaction_id, owner)?;
+        self.locks.release_all(transaction_id);
+        
+        if active.transaction.lock().unwrap().committed {
+            return Err(XLimError::Transaction(format!("Cannot rollback committed transaction: {}", transaction_id)));
+        }
+        
+        Ok(())
+    }
+    
+    /// Buffer the insert of a document in an active transaction.
+    ///
+    /// Fails if a document with the same ID exists as the transaction sees it.
+    pub fn insert(&self, transaction_id: Uuid, owner: Option<u64>, collection: &str, document: Document) -> Result<()> {
+        self.storage.get_collection(collection)?;
+        
+        self.with_transaction(transaction_id, owner, |transaction| {
+            match transaction.read(&self.storage, collection, &document.id.to_string()) {
+                Ok(_) => return Err(XLimError::InvalidOperation(format!("Document already exists: {}", document.id))),
+                Err(XLimError::DocumentNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+            
+            transaction.insert(collection, document);
+            Ok(())
+        })
+    }
+    
+    /// Buffer the update of a document in an active transaction.
+    ///
+    /// Fails if the document does not exist as the transaction sees it.
+    pub fn update(&self, transaction_id: Uuid, owner: Option<u64>, collection: &str, document: Document) -> Result<()> {
+        self.with_transaction(transaction_id, owner, |transaction| {
+            transaction.read(&self.storage, collection, &document.id.to_string())?;
+            transaction.update(collection, document);
+            Ok(())
+        })
+    }
+    
+    /// Buffer the delete of a document in an active transaction.
+    ///
+    /// Fails if the document does not exist as the transaction sees it.
+    pub fn delete(&self, transaction_id: Uuid, owner: Option<u64>, collection: &str, document_id: &str) -> Result<()> {
+        self.with_transaction(transaction_id, owner, |transaction| {
+            let document = transaction.read(&self.storage, collection, document_id)?;
+            transaction.delete(collection, document.id);
+            Ok(())
+        })
+    }
+    
+    /// Set a savepoint in an active transaction
+    pub fn savepoint(&self, transaction_id: Uuid, owner: Option<u64>, name: &str) -> Result<()> {
+        self.with_transaction(transaction_id, owner, |transaction| {
+            transaction.savepoint(name);
+            Ok(())
+        })
+    }
+    
+    /// Undo the operations of an active transaction since a savepoint
+    pub fn rollback_to(&self, transaction_id: Uuid, owner: Option<u64>, name: &str) -> Result<()> {
+        self.with_transaction(transaction_id, owner, |transaction| transaction.rollback_to(name))
+    }
+    
+    /// Remove a savepoint from an active transaction
+    pub fn release(&self, transaction_id: Uuid, owner: Option<u64>, name: &str) -> Result<()> {
+        self.with_transaction(transaction_id, owner, |transaction| transaction.release(name))
+    }
+    
+    /// Get a document as an active transaction sees it
+    pub fn get(&self, transaction_id: Uuid, owner: Option<u64>, collection: &str, document_id: &str) -> Result<Document> {
+        self.with_transaction(transaction_id, owner, |transaction| {
+            transaction.get(&self.storage, collection, document_id)
+        })
+    }
+    
+    /// Find the documents that match a query as an active transaction sees them
+    pub fn find(&self, transaction_id: Uuid, owner: Option<u64>, collection: &str, query: &Query) -> Result<Vec<Document>> {
+        self.with_transaction(transaction_id, owner, |transaction| {
+            transaction.find(&self.storage, collection, query)
+        })
+    }
+    
+    /// Lock a document for an active transaction until it commits or rolls back, waiting for
+    /// other transactions to release conflicting locks for up to the lock timeout.
+    ///
+    /// Reads of the document in the transaction see its latest committed version from then
+    /// on, and since every other transaction that locks it must wait, a transaction that
+    /// holds an exclusive lock on a document can write it without conflicting with them.
+    /// Transactions that write the document without locking it are still caught at commit.
+    ///
+    /// Fails with `XLimError::Timeout` if the lock is not granted in time. If waiting would
+    /// deadlock, the transaction is rolled back and the error is a conflict, so that the
+    /// transaction can be retried.
+    pub fn lock(&self, transaction_id: Uuid, owner: Option<u64>, collection: &str, document_id: &str, mode: LockMode) -> Result<()> {
+        self.storage.get_collection(collection)?;
+        
+        let document_id = Uuid::parse_str(document_id)
+            .map_err(|_| XLimError::DocumentNotFound(document_id.to_string()))?;
+        let key = (collection.to_string(), document_id);
+        
+        // Checked before waiting so that unknown and expired transactions fail at once
+        self.with_transaction(transaction_id, owner, |_| Ok(()))?;
+        
+        if let Err(e) = self.locks.acquire(transaction_id, &key, mode, self.lock_timeout) {
+            if e.is_conflict() {
+                self.abort(transaction_id);
+            }
+            
+            return Err(e);
+        }
+        
+        let snapshot = Arc::new(self.storage.snapshot());
+        let locked = self.with_transaction(transaction_id, owner, |transaction| {
+            transaction.locks.entry(key).or_insert(snapshot);
+            Ok(())
+        });
+        
+        // The transaction ended while it was waiting
+        if locked.is_err() {
+            self.locks.release_all(transaction_id);
+        }
+        
+        locked
+    }
+    
+    /// Discard a transaction and release its locks, if it is still active
+    fn abort(&self, transaction_id: Uuid) {
         let mut active_transactions = self.active_transactions.lock().unwrap();
         
-        let transaction_index = active_transactions
-            .iter()
-            .position(|t| t.id == transaction_id)
-            .ok_or_else(|| XLimError::Transaction(format!("Transaction not found: {}", transaction_id)))?;
+        active_transactions.remove(&transaction_id);
+        self.locks.release_all(transaction_id);
+    }
+    
+    /// Run a function on an active transaction.
+    ///
+    /// Only the transaction is locked while the function runs, so that requests for other
+    /// transactions are not held up by its reads.
+    fn with_transaction<T, F: FnOnce(&mut Transaction) -> Result<T>>(&self, transaction_id: Uuid, owner: Option<u64>, f: F) -> Result<T> {
+        let transaction = {
+            let mut active_transactions = self.active_transactions.lock().unwrap();
+            self.check_active(&mut active_transactions, transaction_id, owner)?;
+            
+            active_transactions.get(&transaction_id).map(|active| active.transaction.clone())
+        };
         
-        let transaction = active_transactions.remove(transaction_index);
+        let transaction = transaction.ok_or_else(|| Self::not_found(transaction_id))?;
+        let mut transaction = transaction.lock().unwrap();
         
+        // The transaction was committed while this request waited for it
         if transaction.committed {
-            return Err(XLimError::Transaction(format!("Cannot rollback committed transaction: {}", transaction_id)));
+            return Err(XLimError::Transaction(format!("Transaction already committed: {}", transaction_id)));
+        }
+        
+        f(&mut transaction)
+    }
+    
+    /// Remove an active transaction from the list, to commit or roll it back
+    fn take(&self, transaction_id: Uuid, owner: Option<u64>) -> Result<ActiveTransaction> {
+        let mut active_transactions = self.active_transactions.lock().unwrap();
+        self.check_active(&mut active_transactions, transaction_id, owner)?;
+        
+        active_transactions.remove(&transaction_id).ok_or_else(|| Self::not_found(transaction_id))
+    }
+    
+    /// Check that a transaction is active and belongs to `owner`, aborting it if it has timed
+    /// out. Other connections' transactions are reported as not found.
+    fn check_active(&self, active_transactions: &mut HashMap<Uuid, ActiveTransaction>, transaction_id: Uuid, owner: Option<u64>) -> Result<()> {
+        let Some(active) = active_transactions.get(&transaction_id).filter(|active| active.owner == owner) else {
+            if self.timed_out.lock().unwrap().contains_key(&transaction_id) {
+                return Err(XLimError::Timeout(format!("Transaction timed out: {}", transaction_id)));
+            }
+            
+            return Err(Self::not_found(transaction_id));
+        };
+        
+        if active.is_expired() {
+            active_transactions.remove(&transaction_id);
+            self.locks.release_all(transaction_id);
+            self.timed_out.lock().unwrap().insert(transaction_id, Utc::now());
+            
+            return Err(XLimError::Timeout(format!("Transaction timed out: {}", transaction_id)));
         }
         
         Ok(())
     }
     
+    /// Error for a transaction that is not active
+    fn not_found(transaction_id: Uuid) -> XLimError {
+        XLimError::Transaction(format!("Transaction not found: {}", transaction_id))
+    }
+    
+    /// Abort every transaction that has run past its timeout, returning how many there were
+    pub fn abort_expired(&self) -> usize {
+        let mut active_transactions = self.active_transactions.lock().unwrap();
+        let mut timed_out = self.timed_out.lock().unwrap();
+        let now = Utc::now();
+        
+        let before = active_transactions.len();
+        
+        active_transactions.retain(|transaction_id, active| {
+            if active.is_expired() {
+                timed_out.insert(*transaction_id, now);
+                self.locks.release_all(*transaction_id);
+                return false;
+            }
+            
+            true
+        });
+        
+        let retention = chrono::Duration::from_std(TIMED_OUT_RETENTION).unwrap_or_default();
+        timed_out.retain(|_, aborted_at| now - *aborted_at < retention);
+        
+        before - active_transactions.len()
+    }
+    
+    /// Roll back every transaction begun by a connection, returning how many there were
+    pub fn rollback_owned_by(&self, owner: u64) -> usize {
+        let mut active_transactions = self.active_transactions.lock().unwrap();
+        let before = active_transactions.len();
+        
+        active_transactions.retain(|transaction_id, active| {
+            if active.owner == Some(owner) {
+                self.locks.release_all(*transaction_id);
+                return false;
+            }
+            
+            true
+        });
+        
+        before - active_transactions.len()
+    }
+    
     /// Get a transaction by ID
     pub fn get_transaction(&self, transaction_id: Uuid) -> Result<Transaction> {
-        let active_transactions = self.active_transactions.lock().unwrap();
+        let transaction = self.active_transactions
+            .lock()
+            .unwrap()
+            .get(&transaction_id)
+            .map(|active| active.transaction.clone())
+            .ok_or_else(|| Self::not_found(transaction_id))?;
         
-        let transaction = active_transactions
-            .iter()
-            .find(|t| t.id == transaction_id)
-            .ok_or_else(|| XLimError::Transaction(format!("Transaction not found: {}", transaction_id)))?;
+        let transaction = transaction.lock().unwrap().clone();
         
-        Ok(transaction.clone())
+        Ok(transaction)
     }
     
-    /// Get all active transactions
+    /// Get all active transactions, oldest first
     pub fn get_active_transactions(&self) -> Vec<Transaction> {
-        let active_transactions = self.active_transactions.lock().unwrap();
-        active_transactions.clone()
+        let transactions: Vec<Arc<Mutex<Transaction>>> = self.active_transactions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|active| active.transaction.clone())
+            .collect();
+        
+        // Each transaction is locked only after the list is released
+        let mut transactions: Vec<Transaction> = transactions
+            .iter()
+            .map(|transaction| transaction.lock().unwrap().clone())
+            .collect();
+        transactions.sort_by_key(|transaction| transaction.created_at);
+        
+        transactions
     }
-} 
\ No newline at end of file
+}
+
+/// Abort expired transactions periodically.
+///
+/// Stops once the transaction manager has been dropped.
+pub async fn reap_transactions(manager: Weak<TransactionManager>) {
+    loop {
+        tokio::time::sleep(REAP_INTERVAL).await;
+        
+        let Some(manager) = manager.upgrade() else {
+            return;
+        };
+        
+        let aborted = manager.abort_expired();
+        
+        if aborted > 0 {
+            info!("Aborted {} transactions that timed out", aborted);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// Transaction manager over an in-memory store with collection "c" and one document
+    fn manager() -> (TransactionManager, Arc<StorageEngine>, Document) {
+        let storage = Arc::new(StorageEngine::in_memory().unwrap());
+        storage.create_collection("c").unwrap();
+        
+        let document = Document::new().set("x", 1);
+        storage.insert_document("c", &document).unwrap();
+        
+        let manager = TransactionManager::new(storage.clone(), false, Duration::from_secs(60), Duration::from_secs(1));
+        (manager, storage, document)
+    }
+    
+    #[test]
+    fn concurrent_writes_conflict() {
+        let (manager, storage, document) = manager();
+        
+        let first = manager.begin(TransactionOptions::default(), None).unwrap();
+        let second = manager.begin(TransactionOptions::default(), None).unwrap();
+        manager.update(first.id, None, "c", document.clone().set("x", 2)).unwrap();
+        manager.update(second.id, None, "c", document.clone().set("x", 3)).unwrap();
+        
+        manager.commit(first.id, None).unwrap();
+        let error = manager.commit(second.id, None).unwrap_err();
+        assert!(matches!(error, XLimError::Conflict(_)), "{}", error);
+        assert_eq!(storage.get_document("c", &document.id.to_string()).unwrap().get("x"), Some(&2.into()));
+    }
+    
+    #[test]
+    fn serializable_transactions_conflict_on_changed_reads() {
+        let (manager, storage, document) = manager();
+        let options = TransactionOptions { isolation: IsolationLevel::Serializable, timeout: None };
+        
+        let transaction = manager.begin(options.clone(), None).unwrap();
+        manager.get(transaction.id, None, "c", &document.id.to_string()).unwrap();
+        storage.update_document("c", &document.clone().set("x", 2)).unwrap();
+        assert!(matches!(manager.commit(transaction.id, None), Err(XLimError::Conflict(_))));
+        
+        let transaction = manager.begin(options, None).unwrap();
+        manager.find(transaction.id, None, "c", &Query::new().filter("x", ">", 100).unwrap()).unwrap();
+        storage.insert_document("c", &Document::new().set("x", 200)).unwrap();
+        assert!(matches!(manager.commit(transaction.id, None), Err(XLimError::Conflict(_))));
+    }
+    
+    #[test]
+    fn savepoints_undo_only_later_operations() {
+        let mut transaction = Transaction::new();
+        transaction.insert("c", Document::new());
+        transaction.savepoint("a");
+        transaction.insert("c", Document::new());
+        transaction.savepoint("b");
+        transaction.insert("c", Document::new());
+        transaction.savepoint("a");
+        transaction.insert("c", Document::new());
+        
+        // The most recent savepoint with a name is the one used
+        transaction.rollback_to("a").unwrap();
+        assert_eq!(transaction.operations.len(), 3);
+        
+        transaction.release("a").unwrap();
+        transaction.rollback_to("a").unwrap();
+        assert_eq!(transaction.operations.len(), 1);
+        assert!(transaction.rollback_to("b").is_err());
+        
+        // Rolling back keeps the savepoint, so it can be rolled back to again
+        transaction.insert("c", Document::new());
+        transaction.rollback_to("a").unwrap();
+        assert_eq!(transaction.operations.len(), 1);
+        
+        transaction.release("a").unwrap();
+        assert!(matches!(transaction.release("a"), Err(XLimError::Transaction(_))));
+    }
+    
+    #[test]
+    fn commits_keep_only_operations_before_a_rolled_back_savepoint() {
+        let (manager, storage, document) = manager();
+        let transaction = manager.begin(TransactionOptions::default(), None).unwrap();
+        
+        let kept = Document::new();
+        manager.insert(transaction.id, None, "c", kept.clone()).unwrap();
+        manager.savepoint(transaction.id, None, "step").unwrap();
+        
+        let discarded = Document::new();
+        manager.insert(transaction.id, None, "c", discarded.clone()).unwrap();
+        manager.delete(transaction.id, None, "c", &document.id.to_string()).unwrap();
+        
+        manager.rollback_to(transaction.id, None, "step").unwrap();
+        assert!(manager.get(transaction.id, None, "c", &discarded.id.to_string()).is_err());
+        manager.commit(transaction.id, None).unwrap();
+        
+        assert!(storage.get_document("c", &kept.id.to_string()).is_ok());
+        assert!(storage.get_document("c", &document.id.to_string()).is_ok());
+        assert!(matches!(storage.get_document("c", &discarded.id.to_string()), Err(XLimError::DocumentNotFound(_))));
+    }
+}
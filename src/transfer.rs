@@ -0,0 +1,611 @@
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::client::Collection;
+use crate::document::Document;
+use crate::error::{Result, XLimError};
+
+/// Number of documents an import writes per batch unless told otherwise
+pub const DEFAULT_IMPORT_BATCH_SIZE: usize = 1000;
+
+/// Fields that hold a document's own ID and timestamps rather than its data
+const DOCUMENT_FIELDS: [&str; 4] = ["id", "created_at", "updated_at", "expires_at"];
+
+/// File format of an export or import
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// One JSON document per line
+    Jsonl,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+impl Format {
+    /// Guess the format of a file from its extension, defaulting to JSON Lines
+    pub fn of_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("csv") => Self::Csv,
+            _ => Self::Jsonl,
+        }
+    }
+}
+
+/// What a bulk insert does with a document whose ID is already taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    /// Leave the existing document and report the conflict
+    Error,
+    /// Leave the existing document without reporting it
+    Skip,
+    /// Replace the existing document, making the insert an upsert
+    Replace,
+    /// Write nothing from the batch with the conflict and stop
+    Abort,
+}
+
+/// Outcome of writing a batch of documents with a bulk insert
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BulkWriteResult {
+    /// Number of new documents written
+    pub inserted: usize,
+    
+    /// Number of existing documents replaced
+    pub replaced: usize,
+    
+    /// Positions in the batch of the documents that were not written because their ID was
+    /// already taken, by a stored document or an earlier one in the batch
+    pub conflicts: Vec<usize>,
+}
+
+/// A CSV column and the document field it holds.
+///
+/// `id`, `created_at`, `updated_at` and `expires_at` are the document's own ID and timestamps;
+/// any other field is a top-level data field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnMapping {
+    /// Name of the column in the header row
+    pub column: String,
+    
+    /// Field the column holds, or `None` for a column that is ignored on import
+    pub field: Option<String>,
+}
+
+impl ColumnMapping {
+    /// Map a column to the field of the same name
+    pub fn same(field: &str) -> Self {
+        Self {
+            column: field.to_string(),
+            field: Some(field.to_string()),
+        }
+    }
+}
+
+impl FromStr for ColumnMapping {
+    type Err = XLimError;
+    
+    /// Parse `<column>=<field>`, `<column>=` to ignore a column, or `<field>` for a column
+    /// named after its field
+    fn from_str(s: &str) -> Result<Self> {
+        let (column, field) = s.split_once('=').unwrap_or((s, s));
+        
+        if column.is_empty() {
+            return Err(XLimError::InvalidOperation(format!("Invalid column mapping '{}', expected <column>=<field>", s)));
+        }
+        
+        Ok(Self {
+            column: column.to_string(),
+            field: Some(field.to_string()).filter(|field| !field.is_empty()),
+        })
+    }
+}
+
+/// A record read from an import file: its line number and the document on it, or why the
+/// line could not be read as one
+pub type ParsedLine = (u64, Result<Document>);
+
+/// Reads documents from a JSON Lines or CSV file
+pub enum DocumentReader<R: Read> {
+    /// One document per line, as written by an export
+    Jsonl {
+        /// Lines of the file
+        lines: Lines<BufReader<R>>,
+        
+        /// Number of the last line read
+        line: u64,
+    },
+    /// One document per record, with fields named by the header row
+    Csv {
+        /// Records after the header row
+        records: csv::StringRecordsIntoIter<R>,
+        
+        /// Field each column holds, in column order
+        fields: Vec<Option<String>>,
+        
+        /// Number of the last line read
+        line: u64,
+    },
+}
+
+impl<R: Read> DocumentReader<R> {
+    /// Read documents as JSON Lines
+    pub fn jsonl(reader: R) -> Self {
+        Self::Jsonl {
+            lines: BufReader::new(reader).lines(),
+            line: 0,
+        }
+    }
+    
+    /// Read documents as CSV. Columns are read into the field named by their header unless a
+    /// mapping says otherwise.
+    pub fn csv(reader: R, mappings: &[ColumnMapping]) -> Result<Self> {
+        let mut reader = csv::Reader::from_reader(reader);
+        let headers = reader.headers()?.clone();
+        
+        if let Some(mapping) = mappings.iter().find(|mapping| !headers.iter().any(|header| header == mapping.column)) {
+            return Err(XLimError::InvalidOperation(format!("No column '{}' in the CSV header", mapping.column)));
+        }
+        
+        let fields = headers
+            .iter()
+            .map(|header| match mappings.iter().find(|mapping| mapping.column == header) {
+                Some(mapping) => mapping.field.clone(),
+                None => Some(header.to_string()),
+            })
+            .collect();
+        
+        Ok(Self::Csv {
+            records: reader.into_records(),
+            fields,
+            line: 1,
+        })
+    }
+}
+
+impl<R: Read> Iterator for DocumentReader<R> {
+    /// A record, or an error that stops the import because the file cannot be read further
+    type Item = Result<ParsedLine>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Jsonl { lines, line } => loop {
+                let text = match lines.next()? {
+                    Ok(text) => text,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                
+                *line += 1;
+                
+                if !text.trim().is_empty() {
+                    return Some(Ok((*line, Document::from_json(&text))));
+                }
+            },
+            Self::Csv { records, fields, line } => match records.next()? {
+                Ok(record) => {
+                    *line = record.position().map_or(*line + 1, |position| position.line());
+                    
+                    Some(Ok((*line, document_from_record(&record, fields))))
+                }
+                Err(e) if e.is_io_error() => Some(Err(e.into())),
+                Err(e) => {
+                    *line = match e.kind() {
+                        csv::ErrorKind::Utf8 { pos: Some(position), .. }
+                        | csv::ErrorKind::UnequalLengths { pos: Some(position), .. } => position.line(),
+                        _ => *line + 1,
+                    };
+                    
+                    Some(Ok((*line, Err(e.into()))))
+                }
+            },
+        }
+    }
+}
+
+/// Writes documents to a JSON Lines or CSV file
+pub enum DocumentWriter<W: Write> {
+    /// One document per line, with its ID and timestamps
+    Jsonl(BufWriter<W>),
+    /// One document per record, under a header row
+    Csv {
+        /// CSV writer, which buffers its output
+        writer: Box<csv::Writer<W>>,
+        
+        /// Field of each column, in column order
+        fields: Vec<String>,
+    },
+}
+
+impl<W: Write> DocumentWriter<W> {
+    /// Write documents as JSON Lines
+    pub fn jsonl(writer: W) -> Self {
+        Self::Jsonl(BufWriter::new(writer))
+    }
+    
+    /// Write documents as CSV with the given columns, starting with the header row
+    pub fn csv(writer: W, columns: Vec<ColumnMapping>) -> Result<Self> {
+        let mut writer = csv::Writer::from_writer(writer);
+        let mut fields = Vec::with_capacity(columns.len());
+        
+        for column in &columns {
+            let field = column.field.clone()
+                .ok_or_else(|| XLimError::InvalidOperation(format!("Column '{}' must name the field it holds", column.column)))?;
+            
+            fields.push(field);
+        }
+        
+        writer.write_record(columns.iter().map(|column| &column.column))?;
+        
+        Ok(Self::Csv {
+            writer: Box::new(writer),
+            fields,
+        })
+    }
+    
+    /// Write a document
+    pub fn write(&mut self, document: &Document) -> Result<()> {
+        match self {
+            Self::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, document)?;
+                writer.write_all(b"\n")?;
+            }
+            Self::Csv { writer, fields } => {
+                writer.write_record(fields.iter().map(|field| cell_of(document, field)))?;
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// Flush everything written so far
+    pub fn finish(self) -> Result<()> {
+        match self {
+            Self::Jsonl(mut writer) => writer.flush()?,
+            Self::Csv { mut writer, .. } => writer.flush()?,
+        }
+        
+        Ok(())
+    }
+}
+
+/// An import file line that could not be imported
+#[derive(Debug, Clone)]
+pub struct LineError {
+    /// Number of the line, counting from 1
+    pub line: u64,
+    
+    /// Why the line was not imported
+    pub message: String,
+}
+
+/// Counts and errors of an import
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+    /// Number of records read from the file
+    pub read: usize,
+    
+    /// Number of new documents written
+    pub inserted: usize,
+    
+    /// Number of existing documents replaced
+    pub replaced: usize,
+    
+    /// Number of documents left out because their ID was already taken
+    pub skipped: usize,
+    
+    /// Lines that were not imported, in line order
+    pub errors: Vec<LineError>,
+    
+    /// Whether the import stopped at a conflict before reading the whole file
+    pub aborted: bool,
+}
+
+/// Export every document of a collection, returning how many there were.
+///
+/// Documents are streamed from a snapshot of the collection, so memory use does not grow
+/// with its size.
+pub async fn export<W: Write>(collection: &Collection, mut writer: DocumentWriter<W>) -> Result<usize> {
+    let mut documents = std::pin::pin!(collection.stream());
+    let mut exported = 0;
+    
+    while let Some(document) = documents.try_next().await? {
+        writer.write(&document)?;
+        exported += 1;
+    }
+    
+    writer.finish()?;
+    
+    Ok(exported)
+}
+
+/// Find the CSV columns that export every field of a collection: the ID and timestamps of
+/// its documents, followed by every data field in order of appearance.
+///
+/// This reads the whole collection once before the export reads it again.
+pub async fn discover_columns(collection: &Collection) -> Result<Vec<ColumnMapping>> {
+    let mut documents = std::pin::pin!(collection.stream());
+    let mut fields: Vec<String> = Vec::new();
+    let mut expires = false;
+    
+    while let Some(document) = documents.try_next().await? {
+        expires |= document.expires_at.is_some();
+        
+        for field in document.data.keys() {
+            if !fields.contains(field) {
+                fields.push(field.clone());
+            }
+        }
+    }
+    
+    let columns = DOCUMENT_FIELDS
+        .iter()
+        .filter(|field| expires || **field != "expires_at")
+        .map(|field| field.to_string())
+        .chain(fields);
+    
+    Ok(columns.map(|field| ColumnMapping::same(&field)).collect())
+}
+
+/// Import documents into a collection, writing each batch atomically.
+///
+/// Lines that cannot be read as documents are reported and left out, and the import goes on.
+/// Documents whose ID is already taken are handled as `on_conflict` says; with
+/// [`OnConflict::Abort`], the batches before the one with the conflict stay written.
+/// `progress` is called after every batch.
+pub async fn import<R: Read>(collection: &Collection, reader: DocumentReader<R>, on_conflict: OnConflict, batch_size: usize, mut progress: impl FnMut(&ImportReport)) -> Result<ImportReport> {
+    let batch_size = batch_size.max(1);
+    let mut report = ImportReport::default();
+    let mut batch = Vec::with_capacity(batch_size);
+    
+    for parsed in reader {
+        let (line, document) = parsed?;
+        report.read += 1;
+        
+        match document {
+            Ok(document) => batch.push((line, document)),
+            Err(e) => report.errors.push(LineError { line, message: e.to_string() }),
+        }
+        
+        if batch.len() >= batch_size {
+            write_batch(collection, &mut batch, on_conflict, &mut report).await?;
+            progress(&report);
+            
+            if report.aborted {
+                break;
+            }
+        }
+    }
+    
+    if !batch.is_empty() && !report.aborted {
+        write_batch(collection, &mut batch, on_conflict, &mut report).await?;
+        progress(&report);
+    }
+    
+    report.errors.sort_by_key(|error| error.line);
+    
+    Ok(report)
+}
+
+/// Write a batch of documents read from the given lines, adding the outcome to a report
+async fn write_batch(collection: &Collection, batch: &mut Vec<(u64, Document)>, on_conflict: OnConflict, report: &mut ImportReport) -> Result<()> {
+    let (lines, documents): (Vec<u64>, Vec<Document>) = std::mem::take(batch).into_iter().unzip();
+    let ids: Vec<Uuid> = documents.iter().map(|document| document.id).collect();
+    
+    let result = collection.insert_many(documents, on_conflict).await?;
+    
+    report.inserted += result.inserted;
+    report.replaced += result.replaced;
+    report.aborted = on_conflict == OnConflict::Abort && !result.conflicts.is_empty();
+    
+    for position in result.conflicts {
+        match on_conflict {
+            OnConflict::Skip => report.skipped += 1,
+            _ => report.errors.push(LineError {
+                line: lines[position],
+                message: format!("Document {} already exists", ids[position]),
+            }),
+        }
+    }
+    
+    Ok(())
+}
+
+/// Build a document from a CSV record, given the field each column holds
+fn document_from_record(record: &csv::StringRecord, fields: &[Option<String>]) -> Result<Document> {
+    let mut document = Document::new();
+    
+    for (cell, field) in record.iter().zip(fields) {
+        // Empty cells are missing fields; an empty string is written as `""`
+        let Some(field) = field.as_deref().filter(|_| !cell.is_empty()) else {
+            continue;
+        };
+        
+        match field {
+            "id" => document.id = Uuid::parse_str(cell)
+                .map_err(|e| XLimError::InvalidOperation(format!("Invalid id '{}': {}", cell, e)))?,
+            "created_at" => document.created_at = parse_timestamp(field, cell)?,
+            "updated_at" => document.updated_at = parse_timestamp(field, cell)?,
+            "expires_at" => document.expires_at = Some(parse_timestamp(field, cell)?),
+            field => {
+                document.data.insert(field.to_string(), decode_cell(cell));
+            }
+        }
+    }
+    
+    Ok(document)
+}
+
+/// Get the CSV cell of a document field, which is empty if the document does not have it
+fn cell_of(document: &Document, field: &str) -> String {
+    match field {
+        "id" => document.id.to_string(),
+        "created_at" => document.created_at.to_rfc3339(),
+        "updated_at" => document.updated_at.to_rfc3339(),
+        "expires_at" => document.expires_at.map(|expires_at| expires_at.to_rfc3339()).unwrap_or_default(),
+        field => document.get(field).map(encode_cell).unwrap_or_default(),
+    }
+}
+
+/// Write a value as a CSV cell. Strings are written as they are unless they would be read
+/// back as another value, such as `"42"` or the empty string, in which case they are quoted
+/// as JSON; every other value is written as JSON.
+fn encode_cell(value: &Value) -> String {
+    match value {
+        Value::String(text) if !text.is_empty() && serde_json::from_str::<Value>(text).is_err() => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Read a CSV cell as a value: JSON if it parses as JSON, and a string otherwise
+fn decode_cell(cell: &str) -> Value {
+    serde_json::from_str(cell).unwrap_or_else(|_| Value::String(cell.to_string()))
+}
+
+/// Parse an RFC 3339 timestamp from a CSV cell
+fn parse_timestamp(field: &str, cell: &str) -> Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(cell)
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .map_err(|e| XLimError::InvalidOperation(format!("Invalid {} '{}': {}", field, cell, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+    use serde_json::json;
+    
+    /// Documents with values of every kind, including strings that look like other values
+    fn documents() -> Vec<Document> {
+        vec![
+            Document::new().set("name", "Ann").set("age", 30).set("tags", json!(["a", "b"])),
+            Document::new().set("name", "42").set("age", json!(null)).set("tags", json!({ "nested": true })),
+            Document::new().set("name", "").expire_at(Utc::now() + TimeDelta::hours(1)),
+        ]
+    }
+    
+    /// Read every document from a reader, failing on any line that is not one
+    fn read_all<R: Read>(reader: DocumentReader<R>) -> Vec<Document> {
+        reader.map(|parsed| parsed.unwrap().1.unwrap()).collect()
+    }
+    
+    /// A document as JSON, to compare documents with
+    fn to_json(document: &Document) -> Value {
+        serde_json::to_value(document).unwrap()
+    }
+    
+    #[test]
+    fn cells_decode_to_the_values_they_encode() {
+        let values = [
+            json!("plain text"),
+            json!(""),
+            json!("42"),
+            json!("true"),
+            json!("null"),
+            json!(" 7"),
+            json!("\"quoted\""),
+            json!("[1, 2]"),
+            json!(42),
+            json!(-1.5),
+            json!(true),
+            json!(null),
+            json!([1, "two", null]),
+            json!({ "a": { "b": "c" } }),
+        ];
+        
+        for value in values {
+            let cell = encode_cell(&value);
+            
+            assert!(!cell.is_empty(), "{} encodes to an empty cell", value);
+            assert_eq!(decode_cell(&cell), value, "cell {:?}", cell);
+        }
+        
+        assert_eq!(encode_cell(&json!("plain text")), "plain text");
+    }
+    
+    #[test]
+    fn jsonl_round_trips_documents() {
+        let documents = documents();
+        let mut output = Vec::new();
+        let mut writer = DocumentWriter::jsonl(&mut output);
+        
+        for document in &documents {
+            writer.write(document).unwrap();
+        }
+        
+        writer.finish().unwrap();
+        
+        // Blank lines are skipped, but still counted
+        output.extend_from_slice(b"\n{not json}\n");
+        
+        let mut reader = DocumentReader::jsonl(output.as_slice());
+        let read: Vec<Document> = reader.by_ref().take(3).map(|parsed| parsed.unwrap().1.unwrap()).collect();
+        
+        assert_eq!(read.iter().map(to_json).collect::<Vec<_>>(), documents.iter().map(to_json).collect::<Vec<_>>());
+        
+        let (line, bad) = reader.next().unwrap().unwrap();
+        assert_eq!(line, 5);
+        assert!(bad.is_err());
+        assert!(reader.next().is_none());
+    }
+    
+    #[test]
+    fn csv_round_trips_documents() {
+        let documents = documents();
+        let columns: Vec<ColumnMapping> = ["id", "created_at", "updated_at", "expires_at", "name", "age", "tags"]
+            .iter()
+            .map(|field| ColumnMapping::same(field))
+            .collect();
+        
+        let mut output = Vec::new();
+        let mut writer = DocumentWriter::csv(&mut output, columns).unwrap();
+        
+        for document in &documents {
+            writer.write(document).unwrap();
+        }
+        
+        writer.finish().unwrap();
+        
+        let read = read_all(DocumentReader::csv(output.as_slice(), &[]).unwrap());
+        
+        assert_eq!(read.iter().map(to_json).collect::<Vec<_>>(), documents.iter().map(to_json).collect::<Vec<_>>());
+    }
+    
+    #[test]
+    fn csv_columns_can_be_renamed_or_ignored() {
+        let input = "full name,internal,score\nAnn,x,3\nBob,y,\n";
+        let mappings: Vec<ColumnMapping> = ["full name=name", "internal="].iter().map(|s| s.parse().unwrap()).collect();
+        
+        let read = read_all(DocumentReader::csv(input.as_bytes(), &mappings).unwrap());
+        
+        assert_eq!(read[0].data, json!({ "name": "Ann", "score": 3 }).as_object().unwrap().clone());
+        assert_eq!(read[1].data, json!({ "name": "Bob" }).as_object().unwrap().clone());
+        
+        let missing = ["nope=name".parse().unwrap()];
+        assert!(matches!(DocumentReader::csv(input.as_bytes(), &missing), Err(XLimError::InvalidOperation(_))));
+        assert!("=name".parse::<ColumnMapping>().is_err());
+    }
+    
+    #[test]
+    fn bad_csv_records_are_reported_with_their_line() {
+        let input = "id,name\nnot-a-uuid,Ann\n,Bob\n,Cy,extra\n";
+        let mut reader = DocumentReader::csv(input.as_bytes(), &[]).unwrap();
+        
+        let (line, result) = reader.next().unwrap().unwrap();
+        assert_eq!(line, 2);
+        assert!(matches!(result, Err(XLimError::InvalidOperation(_))));
+        
+        let (line, result) = reader.next().unwrap().unwrap();
+        assert_eq!(line, 3);
+        assert_eq!(result.unwrap().get("name"), Some(&json!("Bob")));
+        
+        let (line, result) = reader.next().unwrap().unwrap();
+        assert_eq!(line, 4);
+        assert!(matches!(result, Err(XLimError::Csv(_))));
+    }
+}
@@ -0,0 +1,121 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Weak;
+use std::time::Duration;
+
+use crate::document::{Collection, Document};
+use crate::error::{Result, XLimError};
+use crate::storage::StorageEngine;
+
+/// Key of the TTL policy in `Collection::metadata`
+pub const TTL_METADATA_KEY: &str = "ttl";
+
+/// When the documents of a collection expire.
+///
+/// A document expires `expire_after_secs` seconds after the time in `field`, which is either
+/// `created_at`, `updated_at` or a data field holding an RFC 3339 timestamp or a number of
+/// seconds since the Unix epoch. Documents whose field is missing or not a time never expire.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TtlPolicy {
+    /// Field holding the time the expiry is counted from
+    pub field: String,
+    
+    /// Seconds after that time that the document expires
+    #[serde(default)]
+    pub expire_after_secs: u64,
+}
+
+impl TtlPolicy {
+    /// Check that the policy can be applied
+    pub fn validate(&self) -> Result<()> {
+        if self.field.is_empty() {
+            return Err(XLimError::InvalidOperation("TTL field must not be empty".to_string()));
+        }
+        
+        if TimeDelta::try_seconds(self.expire_after_secs as i64).is_none() {
+            return Err(XLimError::InvalidOperation(format!("TTL of {} seconds is too long", self.expire_after_secs)));
+        }
+        
+        Ok(())
+    }
+    
+    /// Get the time a document expires under the policy, if it does
+    pub fn expiry_of(&self, document: &Document) -> Option<DateTime<Utc>> {
+        let start = match self.field.as_str() {
+            "created_at" => document.created_at,
+            "updated_at" => document.updated_at,
+            field => parse_time(document.get(field)?)?,
+        };
+        
+        start.checked_add_signed(TimeDelta::try_seconds(self.expire_after_secs as i64)?)
+    }
+}
+
+/// Decides which documents of a collection have expired, as of a fixed time
+#[derive(Debug, Clone)]
+pub struct Expiry {
+    /// TTL policy of the collection
+    policy: Option<TtlPolicy>,
+    
+    /// Time that expiry is judged at
+    now: DateTime<Utc>,
+}
+
+impl Expiry {
+    /// Judge the documents of a collection as of now
+    pub fn of(collection: &Collection) -> Self {
+        Self {
+            policy: collection.ttl(),
+            now: Utc::now(),
+        }
+    }
+    
+    /// Check whether a document has expired.
+    ///
+    /// A document's own `expires_at` takes precedence over the collection's policy.
+    pub fn is_expired(&self, document: &Document) -> bool {
+        let expires_at = document.expires_at
+            .or_else(|| self.policy.as_ref().and_then(|policy| policy.expiry_of(document)));
+        
+        expires_at.is_some_and(|expires_at| expires_at <= self.now)
+    }
+}
+
+/// Parse a timestamp stored in a document field
+fn parse_time(value: &Value) -> Option<DateTime<Utc>> {
+    match value {
+        Value::String(text) => DateTime::parse_from_rfc3339(text).ok().map(|time| time.with_timezone(&Utc)),
+        Value::Number(number) => {
+            let seconds = number.as_f64()?;
+            
+            DateTime::from_timestamp_millis((seconds * 1000.0) as i64)
+        }
+        _ => None,
+    }
+}
+
+/// Purge expired documents periodically.
+///
+/// Expired documents are hidden from reads as soon as they expire; this only frees the space
+/// they take up. Stops once the storage engine has been dropped.
+pub async fn reap_expired(storage: Weak<StorageEngine>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        
+        let Some(storage) = storage.upgrade() else {
+            return;
+        };
+        
+        // Purging scans whole collections, so it must not hold up the async workers
+        let purged = tokio::task::spawn_blocking(move || storage.purge_expired()).await;
+        
+        match purged {
+            Ok(Ok(0)) => {}
+            Ok(Ok(purged)) => info!("Purged {} expired documents", purged),
+            Ok(Err(e)) => error!("Failed to purge expired documents: {}", e),
+            Err(e) => error!("Expired document purge panicked: {}", e),
+        }
+    }
+}